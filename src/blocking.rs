@@ -0,0 +1,87 @@
+//! Synchronous versions of the library's entry points, for callers
+//! without a tokio runtime of their own (e.g. build scripts). Each call
+//! runs on a current-thread runtime of its own, so they must not be
+//! called from async code: that panics.
+//!
+//! The options and results are the same as those of the async API.
+
+use std::fmt::Display;
+use std::future::Future;
+
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+use crate::clone::{self, CloneError, CloneOptions, CloneResult, CloneTarget, FoundBranch};
+use crate::git_http_client::{GitClient, GitClientError};
+use crate::RefInfo;
+
+fn block_on<F: Future>(function: &str, future: F) -> F::Output {
+    if tokio::runtime::Handle::try_current().is_ok() {
+        panic!(
+            "git_sleipnir::blocking::{} was called from within an async runtime, \
+             use the async version in git_sleipnir::clone instead",
+            function
+        );
+    }
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("building a tokio runtime")
+        .block_on(future)
+}
+
+/// Blocking [`clone::clone_repo`]. `cancel` can be cancelled from another
+/// thread.
+pub fn clone_repo(
+    target: &CloneTarget,
+    opts: &CloneOptions,
+    cancel: &CancellationToken,
+) -> Result<CloneResult, CloneError> {
+    block_on("clone_repo", clone::clone_repo(target, opts, cancel))
+}
+
+/// Blocking [`clone::find_branch`]
+pub fn find_branch(target: &CloneTarget, opts: &CloneOptions) -> Result<FoundBranch, CloneError> {
+    block_on("find_branch", clone::find_branch(target, opts))
+}
+
+/// Lists the refs of the repository at `url` starting with any of
+/// `ref_prefixes`
+pub fn ls_refs<T: AsRef<str> + Display>(
+    url: &Url,
+    ref_prefixes: &[T],
+) -> Result<Vec<RefInfo>, GitClientError> {
+    block_on("ls_refs", async {
+        GitClient::new()?.for_url(url).ls_refs(ref_prefixes).await
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_errors() {
+        /* Nothing listens there once the listener is gone */
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = Url::parse(&format!("http://127.0.0.1:{}/repo.git", port)).unwrap();
+        assert!(matches!(
+            ls_refs(&url, &["refs/heads/"]),
+            Err(GitClientError::ConnectionError(_))
+        ));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "blocking::find_branch was called from within an async runtime")]
+    async fn panics_in_async_context() {
+        let target = CloneTarget::new(
+            Url::parse("http://127.0.0.1:1/repo.git").unwrap(),
+            "repo".into(),
+        );
+        let _ = find_branch(&target, &CloneOptions::default());
+    }
+}
@@ -1,17 +1,76 @@
-use regex::Regex;
+use regex::{Regex, RegexBuilder};
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::path::Path;
 
-use log::trace;
+use log::{debug, trace, warn};
+use serde::Serialize;
 
 #[derive(Clone, Debug)]
 pub struct BranchFallback {
     pub pattern: Regex,
     pub replacement: String,
+    pub global: bool,
+    pub spec: String,
+    /* The replacement after environment expansion, if it had any */
+    pub expanded: Option<String>,
+}
+
+impl std::fmt::Display for BranchFallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.expanded {
+            Some(expanded) => write!(f, "{} (replacement: {})", self.spec, expanded),
+            None => write!(f, "{}", self.spec),
+        }
+    }
+}
+
+/* Expands ${env:NAME} in a replacement string, looking the variables up
+ * with `var`. Returns the replacement to use with the regex (with '$' in
+ * the values escaped) and the plain expanded text, or None if there was
+ * nothing to expand. */
+fn expand_env(
+    replacement: &str,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<Option<(String, String)>, String> {
+    const TOKEN: &str = "${env:";
+    if !replacement.contains(TOKEN) {
+        return Ok(None);
+    }
+
+    let mut for_regex = String::new();
+    let mut plain = String::new();
+    let mut rest = replacement;
+    while let Some(start) = rest.find(TOKEN) {
+        for_regex.push_str(&rest[..start]);
+        plain.push_str(&rest[..start]);
+        let after = &rest[start + TOKEN.len()..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| format!("Unterminated '{}' in replacement", TOKEN))?;
+        let name = &after[..end];
+        let value =
+            var(name).ok_or_else(|| format!("Environment variable '{}' is not set", name))?;
+        for_regex.push_str(&value.replace('$', "$$"));
+        plain.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    for_regex.push_str(rest);
+    plain.push_str(rest);
+    Ok(Some((for_regex, plain)))
 }
 
 impl BranchFallback {
     pub fn parse(s: &str) -> Result<BranchFallback, String> {
+        Self::parse_with_env(s, |name| std::env::var(name).ok())
+    }
+
+    /* The same with the environment variables looked up with `var` */
+    fn parse_with_env(
+        s: &str,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<BranchFallback, String> {
         let mut chars = s.chars();
 
         let delim = chars.next().ok_or("Empty fallback string")?;
@@ -41,58 +100,372 @@ impl BranchFallback {
             return Err("Trailing escape character".to_string());
         }
 
-        if !current.is_empty() || parts.len() != 2 {
+        if parts.len() != 2 {
             return Err(format!(
                 "Expected format: {d}regex{d}replacement{d}",
                 d = delim
             ));
         }
 
-        let regex_str = &parts[0];
-        let replacement = &parts[1];
+        /* Optional flags after the last delimiter: 'i' for case insensitive
+         * matching, 'a' to anchor the regex to the whole branch name and
+         * 'g' to replace all matches instead of just the first */
+        let mut case_insensitive = false;
+        let mut anchored = false;
+        let mut global = false;
+        for flag in current.chars() {
+            match flag {
+                'i' => case_insensitive = true,
+                'a' => anchored = true,
+                'g' => global = true,
+                _ => {
+                    return Err(format!(
+                        "Unknown flag '{f}'. Expected format: {d}regex{d}replacement{d} optionally followed by flags 'i', 'a' and/or 'g'",
+                        f = flag,
+                        d = delim
+                    ))
+                }
+            }
+        }
 
-        let pattern =
-            Regex::new(regex_str).map_err(|e| format!("Invalid regex '{}': {}", regex_str, e))?;
+        let regex_str = if anchored {
+            format!("^(?:{})$", parts[0])
+        } else {
+            parts[0].clone()
+        };
+        let (replacement, expanded) = match expand_env(&parts[1], var)? {
+            Some((replacement, expanded)) => (replacement, Some(expanded)),
+            None => (parts[1].clone(), None),
+        };
+
+        let pattern = RegexBuilder::new(&regex_str)
+            .case_insensitive(case_insensitive)
+            .build()
+            .map_err(|e| format!("Invalid regex '{}': {}", parts[0], e))?;
 
         Ok(BranchFallback {
             pattern,
-            replacement: replacement.clone(),
+            replacement,
+            global,
+            spec: s.to_string(),
+            expanded,
         })
     }
 }
 
-pub fn resolve<'a, T>(
+/* An exact alias, "from=to" */
+#[derive(Clone, Debug)]
+pub struct BranchAlias {
+    pub from: String,
+    pub to: String,
+}
+
+impl BranchAlias {
+    pub fn parse(s: &str) -> Result<BranchAlias, String> {
+        match s.split_once('=') {
+            Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok(BranchAlias {
+                from: from.to_string(),
+                to: to.to_string(),
+            }),
+            _ => Err("Expected format: from=to".to_string()),
+        }
+    }
+}
+
+/* A rule producing a new candidate from a branch name. Regex and alias
+ * rules are applied in the order they were given. */
+#[derive(Clone, Debug)]
+pub enum FallbackRule {
+    Regex(BranchFallback),
+    Alias(BranchAlias),
+}
+
+impl std::fmt::Display for FallbackRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FallbackRule::Regex(fb) => write!(f, "{}", fb),
+            FallbackRule::Alias(alias) => write!(f, "alias:{}={}", alias.from, alias.to),
+        }
+    }
+}
+
+impl FallbackRule {
+    /* Either an "alias:from=to" or a regex rule */
+    pub fn parse(s: &str) -> Result<FallbackRule, String> {
+        match s.strip_prefix("alias:") {
+            Some(alias) => BranchAlias::parse(alias).map(FallbackRule::Alias),
+            None => BranchFallback::parse(s).map(FallbackRule::Regex),
+        }
+    }
+
+    fn apply(&self, cand: &str) -> Option<String> {
+        match self {
+            FallbackRule::Regex(fb) => {
+                let new_cand = if fb.global {
+                    fb.pattern.replace_all(cand, &fb.replacement)
+                } else {
+                    fb.pattern.replace(cand, &fb.replacement)
+                };
+                (new_cand != cand).then(|| new_cand.to_string())
+            }
+            FallbackRule::Alias(alias) => (alias.from == cand).then(|| alias.to.clone()),
+        }
+    }
+}
+
+/* One rule per line, blank lines and lines starting with '#' are skipped */
+pub fn parse_rules(contents: &str) -> Result<Vec<FallbackRule>, String> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(lineno, line)| {
+            FallbackRule::parse(line).map_err(|e| format!("line {}: {}", lineno, e))
+        })
+        .collect()
+}
+
+pub fn load(path: &Path) -> Result<Vec<FallbackRule>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read '{}': {}", path.display(), e))?;
+    parse_rules(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/* How to pick among the candidates the fallback rules produce */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum FallbackStrategy {
+    /* The first existing candidate, in breadth first order */
+    #[default]
+    First,
+    /* The existing candidate with the highest (natural order) name */
+    Best,
+}
+
+/* A candidate branch name that was tried, and the rule that produced it */
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ResolutionStep {
+    pub candidate: String,
+    pub rule: Option<String>,
+    pub found: bool,
+    pub chosen: bool,
+}
+
+/* Returns the selected candidate together with the fallback rule that
+ * produced it (None if it was the target branch itself). */
+pub fn resolve<'a, 'f, T>(
+    target_branch: &'a str,
+    fallbacks: &'f [FallbackRule],
+    available_branches: &HashMap<&'a str, &'a T>,
+    strategy: FallbackStrategy,
+) -> Option<(&'a T, Option<&'f FallbackRule>)> {
+    resolve_traced(target_branch, fallbacks, available_branches, strategy).0
+}
+
+/* Upper limit on the number of candidates tried, rules can rewrite
+ * branch names into ever longer ones. */
+pub const MAX_CANDIDATES: usize = 1000;
+
+/* Like resolve, but also returns every candidate tried, in order */
+pub fn resolve_traced<'a, 'f, T>(
+    target_branch: &'a str,
+    fallbacks: &'f [FallbackRule],
+    available_branches: &HashMap<&'a str, &'a T>,
+    strategy: FallbackStrategy,
+) -> (
+    Option<(&'a T, Option<&'f FallbackRule>)>,
+    Vec<ResolutionStep>,
+) {
+    resolve_refs(
+        target_branch,
+        fallbacks,
+        available_branches,
+        &HashMap::new(),
+        strategy,
+    )
+}
+
+/* Like resolve_traced, but rules rewriting into "tag:NAME" look up NAME
+ * among the tags. Tag candidates are not rewritten any further. */
+pub fn resolve_refs<'a, 'f, T>(
     target_branch: &'a str,
-    fallbacks: &Vec<BranchFallback>,
+    fallbacks: &'f [FallbackRule],
     available_branches: &HashMap<&'a str, &'a T>,
-) -> Option<&'a T> {
+    available_tags: &HashMap<&'a str, &'a T>,
+    strategy: FallbackStrategy,
+) -> (
+    Option<(&'a T, Option<&'f FallbackRule>)>,
+    Vec<ResolutionStep>,
+) {
+    resolve_limited(
+        target_branch,
+        fallbacks,
+        available_branches,
+        available_tags,
+        strategy,
+        MAX_CANDIDATES,
+    )
+}
+
+pub fn resolve_limited<'a, 'f, T>(
+    target_branch: &'a str,
+    fallbacks: &'f [FallbackRule],
+    available_branches: &HashMap<&'a str, &'a T>,
+    available_tags: &HashMap<&'a str, &'a T>,
+    strategy: FallbackStrategy,
+    max_candidates: usize,
+) -> (
+    Option<(&'a T, Option<&'f FallbackRule>)>,
+    Vec<ResolutionStep>,
+) {
+    let mut steps = Vec::new();
     let mut candidates = VecDeque::new();
-    candidates.push_back(target_branch.to_string());
+    let mut seen = HashSet::new();
+    candidates.push_back((target_branch.to_string(), None::<&FallbackRule>));
+    seen.insert(target_branch.to_string());
+
+    /* Index into steps of the chosen candidate so far, and its name */
+    let mut best: Option<(usize, &'a str, &'a T, Option<&'f FallbackRule>)> = None;
 
-    while let Some(cand) = candidates.pop_front() {
+    while let Some((cand, rule)) = candidates.pop_front() {
+        if steps.len() >= max_candidates {
+            warn!(
+                "Gave up resolving '{}' after trying {} candidates",
+                target_branch, max_candidates
+            );
+            break;
+        }
         trace!("Trying: {}", cand);
-        if let Some(b) = available_branches.get(cand.as_str()) {
-            return Some(b);
+        let tagname = cand.strip_prefix("tag:");
+        let found = match tagname {
+            Some(tagname) => available_tags.get_key_value(tagname),
+            None => available_branches.get_key_value(cand.as_str()),
+        };
+        steps.push(ResolutionStep {
+            candidate: cand.clone(),
+            rule: rule.map(|r| r.to_string()),
+            found: found.is_some(),
+            chosen: false,
+        });
+        if let Some((name, b)) = found {
+            let better = match &best {
+                None => true,
+                Some((_, best_name, _, _)) => {
+                    natord::compare(name, best_name) == std::cmp::Ordering::Greater
+                }
+            };
+            if better {
+                best = Some((steps.len() - 1, name, b, rule));
+            }
+            if strategy == FallbackStrategy::First {
+                break;
+            }
+        }
+        if tagname.is_some() {
+            continue;
         }
         for fb in fallbacks {
-            trace!(
-                "Trying transformation: {}  --> {}",
-                fb.pattern.as_str(),
-                fb.replacement
-            );
-            let new_cand = fb.pattern.replace(&cand, &fb.replacement);
-            if new_cand != cand {
+            trace!("Trying transformation: {}", fb);
+            if let Some(new_cand) = fb.apply(&cand) {
                 trace!("Transformed: {} -> {}", cand, new_cand);
-                /* Only allow shorter branches so that it is guarenteede to terminate */
-                if new_cand.len() < cand.len() {
-                    candidates.push_back(new_cand.to_string());
+                /* Each candidate is only tried once, so cycles terminate */
+                if seen.insert(new_cand.clone()) {
+                    candidates.push_back((new_cand, Some(fb)));
                 }
             }
         }
     }
-    None
+
+    match best {
+        Some((idx, _, b, rule)) => {
+            debug!(
+                "Selected {} ({:?} strategy, {} candidates tried)",
+                steps[idx].candidate,
+                strategy,
+                steps.len()
+            );
+            steps[idx].chosen = true;
+            (Some((b, rule)), steps)
+        }
+        None => (None, steps),
+    }
+}
+
+/* How many of the existing branches a NoBranchFound error lists */
+pub const CLOSEST_BRANCHES: usize = 20;
+
+/* No candidate existed. Lists what was tried and the existing branches
+ * sharing the longest prefix with any candidate, so the rules can be
+ * fixed without listing the remote again. */
+#[derive(Debug)]
+pub struct NoBranchFound {
+    pub target: String,
+    pub tried: Vec<String>,
+    pub closest: Vec<String>,
+    pub branch_count: usize,
+}
+
+impl NoBranchFound {
+    pub fn new<'b>(
+        target: &str,
+        steps: &[ResolutionStep],
+        branches: impl IntoIterator<Item = &'b str>,
+    ) -> NoBranchFound {
+        let mut tried: Vec<String> = steps.iter().map(|s| s.candidate.clone()).collect();
+        if tried.is_empty() {
+            tried.push(target.to_string());
+        }
+
+        let common_prefix =
+            |a: &str, b: &str| a.chars().zip(b.chars()).take_while(|(x, y)| x == y).count();
+        let mut branches: Vec<(usize, &str)> = branches
+            .into_iter()
+            .map(|b| {
+                let score = tried
+                    .iter()
+                    .map(|cand| common_prefix(cand, b))
+                    .max()
+                    .unwrap_or(0);
+                (score, b)
+            })
+            .collect();
+        let branch_count = branches.len();
+        branches.sort_by(|(sa, a), (sb, b)| sb.cmp(sa).then_with(|| natord::compare(a, b)));
+
+        NoBranchFound {
+            target: target.to_string(),
+            tried,
+            closest: branches
+                .into_iter()
+                .take(CLOSEST_BRANCHES)
+                .map(|(_, b)| b.to_string())
+                .collect(),
+            branch_count,
+        }
+    }
 }
 
+impl std::fmt::Display for NoBranchFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "No suitable branch found for '{}'", self.target)?;
+        if self.tried.len() > 1 {
+            write!(f, ". Tried: {}", self.tried.join(", "))?;
+        }
+        match self.branch_count {
+            0 => write!(f, ". There are no branches on the remote"),
+            n if n > self.closest.len() => write!(
+                f,
+                ". Closest of the {} branches on the remote: {}",
+                n,
+                self.closest.join(", ")
+            ),
+            _ => write!(f, ". Branches on the remote: {}", self.closest.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for NoBranchFound {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -169,9 +542,347 @@ mod tests {
         );
     }
 
+    #[test]
+    fn reports_matching_rule() {
+        let fallbacks = vec![
+            FallbackRule::parse("/-rc$//").unwrap(),
+            FallbackRule::parse("/(.*)-[^-]*$/$1/").unwrap(),
+        ];
+        let available = HashMap::from([("release", &1), ("release-4", &2)]);
+
+        let (b, rule) =
+            resolve("release-4", &fallbacks, &available, FallbackStrategy::First).unwrap();
+        assert_eq!((*b, rule.map(|r| r.to_string())), (2, None));
+
+        let (b, rule) = resolve(
+            "release-4-rc",
+            &fallbacks,
+            &available,
+            FallbackStrategy::First,
+        )
+        .unwrap();
+        assert_eq!(
+            (*b, rule.map(|r| r.to_string())),
+            (2, Some("/-rc$//".to_string()))
+        );
+
+        let (b, rule) = resolve(
+            "release-4-x",
+            &fallbacks,
+            &available,
+            FallbackStrategy::First,
+        )
+        .unwrap();
+        assert_eq!(
+            (*b, rule.map(|r| r.to_string())),
+            (2, Some("/(.*)-[^-]*$/$1/".to_string()))
+        );
+
+        assert!(resolve("main", &fallbacks, &available, FallbackStrategy::First).is_none());
+    }
+
     #[test]
     fn error_on_empty_input() {
         let err = BranchFallback::parse("").unwrap_err();
         assert_eq!(err, "Empty fallback string");
     }
+
+    #[test]
+    fn traces_candidates_in_order() {
+        let fallbacks = vec![
+            FallbackRule::parse("/-rc$//").unwrap(),
+            FallbackRule::parse("/(.*)-[^-]*$/$1/").unwrap(),
+        ];
+        let available = HashMap::from([("release", &1)]);
+
+        let (b, steps) = resolve_traced(
+            "release-4-rc",
+            &fallbacks,
+            &available,
+            FallbackStrategy::First,
+        );
+        assert_eq!(b.map(|(b, _)| *b), Some(1));
+        let steps: Vec<(&str, Option<&str>, bool)> = steps
+            .iter()
+            .map(|s| (s.candidate.as_str(), s.rule.as_deref(), s.found))
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                ("release-4-rc", None, false),
+                ("release-4", Some("/-rc$//"), false),
+                ("release", Some("/(.*)-[^-]*$/$1/"), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_rules_file() {
+        let rules = parse_rules(
+            r"
+# strip release candidate suffix
+/-rc\d*$//
+
+  %(.*)-[^-]*$%$1%
+",
+        )
+        .expect("should parse");
+        let specs: Vec<String> = rules.iter().map(|r| r.to_string()).collect();
+        assert_eq!(specs, [r"/-rc\d*$//", "%(.*)-[^-]*$%$1%"]);
+    }
+
+    #[test]
+    fn rules_file_error_has_line_number() {
+        let err = parse_rules("# comment\n/ok//\n/broken/\n").unwrap_err();
+        assert!(err.starts_with("line 3: Expected format"), "Got: {}", err);
+    }
+
+    #[test]
+    fn cycle_terminates() {
+        let fallbacks = vec![
+            FallbackRule::parse("/^a$/b/").unwrap(),
+            FallbackRule::parse("/^b$/a/").unwrap(),
+        ];
+        let available = HashMap::from([("c", &1)]);
+
+        let (b, steps) = resolve_traced("a", &fallbacks, &available, FallbackStrategy::First);
+        assert!(b.is_none());
+        assert_eq!(steps.len(), 2);
+    }
+
+    #[test]
+    fn lengthening_rewrite() {
+        let fallbacks = vec![FallbackRule::parse(r"#^release/(\d+)$#maintenance/$1#").unwrap()];
+        let available = HashMap::from([("maintenance/4", &1)]);
+
+        let (b, rule) =
+            resolve("release/4", &fallbacks, &available, FallbackStrategy::First).unwrap();
+        assert_eq!(*b, 1);
+        assert!(rule.is_some());
+    }
+
+    #[test]
+    fn candidate_limit() {
+        let fallbacks = vec![FallbackRule::parse("/$/x/").unwrap()];
+        let available = HashMap::from([("never", &1)]);
+
+        let (b, steps) = resolve_limited(
+            "a",
+            &fallbacks,
+            &available,
+            &HashMap::new(),
+            FallbackStrategy::First,
+            10,
+        );
+        assert!(b.is_none());
+        assert_eq!(steps.len(), 10);
+    }
+
+    #[test]
+    fn parses_flags() {
+        let fallback = BranchFallback::parse(r"/release\/(.*)/rel-$1/ia").expect("should parse");
+        assert_eq!(
+            fallback
+                .pattern
+                .replace("Release/4.2", &fallback.replacement),
+            "rel-4.2"
+        );
+        assert_eq!(
+            fallback
+                .pattern
+                .replace("feature/release/4.2", &fallback.replacement),
+            "feature/release/4.2"
+        );
+
+        let err = BranchFallback::parse("/a/b/x").unwrap_err();
+        assert!(err.starts_with("Unknown flag 'x'"), "Got: {}", err);
+    }
+
+    #[test]
+    fn best_strategy_picks_highest() {
+        let fallbacks = vec![
+            FallbackRule::parse(r"/^release\/(\d+)\.(\d+)\.\d+$/release\/$1.$2/").unwrap(),
+            FallbackRule::parse(r"/^release\/(\d+)\..*$/release\/$1/").unwrap(),
+        ];
+        let available = HashMap::from([("release/4", &1), ("release/4.2", &2)]);
+
+        let (b, steps) = resolve_traced(
+            "release/4.2.1",
+            &fallbacks,
+            &available,
+            FallbackStrategy::Best,
+        );
+        assert_eq!(b.map(|(b, _)| *b), Some(2));
+        let chosen: Vec<&str> = steps
+            .iter()
+            .filter(|s| s.chosen)
+            .map(|s| s.candidate.as_str())
+            .collect();
+        assert_eq!(chosen, ["release/4.2"]);
+
+        /* First takes release/4, generated at the same level but earlier */
+        let available = HashMap::from([("release/4", &1), ("release/10", &2)]);
+        let fallbacks = vec![
+            FallbackRule::parse("/^x$/release\\/4/").unwrap(),
+            FallbackRule::parse("/^x$/release\\/10/").unwrap(),
+        ];
+        let first = resolve("x", &fallbacks, &available, FallbackStrategy::First);
+        let best = resolve("x", &fallbacks, &available, FallbackStrategy::Best);
+        assert_eq!(first.map(|(b, _)| *b), Some(1));
+        assert_eq!(best.map(|(b, _)| *b), Some(2));
+    }
+
+    #[test]
+    fn aliases_mix_with_regex_rules() {
+        let fallbacks = vec![
+            FallbackRule::parse("alias:customer-foo=main").unwrap(),
+            FallbackRule::parse("/-foo$//").unwrap(),
+        ];
+        let available = HashMap::from([("main", &1), ("customer", &2)]);
+
+        let (b, rule) = resolve(
+            "customer-foo",
+            &fallbacks,
+            &available,
+            FallbackStrategy::First,
+        )
+        .unwrap();
+        assert_eq!(
+            (*b, rule.map(|r| r.to_string())),
+            (1, Some("alias:customer-foo=main".to_string()))
+        );
+
+        /* Exact match only, the '.' isn't a wildcard */
+        let fallbacks = vec![FallbackRule::parse("alias:a.b=main").unwrap()];
+        assert!(resolve("axb", &fallbacks, &available, FallbackStrategy::First).is_none());
+
+        assert!(FallbackRule::parse("alias:nothing").is_err());
+    }
+
+    #[test]
+    fn global_flag_replaces_all_matches() {
+        let candidates = |spec: &str| {
+            let fallbacks = vec![FallbackRule::parse(spec).unwrap()];
+            let available: HashMap<&str, &()> = HashMap::new();
+            let (_, steps) = resolve_traced(
+                "release/4.2-rc1/hotfix-rc2",
+                &fallbacks,
+                &available,
+                FallbackStrategy::First,
+            );
+            steps.into_iter().map(|s| s.candidate).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            candidates(r"/-rc\d+//"),
+            [
+                "release/4.2-rc1/hotfix-rc2",
+                "release/4.2/hotfix-rc2",
+                "release/4.2/hotfix"
+            ]
+        );
+        assert_eq!(
+            candidates(r"/-rc\d+//g"),
+            ["release/4.2-rc1/hotfix-rc2", "release/4.2/hotfix"]
+        );
+    }
+
+    #[test]
+    fn named_groups_in_replacement() {
+        let fallback = BranchFallback::parse(r"/^release-(?P<ver>\d+)$/rel\/$ver-${ver}/")
+            .expect("should parse");
+        assert_eq!(
+            fallback.pattern.replace("release-4", &fallback.replacement),
+            "rel/4-4"
+        );
+    }
+
+    #[test]
+    fn expands_environment_in_replacement() {
+        let env = HashMap::from([("SLEIPNIR_TEST_TARGET", "acme$1")]);
+        let parse =
+            |s| BranchFallback::parse_with_env(s, |name| env.get(name).map(|v| v.to_string()));
+        let fallback =
+            parse(r"/^(.*)$/integration\/${env:SLEIPNIR_TEST_TARGET}/").expect("should parse");
+        assert_eq!(
+            fallback.pattern.replace("x", &fallback.replacement),
+            "integration/acme$1"
+        );
+        assert_eq!(
+            fallback.to_string(),
+            r"/^(.*)$/integration\/${env:SLEIPNIR_TEST_TARGET}/ (replacement: integration/acme$1)"
+        );
+
+        let err = parse("/a/${env:SLEIPNIR_TEST_UNSET}/").unwrap_err();
+        assert_eq!(err, "Environment variable 'SLEIPNIR_TEST_UNSET' is not set");
+    }
+
+    #[test]
+    fn rewrites_into_tag_namespace() {
+        let fallbacks = vec![
+            FallbackRule::parse(r"/^release\/(.*)$/tag:v$1/").unwrap(),
+            FallbackRule::parse("/^tag:.*$/main/").unwrap(),
+        ];
+        let branches = HashMap::from([("main", &1)]);
+        let tags = HashMap::from([("v4.2", &2)]);
+
+        let (b, steps) = resolve_refs(
+            "release/4.2",
+            &fallbacks,
+            &branches,
+            &tags,
+            FallbackStrategy::First,
+        );
+        assert_eq!(b.map(|(b, _)| *b), Some(2));
+        assert_eq!(steps[1].candidate, "tag:v4.2");
+
+        /* Without the tag the tag candidate is a dead end */
+        let (b, steps) = resolve_refs(
+            "release/4.3",
+            &fallbacks,
+            &branches,
+            &tags,
+            FallbackStrategy::First,
+        );
+        assert!(b.is_none());
+        assert_eq!(steps.len(), 2);
+    }
+
+    #[test]
+    fn no_branch_found_lists_closest() {
+        let rules = vec![FallbackRule::parse("/feature/release/").unwrap()];
+        let branches: Vec<String> = (0..30)
+            .map(|i| format!("other-{}", i))
+            .chain(["feat", "release-2", "feature2", "main"].map(String::from))
+            .collect();
+        let available: HashMap<&str, &()> = branches.iter().map(|b| (b.as_str(), &())).collect();
+        let (found, steps) =
+            resolve_traced("feature-1", &rules, &available, FallbackStrategy::First);
+        assert!(found.is_none());
+
+        let err = NoBranchFound::new("feature-1", &steps, available.keys().copied());
+        assert_eq!(err.tried, ["feature-1", "release-1"]);
+        assert_eq!(err.branch_count, 34);
+        assert_eq!(err.closest.len(), CLOSEST_BRANCHES);
+        assert_eq!(err.closest[..4], ["release-2", "feature2", "feat", "main"]);
+        assert_eq!(err.closest[4], "other-0");
+        assert!(err
+            .to_string()
+            .starts_with("No suitable branch found for 'feature-1'. Tried: feature-1, release-1. Closest of the 34 branches on the remote: release-2, feature2, feat, main, other-0, "));
+    }
+
+    #[test]
+    fn no_branch_found_few_branches() {
+        let err = NoBranchFound::new("dev", &[], ["main", "develop"]);
+        assert_eq!(
+            err.to_string(),
+            "No suitable branch found for 'dev'. Branches on the remote: develop, main"
+        );
+
+        let err = NoBranchFound::new("dev", &[], []);
+        assert_eq!(
+            err.to_string(),
+            "No suitable branch found for 'dev'. There are no branches on the remote"
+        );
+    }
 }
@@ -0,0 +1,115 @@
+use std::io::Write;
+use std::path::Path;
+
+/* Results as key/value outputs for CI systems: GitHub Actions step
+ * outputs ($GITHUB_OUTPUT) and KEY=value files for the others */
+#[derive(Debug, Default)]
+pub struct CiOutputs {
+    entries: Vec<(String, String)>,
+}
+
+/* Repository names become part of keys, which only keep what shells and
+ * dotenv parsers accept in a variable name */
+fn key_name(key: &str) -> String {
+    key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+impl CiOutputs {
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.entries.push((key_name(key), value.to_string()));
+    }
+
+    /* Multiline values use GitHub's heredoc syntax, with a delimiter
+     * that isn't part of the value */
+    pub fn github_format(&self) -> String {
+        let mut out = String::new();
+        for (key, value) in &self.entries {
+            if value.contains(['\n', '\r']) {
+                let mut delimiter = "SLEIPNIR_EOF".to_string();
+                while value.contains(&delimiter) {
+                    delimiter.push('_');
+                }
+                out.push_str(&format!("{key}<<{delimiter}\n{value}\n{delimiter}\n"));
+            } else {
+                out.push_str(&format!("{key}={value}\n"));
+            }
+        }
+        out
+    }
+
+    /* Multiline values are double quoted with escapes, as dotenv files
+     * have them */
+    pub fn env_format(&self) -> String {
+        let mut out = String::new();
+        for (key, value) in &self.entries {
+            if value.contains(['\n', '\r']) {
+                let escaped = value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n")
+                    .replace('\r', "\\r");
+                out.push_str(&format!("{key}=\"{escaped}\"\n"));
+            } else {
+                out.push_str(&format!("{key}={value}\n"));
+            }
+        }
+        out
+    }
+
+    /* $GITHUB_OUTPUT is shared by the whole step, so it's appended to */
+    pub fn append_github(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?
+            .write_all(self.github_format().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outputs() -> CiOutputs {
+        let mut outputs = CiOutputs::default();
+        outputs.set("tag", "v1.2");
+        outputs.set("tag_my-repo.js", "v1.1");
+        outputs.set("failed_repos", "");
+        outputs
+    }
+
+    #[test]
+    fn formats_outputs() {
+        let expected = "tag=v1.2\ntag_my_repo_js=v1.1\nfailed_repos=\n";
+        assert_eq!(outputs().github_format(), expected);
+        assert_eq!(outputs().env_format(), expected);
+    }
+
+    #[test]
+    fn multiline_values() {
+        let mut outputs = CiOutputs::default();
+        outputs.set("errors", "a: \"failed\"\nSLEIPNIR_EOF\\");
+        assert_eq!(
+            outputs.github_format(),
+            "errors<<SLEIPNIR_EOF_\na: \"failed\"\nSLEIPNIR_EOF\\\nSLEIPNIR_EOF_\n"
+        );
+        assert_eq!(
+            outputs.env_format(),
+            "errors=\"a: \\\"failed\\\"\\nSLEIPNIR_EOF\\\\\"\n"
+        );
+    }
+
+    #[test]
+    fn appends_to_github_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output");
+        std::fs::write(&path, "earlier=1\n").unwrap();
+        outputs().append_github(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "earlier=1\ntag=v1.2\ntag_my_repo_js=v1.1\nfailed_repos=\n"
+        );
+    }
+}
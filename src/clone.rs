@@ -0,0 +1,1819 @@
+//! Cloning a repository at the right branch, with just enough history
+//! to find its latest tag
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::num::NonZeroUsize;
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+
+use log::{debug, info, warn};
+use regex::Regex;
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+use crate::branch_fallback::{self, FallbackRule, FallbackStrategy, NoBranchFound, ResolutionStep};
+use crate::git_http_client::{Deepen, GitClient, GitClientError, TlsSettings, Transport};
+use crate::local_repo::{
+    CleanupGuard, GitSettings, LocalRepo, LocalRepoError, PeeledTag, RevListOptions,
+};
+use crate::refs_cache::{DiskCache, RefsCache};
+use crate::tag_order::{TagOrder, TagSelector, DEFAULT_PRERELEASE_PATTERN};
+use crate::util::{anonymous_url, masked_url};
+use crate::RefInfo;
+
+/// How repositories are cloned. The defaults are those of the
+/// command line.
+#[derive(Clone, Debug)]
+pub struct CloneOptions {
+    /// The branch (or `refs/tags/` tag) wanted, for the targets without
+    /// one of their own. Not needed with `tags_only` or pinned targets
+    pub branch: Option<String>,
+    /// Fallback rules to try when it doesn't exist
+    pub rules: Vec<FallbackRule>,
+    pub fallback_strategy: FallbackStrategy,
+    /// Branches never used
+    pub excludes: Vec<Regex>,
+    /// Used when neither the branch nor a fallback exists
+    pub default_branch: Option<String>,
+    /// A tag with the branch's name will do as well
+    pub allow_tag_as_branch: bool,
+    /// Fetch all branches listed along with the selected one
+    pub all_matching_branches: bool,
+    /// Only list branches starting with this
+    pub branches_starting_with: Option<String>,
+    /// Only list tags starting with this
+    pub tags_starting_with: Option<String>,
+    pub depth: NonZeroUsize,
+    pub deepen_step: NonZeroUsize,
+    /// Double the depth instead of adding `deepen_step`
+    pub deepen_exponential: bool,
+    pub max_depth: usize,
+    pub first_parent: bool,
+    /// Look for tags in at most this many commits
+    pub max_count: Option<NonZeroUsize>,
+    pub tag_order: TagOrder,
+    pub tag_prefix: Option<String>,
+    pub keep_tag_prefix: bool,
+    pub tag_includes: Vec<Regex>,
+    pub tag_excludes: Vec<Regex>,
+    pub prerelease_pattern: Regex,
+    pub include_prereleases: bool,
+    /// Succeed without a tag if none is reachable
+    pub allow_no_tag: bool,
+    /// Don't look for tags at all
+    pub no_tags: bool,
+    /// Only list the tags, no branch is needed
+    pub tags_only: bool,
+    /// Fetch into an existing repository instead of failing
+    pub update: bool,
+    /// Remove an existing repository first
+    pub force: bool,
+    /// Rename a failed clone aside instead of removing it
+    pub keep_failed: bool,
+    pub bare: bool,
+    pub no_checkout: bool,
+    /// Don't configure the remote "origin"
+    pub no_set_remote: bool,
+    /// Check that all objects of the fetched history are present
+    pub verify: bool,
+    /// Repositories to borrow objects from
+    pub references: Vec<PathBuf>,
+    /// Borrow objects from the repository with the same name in here
+    pub reference_base: Option<PathBuf>,
+    /// Copy the borrowed objects
+    pub dissociate: bool,
+    pub initial_branch: Option<String>,
+    /// How git is run and packs are received
+    pub git: GitSettings,
+    /// Keep the listed refs here too, for later runs
+    pub refs_cache: Option<DiskCache>,
+    /// List the refs every time they are needed
+    pub no_cache: bool,
+    /// Certificates for https
+    pub tls: TlsSettings,
+}
+
+impl Default for CloneOptions {
+    fn default() -> Self {
+        CloneOptions {
+            branch: None,
+            rules: Vec::new(),
+            fallback_strategy: FallbackStrategy::First,
+            excludes: Vec::new(),
+            default_branch: None,
+            allow_tag_as_branch: false,
+            all_matching_branches: false,
+            branches_starting_with: None,
+            tags_starting_with: None,
+            depth: NonZeroUsize::MIN,
+            deepen_step: NonZeroUsize::new(50).unwrap(),
+            deepen_exponential: false,
+            max_depth: 10000,
+            first_parent: false,
+            max_count: None,
+            tag_order: TagOrder::Natural,
+            tag_prefix: None,
+            keep_tag_prefix: false,
+            tag_includes: Vec::new(),
+            tag_excludes: Vec::new(),
+            prerelease_pattern: Regex::new(DEFAULT_PRERELEASE_PATTERN).unwrap(),
+            include_prereleases: false,
+            allow_no_tag: false,
+            no_tags: false,
+            tags_only: false,
+            update: false,
+            force: false,
+            keep_failed: false,
+            bare: false,
+            no_checkout: false,
+            no_set_remote: false,
+            verify: false,
+            references: Vec::new(),
+            reference_base: None,
+            dissociate: false,
+            initial_branch: None,
+            git: GitSettings::default(),
+            refs_cache: None,
+            no_cache: false,
+            tls: TlsSettings::default(),
+        }
+    }
+}
+
+impl CloneOptions {
+    /// A client keeping the listed refs and connecting as these options say
+    pub fn git_client(&self) -> Result<GitClient, GitClientError> {
+        GitClient::with_settings(
+            RefsCache::new(!self.no_cache, self.refs_cache.clone()),
+            &self.tls,
+        )
+    }
+
+    /// How tags are filtered and compared
+    pub fn tag_selector(&self) -> TagSelector<'_> {
+        TagSelector {
+            order: self.tag_order,
+            prefix: self.tag_prefix.as_deref(),
+            keep_prefix: self.keep_tag_prefix,
+            includes: &self.tag_includes,
+            excludes: &self.tag_excludes,
+            prerelease: Some(&self.prerelease_pattern).filter(|_| !self.include_prereleases),
+            times: None,
+        }
+    }
+
+    fn rev_list_options(&self) -> RevListOptions {
+        RevListOptions {
+            first_parent: self.first_parent,
+            max_count: self.max_count.map(NonZeroUsize::get),
+        }
+    }
+}
+
+/// Why a clone failed
+#[derive(Debug)]
+pub enum CloneError {
+    /// Talking to the server failed
+    Remote(GitClientError),
+    /// Working on the local repository failed
+    Local(LocalRepoError),
+    /// Neither the branch nor any fallback exists
+    NoBranch(NoBranchFound),
+    /// Neither the target nor the options name a branch
+    NoBranchGiven(String),
+    /// No acceptable tag is reachable (and `allow_no_tag` isn't set)
+    NoTag {
+        refname: String,
+        repo: String,
+        /* How far was searched */
+        searched: String,
+    },
+    /// An object of a tag is missing even after fetching it
+    IncompleteTag {
+        tag: String,
+        object: String,
+    },
+    /// The pinned commit isn't part of the history of its ref (any more)
+    PinnedMissing {
+        refname: String,
+        sha: String,
+        repo: String,
+    },
+    /// `verify` found objects missing
+    Incomplete {
+        refname: String,
+        error: LocalRepoError,
+    },
+    Io(io::Error),
+    /// The cancellation token was cancelled
+    Cancelled,
+}
+
+impl fmt::Display for CloneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CloneError::Remote(e) => write!(f, "{}", e),
+            CloneError::Local(e) => write!(f, "{}", e),
+            CloneError::NoBranch(e) => write!(f, "{}", e),
+            CloneError::NoBranchGiven(repo) => write!(f, "No branch given for {}", repo),
+            CloneError::NoTag {
+                refname,
+                repo,
+                searched,
+            } => write!(
+                f,
+                "No tag reachable from {} in {} ({}, use --allow-no-tag to accept this)",
+                refname, repo, searched
+            ),
+            CloneError::IncompleteTag { tag, object } => {
+                write!(f, "Tag {} is missing object {} after fetch", tag, object)
+            }
+            CloneError::PinnedMissing { refname, sha, repo } => {
+                write!(f, "{} is not reachable from {} in {}", sha, refname, repo)
+            }
+            CloneError::Incomplete { refname, error } => {
+                write!(f, "{} is incomplete after fetching: {}", refname, error)
+            }
+            CloneError::Io(e) => write!(f, "{}", e),
+            CloneError::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+
+impl Error for CloneError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CloneError::Remote(e) => Some(e),
+            CloneError::Local(e) => Some(e),
+            CloneError::NoBranch(e) => Some(e),
+            CloneError::Incomplete { error, .. } => Some(error),
+            CloneError::Io(e) => Some(e),
+            CloneError::NoBranchGiven(_)
+            | CloneError::NoTag { .. }
+            | CloneError::IncompleteTag { .. }
+            | CloneError::PinnedMissing { .. }
+            | CloneError::Cancelled => None,
+        }
+    }
+}
+
+impl CloneError {
+    /// The server couldn't be reached or failed, a mirror might not
+    pub fn is_unavailable(&self) -> bool {
+        matches!(self, CloneError::Remote(e) if e.is_unavailable())
+    }
+}
+
+impl From<GitClientError> for CloneError {
+    fn from(e: GitClientError) -> Self {
+        CloneError::Remote(e)
+    }
+}
+
+impl From<LocalRepoError> for CloneError {
+    fn from(e: LocalRepoError) -> Self {
+        CloneError::Local(e)
+    }
+}
+
+impl From<NoBranchFound> for CloneError {
+    fn from(e: NoBranchFound) -> Self {
+        CloneError::NoBranch(e)
+    }
+}
+
+impl From<io::Error> for CloneError {
+    fn from(e: io::Error) -> Self {
+        CloneError::Io(e)
+    }
+}
+
+/// A repository to clone and where to put it
+#[derive(Clone, Debug)]
+pub struct CloneTarget {
+    /// Used in messages and for finding it under `reference_base`
+    pub name: String,
+    pub url: Url,
+    /// Tried in order instead of `url` when its server can't be reached
+    /// or fails
+    pub mirrors: Vec<Url>,
+    /// The directory of the clone
+    pub dest: PathBuf,
+    /// Check out into a linked worktree here instead
+    pub worktree: Option<PathBuf>,
+    /// Overrides `CloneOptions::branch`
+    pub branch: Option<String>,
+    /// Overrides `CloneOptions::branches_starting_with`
+    pub branches_starting_with: Option<String>,
+    /// Overrides `CloneOptions::tags_starting_with`
+    pub tags_starting_with: Option<String>,
+    /// Clone this commit instead of selecting a branch
+    pub pinned: Option<PinnedRef>,
+    /// The refs listed ahead of time with [`CloneTarget::wanted_refs`],
+    /// they aren't listed again
+    pub refs: Option<Vec<RefInfo>>,
+}
+
+/// A ref as it was resolved earlier, e.g. by a previous clone
+#[derive(Clone, Debug)]
+pub struct PinnedRef {
+    pub refname: String,
+    /// The commit checked out
+    pub sha: String,
+}
+
+impl CloneTarget {
+    /// Clones `url` into `dest`, with `dest`'s file name as the name
+    pub fn new(url: Url, dest: PathBuf) -> Self {
+        CloneTarget {
+            name: dest
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            url,
+            mirrors: Vec::new(),
+            dest,
+            worktree: None,
+            branch: None,
+            branches_starting_with: None,
+            tags_starting_with: None,
+            pinned: None,
+            refs: None,
+        }
+    }
+
+    /// `url` followed by the mirrors
+    pub fn urls(&self) -> impl Iterator<Item = &Url> {
+        std::iter::once(&self.url).chain(&self.mirrors)
+    }
+
+    /// The branch wanted: the target's own or else `opts.branch`
+    pub fn wanted_branch<'a>(&'a self, opts: &'a CloneOptions) -> Result<&'a str, CloneError> {
+        self.branch
+            .as_deref()
+            .or(opts.branch.as_deref())
+            .ok_or_else(|| CloneError::NoBranchGiven(self.name.clone()))
+    }
+
+    /// The ref prefixes to list on the server
+    pub fn wanted_refs(&self, opts: &CloneOptions) -> Vec<String> {
+        let mut wanted_refs = Vec::new();
+        if opts.tags_only {
+            /* No branch is needed, only the tags */
+        } else {
+            match self
+                .branches_starting_with
+                .as_ref()
+                .or(opts.branches_starting_with.as_ref())
+            {
+                Some(branches_starting_with) => {
+                    wanted_refs.push(format!("refs/heads/{}", branches_starting_with))
+                }
+                None => wanted_refs.push("refs/heads/".to_string()),
+            }
+        }
+        if opts.no_tags {
+            return wanted_refs;
+        }
+        match self
+            .tags_starting_with
+            .as_ref()
+            .or(opts.tags_starting_with.as_ref())
+        {
+            Some(tags_starting_with) => {
+                wanted_refs.push(format!("refs/tags/{}", tags_starting_with))
+            }
+            None => wanted_refs.push("refs/tags/".to_string()),
+        }
+        wanted_refs
+    }
+
+    /* Object directories of the --reference repositories that exist */
+    fn reference_objects_dirs(&self, opts: &CloneOptions) -> Vec<PathBuf> {
+        let mut result = Vec::new();
+        for path in &opts.references {
+            match LocalRepo::reference_objects_dir(path) {
+                Some(dir) => result.push(dir),
+                None => warn!(
+                    "Reference repository {} not found, ignoring it",
+                    path.display()
+                ),
+            }
+        }
+        if let Some(base) = &opts.reference_base {
+            let candidates = [
+                base.join(&self.name),
+                base.join(format!("{}.git", self.name)),
+            ];
+            match candidates
+                .iter()
+                .find_map(|path| LocalRepo::reference_objects_dir(path))
+            {
+                Some(dir) => result.push(dir),
+                None => warn!(
+                    "No reference repository for {} in {}, ignoring it",
+                    self.name,
+                    base.display()
+                ),
+            }
+        }
+        result
+    }
+}
+
+/// How the branch was chosen
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MatchedBy {
+    Exact,
+    Fallback(String),
+    DefaultBranch,
+    /// `CloneTarget::pinned`
+    Pinned,
+}
+
+impl MatchedBy {
+    pub fn fallback(&self) -> Option<&str> {
+        match self {
+            MatchedBy::Fallback(rule) => Some(rule),
+            _ => None,
+        }
+    }
+}
+
+/// `exact`, `fallback:<rule>`, `default-branch` or `pinned`
+impl fmt::Display for MatchedBy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MatchedBy::Exact => write!(f, "exact"),
+            MatchedBy::Fallback(rule) => write!(f, "fallback:{}", rule),
+            MatchedBy::DefaultBranch => write!(f, "default-branch"),
+            MatchedBy::Pinned => write!(f, "pinned"),
+        }
+    }
+}
+
+/// Which tags `select_branch` takes for a branch name
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TagLookup {
+    /// Only `refs/tags/...` names and `tag:` fallback rules
+    No,
+    /// The name itself, when there is no such branch
+    Exact,
+    /// The name and every fallback candidate, a branch of the same name
+    /// winning over the tag
+    Candidates,
+}
+
+impl TagLookup {
+    pub fn exact_if(allow_tags: bool) -> Self {
+        if allow_tags {
+            TagLookup::Exact
+        } else {
+            TagLookup::No
+        }
+    }
+}
+
+/// Picks the ref to clone from `refs`: `target_branch` if it exists,
+/// otherwise the first (or, depending on `strategy`, best) fallback, and
+/// `default_branch` as the last resort. `tags` says whether tags can
+/// stand in for branches. `steps` receives the candidates that were
+/// tried.
+#[allow(clippy::too_many_arguments)]
+pub fn select_branch<'a>(
+    refs: &'a [RefInfo],
+    target_branch: &'a str,
+    fallbacks: &[FallbackRule],
+    strategy: FallbackStrategy,
+    excludes: &[Regex],
+    default_branch: Option<&'a str>,
+    tags: TagLookup,
+    steps: &mut Vec<ResolutionStep>,
+) -> Option<(&'a RefInfo, MatchedBy)> {
+    let mut available_branches = HashMap::<&str, &RefInfo>::new();
+    let mut excluded_branches = HashMap::<&str, &RefInfo>::new();
+    let mut available_tags = HashMap::<&str, &RefInfo>::new();
+    for r in refs {
+        if let Some(branchname) = r.refname.strip_prefix("refs/heads/") {
+            if excludes.iter().any(|re| re.is_match(branchname)) {
+                excluded_branches.insert(branchname, r);
+            } else {
+                available_branches.insert(branchname, r);
+            }
+        } else if let Some(tagname) = r.refname.strip_prefix("refs/tags/") {
+            available_tags.insert(tagname, r);
+        }
+    }
+
+    /* Fully qualified refnames are taken verbatim */
+    if let Some(tagname) = target_branch.strip_prefix("refs/tags/") {
+        return available_tags.get(tagname).map(|t| (*t, MatchedBy::Exact));
+    }
+    let target_branch = target_branch
+        .strip_prefix("refs/heads/")
+        .unwrap_or(target_branch);
+
+    if tags == TagLookup::Exact && !available_branches.contains_key(target_branch) {
+        if let Some(t) = available_tags.get(target_branch) {
+            debug!("Found tag: {:?}", t);
+            return Some((t, MatchedBy::Exact));
+        }
+    }
+
+    /* The branches last, so they replace tags of the same name */
+    let candidates: HashMap<&str, &RefInfo> = match tags {
+        TagLookup::Candidates => available_tags
+            .iter()
+            .chain(available_branches.iter())
+            .map(|(k, v)| (*k, *v))
+            .collect(),
+        TagLookup::No | TagLookup::Exact => available_branches.clone(),
+    };
+    let (branch, trace) = branch_fallback::resolve_refs(
+        target_branch,
+        fallbacks,
+        &candidates,
+        &available_tags,
+        strategy,
+    );
+    *steps = trace;
+    let branch = branch.map(|(b, rule)| match rule {
+        Some(rule) => (b, MatchedBy::Fallback(rule.to_string())),
+        None => (b, MatchedBy::Exact),
+    });
+    debug!("Found branch: {:?}", branch);
+
+    if !excluded_branches.is_empty() {
+        /* Only worth mentioning if the exclusion changed the outcome */
+        let all_branches: HashMap<&str, &RefInfo> = available_branches
+            .iter()
+            .chain(excluded_branches.iter())
+            .map(|(k, v)| (*k, *v))
+            .collect();
+        if let Some((b, _)) =
+            branch_fallback::resolve(target_branch, fallbacks, &all_branches, strategy)
+        {
+            if excluded_branches.contains_key(short_refname(&b.refname)) {
+                debug!(
+                    "Skipped excluded branch {} that would have matched",
+                    b.refname
+                );
+            }
+        }
+    }
+
+    branch.or_else(|| {
+        default_branch
+            .and_then(|d| available_branches.get(d).copied())
+            .map(|b| (b, MatchedBy::DefaultBranch))
+    })
+}
+
+/// The error for when `select_branch` found nothing
+pub fn no_branch_found(
+    refs: &[RefInfo],
+    target_branch: &str,
+    steps: &[ResolutionStep],
+) -> NoBranchFound {
+    let branches = refs
+        .iter()
+        .filter_map(|r| r.refname.strip_prefix("refs/heads/"));
+    NoBranchFound::new(target_branch, steps, branches)
+}
+
+/// The refname without `refs/heads/` or `refs/tags/`
+pub fn short_refname(refname: &str) -> &str {
+    refname
+        .strip_prefix("refs/heads/")
+        .or_else(|| refname.strip_prefix("refs/tags/"))
+        .unwrap_or(refname)
+}
+
+/// The branch selected for a repository
+#[derive(Debug, Serialize)]
+pub struct FoundBranch {
+    /// e.g. `refs/heads/main`
+    pub refname: String,
+    /// The same without `refs/heads/` (or `refs/tags/`)
+    pub branch: String,
+    /// The commit it points to
+    pub sha: String,
+    /// The fallback rule that picked it, if any
+    pub fallback: Option<String>,
+    pub resolution: Vec<ResolutionStep>,
+}
+
+/// Lists the refs of `target` a clone starts from, at a mirror if its
+/// server is unavailable
+pub async fn list_refs(
+    client: &GitClient,
+    target: &CloneTarget,
+    opts: &CloneOptions,
+) -> Result<Vec<RefInfo>, CloneError> {
+    let (refs, _) = with_failover(target, |url| {
+        let remote_repo = client.for_url(url);
+        async move { Ok(list_refs_with(&remote_repo, target, opts).await?) }
+    })
+    .await?;
+    Ok(refs)
+}
+
+async fn list_refs_with<T: Transport>(
+    remote_repo: &T,
+    target: &CloneTarget,
+    opts: &CloneOptions,
+) -> Result<Vec<RefInfo>, GitClientError> {
+    if let Some(refs) = &target.refs {
+        return Ok(refs.clone());
+    }
+    let wanted_refs = target.wanted_refs(opts);
+    debug!("Listing remote refs (wanted refs: {:?})", wanted_refs);
+    remote_repo.ls_refs(&wanted_refs).await
+}
+
+/// Selects the branch [`clone_repo`] would clone, only listing the refs
+/// of the remote repository
+pub async fn find_branch(
+    target: &CloneTarget,
+    opts: &CloneOptions,
+) -> Result<FoundBranch, CloneError> {
+    let client = opts.git_client()?;
+    let (found, _) = with_failover(target, |url| {
+        let remote_repo = client.for_url(url);
+        async move { find_branch_with(&remote_repo, target, opts).await }
+    })
+    .await?;
+    Ok(found)
+}
+
+/// The same as [`find_branch`], talking to the remote repository through
+/// `remote_repo`
+///
+/// ```
+/// use git_sleipnir::branch_fallback::FallbackRule;
+/// use git_sleipnir::clone::{find_branch_with, CloneOptions, CloneTarget};
+/// use git_sleipnir::git_http_client::{Deepen, GitClientError, Transport};
+/// use git_sleipnir::local_repo::LocalRepo;
+/// use git_sleipnir::RefInfo;
+///
+/// /* A remote with only the branches main and release/2 */
+/// struct Remote;
+///
+/// impl Transport for Remote {
+///     async fn ls_refs<T: AsRef<str> + std::fmt::Display>(
+///         &self,
+///         _ref_prefixes: &[T],
+///     ) -> Result<Vec<RefInfo>, GitClientError> {
+///         let head = |name: &str, sha: char| RefInfo {
+///             refname: format!("refs/heads/{}", name),
+///             sha: sha.to_string().repeat(40),
+///             peeled: None,
+///         };
+///         Ok(vec![head("main", 'a'), head("release/2", 'b')])
+///     }
+///
+///     async fn fetch<T: AsRef<str>>(
+///         &self,
+///         _local_repo: &LocalRepo,
+///         _wants: &[T],
+///         _deepen: Deepen,
+///         _include_tags: bool,
+///     ) -> Result<(), GitClientError> {
+///         unreachable!("finding the branch fetches nothing")
+///     }
+/// }
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let url = url::Url::parse("https://example.com/project.git").unwrap();
+/// let target = CloneTarget::new(url, "project".into());
+/// let options = CloneOptions {
+///     branch: Some("release/2.0".to_string()),
+///     /* release/2.0 -> release/2 */
+///     rules: vec![FallbackRule::parse(r"/\.[^.]*$//").unwrap()],
+///     ..Default::default()
+/// };
+/// let found = find_branch_with(&Remote, &target, &options).await.unwrap();
+/// assert_eq!(found.branch, "release/2");
+/// assert_eq!(found.sha, "b".repeat(40));
+/// # });
+/// ```
+pub async fn find_branch_with<T: Transport>(
+    remote_repo: &T,
+    target: &CloneTarget,
+    opts: &CloneOptions,
+) -> Result<FoundBranch, CloneError> {
+    if let Some(pinned) = &target.pinned {
+        return Ok(FoundBranch {
+            refname: pinned.refname.clone(),
+            branch: short_refname(&pinned.refname).to_string(),
+            sha: pinned.sha.clone(),
+            fallback: None,
+            resolution: Vec::new(),
+        });
+    }
+    let target_branch = target.wanted_branch(opts)?;
+    let refs = list_refs_with(remote_repo, target, opts).await?;
+
+    let mut resolution = Vec::new();
+    let branch = select_branch(
+        &refs,
+        target_branch,
+        &opts.rules,
+        opts.fallback_strategy,
+        &opts.excludes,
+        opts.default_branch.as_deref(),
+        TagLookup::exact_if(opts.allow_tag_as_branch),
+        &mut resolution,
+    );
+    let Some((branch, matched_by)) = branch else {
+        return Err(no_branch_found(&refs, target_branch, &resolution).into());
+    };
+    Ok(FoundBranch {
+        refname: branch.refname.clone(),
+        branch: short_refname(&branch.refname).to_string(),
+        sha: branch.commit().clone(),
+        fallback: matched_by.fallback().map(str::to_string),
+        resolution,
+    })
+}
+
+/// What was cloned
+#[derive(Serialize)]
+pub struct CloneResult {
+    pub name: String,
+    /// With any password masked
+    pub url: String,
+    /// The URL the clone was fetched from, `url` unless a mirror had to
+    /// be used
+    pub served_by: String,
+    pub path: PathBuf,
+    pub worktree: Option<PathBuf>,
+    /// The ref checked out, e.g. `refs/heads/main`
+    pub refname: String,
+    /// The same without `refs/heads/` (or `refs/tags/`)
+    pub branch: String,
+    /// The commit checked out
+    pub sha: String,
+    /// The fallback rule that picked the branch, if any
+    pub fallback: Option<String>,
+    pub resolution: Vec<ResolutionStep>,
+    /// How deep the history was fetched
+    pub depth: usize,
+    pub reachable_tags: Vec<String>,
+    /// The highest acceptable reachable tag
+    pub tag: Option<String>,
+    /// The commit it points to
+    pub tag_sha: Option<String>,
+    /* The commit each reachable tag points to */
+    #[serde(skip)]
+    pub tag_commits: HashMap<String, String>,
+    /* Commit times of the reachable tags, only with TagOrder::CommitterDate */
+    #[serde(skip)]
+    pub tag_times: HashMap<String, i64>,
+    /* How many commits the walk from `sha` passed before reaching each
+     * tagged commit */
+    #[serde(skip)]
+    pub tag_positions: HashMap<String, usize>,
+    #[serde(skip)]
+    pub local_repo: LocalRepo,
+}
+
+impl CloneResult {
+    /// Describes the commit checked out relative to the nearest tag
+    /// accepted by `selector`, like `git describe --tags --always`
+    pub async fn describe(
+        &self,
+        selector: &TagSelector<'_>,
+    ) -> Result<Description, LocalRepoError> {
+        /* The tags first reached walking from the tip, the highest of them
+         * if there are several on that commit */
+        let position = |tag: &str| self.tag_positions.get(&self.tag_commits[tag]);
+        let acceptable: Vec<&str> = self
+            .tag_commits
+            .keys()
+            .map(String::as_str)
+            .filter(|tag| selector.rejection(tag).is_none() && position(tag).is_some())
+            .collect();
+        let nearest = acceptable.iter().filter_map(|tag| position(tag)).min();
+        let tag = TagSelector {
+            times: Some(&self.tag_times),
+            ..*selector
+        }
+        .max(
+            acceptable
+                .iter()
+                .copied()
+                .filter(|tag| position(tag) == nearest),
+        );
+
+        /* The walk starts at `sha`, so the commits it passed are those
+         * on top of the tag */
+        let distance = tag.and_then(position).copied().unwrap_or(0);
+        /* Only an exact match is certain when the history is cut off, a
+         * merge may bring in a nearer tag from beyond the boundary */
+        let exact = tag.is_some() && distance == 0;
+        let shallow = !self.local_repo.get_shallow_shas().await?.is_empty();
+        Ok(Description {
+            tag: tag.map(str::to_string),
+            distance,
+            sha: self.sha.clone(),
+            searched_depth: Some(self.depth).filter(|_| shallow && !exact),
+        })
+    }
+}
+
+/// A commit relative to the nearest tag found, shown like
+/// `v4.2.1-17-gabc1234`, or just the tag when it is on the commit
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Description {
+    pub tag: Option<String>,
+    /// Commits on top of the tag
+    pub distance: usize,
+    pub sha: String,
+    /// How deep the history was searched, when it was cut off there and
+    /// a nearer tag may have been missed
+    pub searched_depth: Option<usize>,
+}
+
+impl fmt::Display for Description {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let short = &self.sha[..self.sha.len().min(7)];
+        match &self.tag {
+            Some(tag) if self.distance == 0 => write!(f, "{}", tag),
+            Some(tag) => write!(f, "{}-{}-g{}", tag, self.distance, short),
+            None => write!(f, "{}", short),
+        }
+    }
+}
+
+/* Runs `attempt` with the URL of `target` and then its mirrors, for as
+ * long as the servers are unavailable. Returns the URL that worked. */
+async fn with_failover<'a, R, F, Fut>(
+    target: &'a CloneTarget,
+    mut attempt: F,
+) -> Result<(R, &'a Url), CloneError>
+where
+    F: FnMut(&'a Url) -> Fut,
+    Fut: std::future::Future<Output = Result<R, CloneError>>,
+{
+    let urls: Vec<&Url> = target.urls().collect();
+    let (last, tried_first) = urls.split_last().expect("a target has a URL");
+    for url in tried_first {
+        match attempt(url).await {
+            Err(e) if e.is_unavailable() => {
+                warn!("{}: {}, trying the next mirror", masked_url(url), e)
+            }
+            res => return res.map(|r| (r, *url)),
+        }
+    }
+    attempt(last).await.map(|r| (r, *last))
+}
+
+/// Clones `target`: selects the branch, fetches it as shallow as possible
+/// while still reaching a tag, and checks it out. Whatever it created is
+/// removed again if it fails. If the server is unavailable the mirrors
+/// are tried in turn.
+///
+/// Cancelling `cancel` stops the clone wherever it is, killing any git
+/// processes it runs, and fails it with [`CloneError::Cancelled`] once
+/// whatever it created is removed.
+///
+/// ```no_run
+/// use git_sleipnir::branch_fallback::FallbackRule;
+/// use git_sleipnir::clone::{clone_repo, CloneError, CloneOptions, CloneTarget};
+/// use tokio_util::sync::CancellationToken;
+///
+/// # async fn example() -> Result<(), CloneError> {
+/// let url = url::Url::parse("https://example.com/project.git").unwrap();
+/// let target = CloneTarget::new(url, "project".into());
+/// let options = CloneOptions {
+///     branch: Some("release/2.0".to_string()),
+///     /* release/2.0 -> release/2 */
+///     rules: vec![FallbackRule::parse(r"/\.[^.]*$//").unwrap()],
+///     ..Default::default()
+/// };
+/// let result = clone_repo(&target, &options, &CancellationToken::new()).await?;
+/// println!("{} at {} (tag {:?})", result.branch, result.sha, result.tag);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn clone_repo(
+    target: &CloneTarget,
+    opts: &CloneOptions,
+    cancel: &CancellationToken,
+) -> Result<CloneResult, CloneError> {
+    let client = opts.git_client()?;
+    /* A mirror's refs are listed afresh, it may lag behind */
+    let from_mirror = CloneTarget {
+        refs: None,
+        ..target.clone()
+    };
+    let (mut result, url) = with_failover(target, |url| {
+        let remote_repo = client.for_url(url);
+        let target = if *url == target.url {
+            target
+        } else {
+            &from_mirror
+        };
+        async move { clone_repo_with(&remote_repo, target, opts, cancel).await }
+    })
+    .await?;
+    result.served_by = masked_url(url);
+    Ok(result)
+}
+
+/// The same as [`clone_repo`], talking to the remote repository through
+/// `remote_repo`
+pub async fn clone_repo_with<T: Transport>(
+    remote_repo: &T,
+    target: &CloneTarget,
+    opts: &CloneOptions,
+    cancel: &CancellationToken,
+) -> Result<CloneResult, CloneError> {
+    /* Dropping the clone kills its git processes (they are spawned with
+     * kill_on_drop) and lets its CleanupGuard remove what it created */
+    tokio::select! {
+        biased;
+        _ = cancel.cancelled() => {
+            info!("Clone of {} cancelled", target.name);
+            Err(CloneError::Cancelled)
+        }
+        res = clone_one(remote_repo, target, opts) => res,
+    }
+}
+
+async fn clone_one<T: Transport>(
+    remote_repo: &T,
+    target: &CloneTarget,
+    opts: &CloneOptions,
+) -> Result<CloneResult, CloneError> {
+    let url = &target.url;
+    let local_repo_path = &target.dest;
+
+    let refs = list_refs_with(remote_repo, target, opts).await?;
+
+    let tag_selector = opts.tag_selector();
+    /* Only acceptable tags end the deepening */
+    let tagged_commits: HashSet<&str> = refs
+        .iter()
+        .filter(|r| {
+            let Some(tagname) = r.refname.strip_prefix("refs/tags/") else {
+                return false;
+            };
+            match tag_selector.rejection(tagname) {
+                Some(reason) => {
+                    debug!("Ignoring tag {}: {}", tagname, reason);
+                    false
+                }
+                None => true,
+            }
+        })
+        .map(|r| r.commit().as_str())
+        .collect();
+
+    let mut resolution = Vec::new();
+    let pinned_ref;
+    let (branch, matched_by) = match &target.pinned {
+        Some(pinned) => {
+            pinned_ref = RefInfo {
+                sha: pinned.sha.clone(),
+                refname: pinned.refname.clone(),
+                peeled: None,
+            };
+            (&pinned_ref, MatchedBy::Pinned)
+        }
+        None => {
+            let target_branch = target.wanted_branch(opts)?;
+            select_branch(
+                &refs,
+                target_branch,
+                &opts.rules,
+                opts.fallback_strategy,
+                &opts.excludes,
+                opts.default_branch.as_deref(),
+                TagLookup::exact_if(opts.allow_tag_as_branch),
+                &mut resolution,
+            )
+            .ok_or_else(|| no_branch_found(&refs, target_branch, &resolution))?
+        }
+    };
+
+    /* Whatever this clone creates is removed again if it fails */
+    let mut cleanup = CleanupGuard::new(opts.keep_failed);
+    let local_repo = if opts.update && local_repo_path.exists() {
+        info!("Updating existing repo {}", local_repo_path.display());
+        LocalRepo::open(local_repo_path, &opts.git).await?
+    } else {
+        if opts.force && local_repo_path.exists() {
+            let full_path = std::path::absolute(local_repo_path)?;
+            LocalRepo::remove(local_repo_path)?;
+            info!("Removed existing {}", full_path.display());
+        }
+        info!("Creating local repo {}", local_repo_path.display());
+        if !local_repo_path.exists() {
+            cleanup.add(local_repo_path);
+        }
+        let initial_branch = opts
+            .initial_branch
+            .as_deref()
+            .or_else(|| branch.refname.strip_prefix("refs/heads/"));
+        LocalRepo::init_new(local_repo_path, opts.bare, initial_branch, &opts.git).await?
+    };
+
+    let reference_dirs = target.reference_objects_dirs(opts);
+    if !reference_dirs.is_empty() {
+        for dir in &reference_dirs {
+            debug!("Borrowing objects from {}", dir.display());
+        }
+        local_repo.add_alternates(&reference_dirs)?;
+    }
+
+    /* A tag is checked out as a detached HEAD at the commit it points to */
+    let is_tag = branch.refname.starts_with("refs/tags/");
+    let commit = branch.commit();
+    debug!("Using {} (sha: {})", branch.refname, commit);
+
+    info!("Getting: {}", branch.refname);
+
+    /* All other branches listed (i.e. matching --branches-starting-with)
+     * are fetched along with the selected one, at the same depth. */
+    let other_branches: Vec<&RefInfo> = if opts.all_matching_branches && target.pinned.is_none() {
+        refs.iter()
+            .filter(|r| r.refname.starts_with("refs/heads/") && r.refname != branch.refname)
+            .collect()
+    } else {
+        Vec::new()
+    };
+    if !other_branches.is_empty() {
+        info!("Also getting {} other branch(es)", other_branches.len());
+    }
+    let mut wants: Vec<&str> = std::iter::once(branch)
+        .chain(other_branches.iter().copied())
+        .map(|r| r.sha.as_str())
+        .collect();
+
+    if opts.deepen_exponential {
+        info!("Initial depth {}, doubling when deepening", opts.depth);
+    } else {
+        info!(
+            "Initial depth {}, deepening by {}",
+            opts.depth, opts.deepen_step
+        );
+    }
+
+    let rev_list_options = opts.rev_list_options();
+    let mut depth = opts.depth.get();
+    let mut fully_fetched = false;
+    /* After deepening only the history beyond the previous shallow
+     * boundary is new, that's where walking continues */
+    let mut walk_from = vec![commit.clone()];
+    let mut searched = 0;
+    loop {
+        let fetched = remote_repo
+            .fetch(&local_repo, &wants, Deepen::Depth(depth), !opts.no_tags)
+            .await;
+        match fetched {
+            /* Servers may only serve commits that are ref tips, then the
+             * pinned commit is fetched as part of its ref's history */
+            Err(e) if matched_by == MatchedBy::Pinned && wants == [commit.as_str()] => {
+                let Some(tip) = refs.iter().find(|r| r.refname == branch.refname) else {
+                    return Err(e.into());
+                };
+                warn!(
+                    "Could not fetch {} ({}), deepening {} until it is there instead",
+                    commit, e, branch.refname
+                );
+                wants = vec![tip.sha.as_str()];
+                depth = fetch_ancestor(remote_repo, &local_repo, tip, commit, opts)
+                    .await?
+                    .ok_or_else(|| CloneError::PinnedMissing {
+                        refname: branch.refname.clone(),
+                        sha: commit.clone(),
+                        repo: target.name.clone(),
+                    })?;
+                debug!("{} is within depth {} of {}", commit, depth, branch.refname);
+            }
+            fetched => fetched?,
+        }
+
+        local_repo.update_ref(&branch.refname, &branch.sha).await?;
+        for r in &other_branches {
+            local_repo.update_ref(&r.refname, &r.sha).await?;
+        }
+        if is_tag {
+            local_repo.detach_head(commit).await?;
+        } else {
+            local_repo.update_head(&branch.refname).await?;
+        }
+
+        if opts.no_tags {
+            break;
+        }
+        let shallow = local_repo.get_shallow_shas().await?;
+        let starts: Vec<&str> = walk_from.iter().map(String::as_str).collect();
+        /* The boundary commits themselves were counted by the previous walk */
+        let revisited = if searched > 0 { starts.len() } else { 0 };
+        let options = RevListOptions {
+            max_count: rev_list_options
+                .max_count
+                .map(|max| max - searched + revisited),
+            ..rev_list_options
+        };
+        let mut found = false;
+        let mut boundary = Vec::new();
+        let walked = local_repo
+            .walk_commits(&starts, options, |sha| {
+                if shallow.contains(sha) {
+                    boundary.push(sha.to_string());
+                }
+                if tagged_commits.contains(sha) {
+                    found = true;
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            })
+            .await?;
+        searched += walked.saturating_sub(revisited);
+        debug!("Searched {} commits for tags", searched);
+        if found {
+            break;
+        }
+        if opts.max_count.is_some_and(|max| searched >= max.get()) {
+            info!("Searched {searched} commits, no tag found");
+            break;
+        }
+        /* Nothing left to deepen: either the server unshallowed
+         * everything or the walk didn't reach a shallow commit */
+        if shallow.is_empty() || boundary.is_empty() {
+            info!("History fully fetched after depth {depth}, no matching tag found");
+            fully_fetched = true;
+            break;
+        }
+        walk_from = boundary;
+
+        if depth >= opts.max_depth {
+            info!("Reached maximum depth {}, no tag found", opts.max_depth);
+            break;
+        }
+
+        depth = next_depth(depth, opts);
+        info!("Could not find tag in shallow clone. Deepening... (depth={depth})");
+    }
+
+    /* Every reachable tag is reported, not only the acceptable ones */
+    let all_tagged_commits: HashSet<&str> = refs
+        .iter()
+        .filter(|r| r.refname.starts_with("refs/tags/"))
+        .map(|r| r.commit().as_str())
+        .collect();
+    let mut commits = Vec::new();
+    /* How many commits the walk passed before each tagged one */
+    let mut tag_positions = HashMap::new();
+    let mut position = 0;
+    let walked = local_repo
+        .walk_commits(&[commit], rev_list_options, |sha| {
+            if all_tagged_commits.contains(sha) {
+                tag_positions.insert(sha.to_string(), position);
+                commits.push(sha.to_string());
+            }
+            position += 1;
+            ControlFlow::Continue(())
+        })
+        .await?;
+
+    let reachable_tags = fetch_reachable_tags(
+        remote_repo,
+        &local_repo,
+        &refs,
+        &commits,
+        Deepen::Depth(depth),
+    )
+    .await?;
+
+    if opts.dissociate {
+        local_repo.dissociate().await?;
+    }
+
+    if opts.verify {
+        for (refname, sha) in std::iter::once(branch)
+            .chain(other_branches.iter().copied())
+            .map(|r| (&r.refname, r.commit()))
+        {
+            let count = local_repo.verify_connectivity(sha).await.map_err(|error| {
+                CloneError::Incomplete {
+                    refname: refname.clone(),
+                    error,
+                }
+            })?;
+            debug!("Verified {} objects reachable from {}", count, refname);
+        }
+    }
+
+    if let Some(worktree) = &target.worktree {
+        if opts.force && worktree.exists() {
+            let full_path = std::path::absolute(worktree)?;
+            LocalRepo::remove(worktree)?;
+            info!("Removed existing {}", full_path.display());
+        }
+        info!("Checking out into worktree {}", worktree.display());
+        if !worktree.exists() {
+            cleanup.add(worktree);
+        }
+        let (rev, detach) = match branch.refname.strip_prefix("refs/heads/") {
+            Some(name) => (name, false),
+            None => (commit.as_str(), true),
+        };
+        local_repo
+            .add_worktree(worktree, rev, detach, opts.update)
+            .await?;
+    } else if !opts.bare && !opts.no_checkout {
+        local_repo.checkout_head(opts.update).await?;
+    }
+
+    /* Makes a plain `git fetch` or `git pull` work in the clone */
+    if !opts.no_set_remote {
+        local_repo
+            .set_config("remote.origin.url", &anonymous_url(url))
+            .await?;
+        if let Some(name) = branch.refname.strip_prefix("refs/heads/") {
+            let tracking = format!("refs/remotes/origin/{}", name);
+            local_repo
+                .set_config(
+                    "remote.origin.fetch",
+                    &format!("+{}:{}", branch.refname, tracking),
+                )
+                .await?;
+            local_repo.update_ref(&tracking, &branch.sha).await?;
+            local_repo
+                .set_config(&format!("branch.{}.remote", name), "origin")
+                .await?;
+            local_repo
+                .set_config(&format!("branch.{}.merge", name), &branch.refname)
+                .await?;
+        }
+    }
+
+    let tag_times = if opts.tag_order == TagOrder::CommitterDate {
+        let shas: Vec<&str> = reachable_tags.iter().map(|(_, c)| c.as_str()).collect();
+        let times = local_repo.commit_times(&shas).await?;
+        reachable_tags
+            .iter()
+            .filter_map(|(tag, c)| Some((tag.to_string(), *times.get(c)?)))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let maxtag = TagSelector {
+        times: Some(&tag_times),
+        ..tag_selector
+    }
+    .max(reachable_tags.iter().map(|(tag, _)| *tag))
+    .and_then(|max| reachable_tags.iter().find(|(tag, _)| *tag == max))
+    .cloned();
+
+    if maxtag.is_none() && !opts.no_tags {
+        let searched = if fully_fetched {
+            format!("searched the whole history of {} commits", walked)
+        } else {
+            format!("searched to depth {}", depth)
+        };
+        if !opts.allow_no_tag {
+            return Err(CloneError::NoTag {
+                refname: branch.refname.clone(),
+                repo: target.name.clone(),
+                searched,
+            });
+        }
+        warn!(
+            "No tag reachable from {} ({}), continuing without a tag",
+            branch.refname, searched
+        );
+    }
+
+    cleanup.disarm();
+    Ok(CloneResult {
+        name: target.name.clone(),
+        url: masked_url(url),
+        served_by: masked_url(url),
+        path: local_repo_path.clone(),
+        worktree: target.worktree.clone(),
+        refname: branch.refname.clone(),
+        branch: short_refname(&branch.refname).to_string(),
+        sha: commit.clone(),
+        fallback: matched_by.fallback().map(str::to_string),
+        resolution,
+        depth,
+        reachable_tags: reachable_tags.iter().map(|t| t.0.to_string()).collect(),
+        tag: maxtag.as_ref().map(|t| t.0.to_string()),
+        tag_sha: maxtag.map(|t| t.1),
+        tag_commits: reachable_tags
+            .iter()
+            .map(|(tag, commit)| (tag.to_string(), commit.clone()))
+            .collect(),
+        tag_times,
+        tag_positions,
+        local_repo,
+    })
+}
+
+fn next_depth(depth: usize, opts: &CloneOptions) -> usize {
+    let depth = if opts.deepen_exponential {
+        depth * 2
+    } else {
+        depth + opts.deepen_step.get()
+    };
+    depth.min(opts.max_depth)
+}
+
+/* Fetches `tip` deeper and deeper until `sha` is part of the fetched
+ * history, returning the depth that took. None if it never is. */
+async fn fetch_ancestor<T: Transport>(
+    remote_repo: &T,
+    local_repo: &LocalRepo,
+    tip: &RefInfo,
+    sha: &str,
+    opts: &CloneOptions,
+) -> Result<Option<usize>, CloneError> {
+    let mut depth = opts.depth.get();
+    loop {
+        remote_repo
+            .fetch(local_repo, &[&tip.sha], Deepen::Depth(depth), !opts.no_tags)
+            .await?;
+        if local_repo.has_commit(sha).await? {
+            return Ok(Some(depth));
+        }
+        if depth >= opts.max_depth || local_repo.get_shallow_shas().await?.is_empty() {
+            return Ok(None);
+        }
+        depth = next_depth(depth, opts);
+    }
+}
+
+/// Finds the tags pointing at any of `commits`, making sure the whole tag
+/// chain is present locally, and creates refs for them. Returns the tag
+/// names along with the commit they point to.
+pub async fn fetch_reachable_tags<'a>(
+    remote_repo: &impl Transport,
+    local_repo: &LocalRepo,
+    refs: &'a [RefInfo],
+    commits: &[String],
+    deepen: Deepen,
+) -> Result<Vec<(&'a str, String)>, CloneError> {
+    let interesting_commits: HashSet<&str> = commits.iter().map(|s| s.as_str()).collect();
+    let candidate_tags: Vec<(&str, &RefInfo)> = refs
+        .iter()
+        .filter_map(|r| Some((r.refname.strip_prefix("refs/tags/")?, r)))
+        .filter(|(_, r)| interesting_commits.contains(r.commit().as_str()))
+        .collect();
+
+    /* Nested tags (tag -> tag -> commit) need every object in the chain
+     * present locally, make sure include-tag actually delivered them. */
+    let mut incomplete = Vec::new();
+    for (_, r) in &candidate_tags {
+        if let PeeledTag::Missing(obj) = local_repo.peel_tag(&r.sha).await? {
+            debug!("Tag {} is missing object {}", r.refname, obj);
+            incomplete.push(r.sha.as_str());
+        }
+    }
+    if !incomplete.is_empty() {
+        info!("Fetching {} incomplete tag chain(s)", incomplete.len());
+        remote_repo
+            .fetch(local_repo, &incomplete, deepen, true)
+            .await?;
+    }
+
+    let mut reachable_tags = Vec::new();
+    for (tagname, r) in candidate_tags {
+        match local_repo.peel_tag(&r.sha).await? {
+            PeeledTag::Peeled(commit) if interesting_commits.contains(commit.as_str()) => {
+                reachable_tags.push((tagname, commit));
+                local_repo.update_ref(&r.refname, &r.sha).await?;
+            }
+            PeeledTag::Peeled(commit) => {
+                warn!(
+                    "Tag {} peels to {} locally, but server advertised {}",
+                    tagname,
+                    commit,
+                    r.commit()
+                );
+            }
+            PeeledTag::Missing(obj) => {
+                return Err(CloneError::IncompleteTag {
+                    tag: tagname.to_string(),
+                    object: obj,
+                });
+            }
+        }
+    }
+
+    Ok(reachable_tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::branch_fallback::BranchFallback;
+    use crate::local_repo::LocalRepo;
+    use crate::test_support::{Fixture, MockTransport};
+
+    #[test]
+    fn formats_description() {
+        let description = |tag: Option<&str>, distance| Description {
+            tag: tag.map(str::to_string),
+            distance,
+            sha: "abc1234def".repeat(4),
+            searched_depth: None,
+        };
+        assert_eq!(description(Some("v4.2.1"), 0).to_string(), "v4.2.1");
+        assert_eq!(
+            description(Some("v4.2.1"), 17).to_string(),
+            "v4.2.1-17-gabc1234"
+        );
+        assert_eq!(description(None, 0).to_string(), "abc1234");
+    }
+
+    #[test]
+    fn reports_what_matched() {
+        let refs: Vec<RefInfo> = [
+            "heads/main",
+            "heads/release/1",
+            "heads/feature/x",
+            "tags/main",
+            "tags/v4.2",
+        ]
+        .iter()
+        .map(|r| RefInfo {
+            sha: "a".repeat(40),
+            refname: format!("refs/{}", r),
+            peeled: None,
+        })
+        .collect();
+        let rules = [FallbackRule::Regex(
+            BranchFallback::parse(r"/\.[^.]*$//").unwrap(),
+        )];
+        let select = |branch: &str, tags: TagLookup| {
+            select_branch(
+                &refs,
+                branch,
+                &rules,
+                FallbackStrategy::First,
+                &[],
+                Some("main"),
+                tags,
+                &mut Vec::new(),
+            )
+            .map(|(b, m)| (b.refname.clone(), m.to_string()))
+        };
+        let matched_by = |branch: &str| select(branch, TagLookup::No);
+        assert_eq!(
+            matched_by("feature/x").unwrap(),
+            ("refs/heads/feature/x".to_string(), "exact".to_string())
+        );
+        assert_eq!(
+            matched_by("release/1.2").unwrap(),
+            (
+                "refs/heads/release/1".to_string(),
+                r"fallback:/\.[^.]*$//".to_string()
+            )
+        );
+        assert_eq!(matched_by("other").unwrap().1, "default-branch");
+
+        /* Tags only when asked, and losing to branches */
+        assert_eq!(matched_by("v4.2.1").unwrap().1, "default-branch");
+        assert_eq!(
+            select("v4.2", TagLookup::Exact).unwrap().0,
+            "refs/tags/v4.2"
+        );
+        assert_eq!(
+            select("v4.2.1", TagLookup::Exact).unwrap().1,
+            "default-branch"
+        );
+        assert_eq!(
+            select("v4.2.1", TagLookup::Candidates).unwrap(),
+            (
+                "refs/tags/v4.2".to_string(),
+                r"fallback:/\.[^.]*$//".to_string()
+            )
+        );
+        assert_eq!(
+            select("main", TagLookup::Candidates).unwrap().0,
+            "refs/heads/main"
+        );
+    }
+
+    fn target(dir: &tempfile::TempDir) -> CloneTarget {
+        CloneTarget::new(
+            Url::parse("https://example.com/fixture.git").unwrap(),
+            dir.path().join("fixture"),
+        )
+    }
+
+    fn options(deepen_step: usize) -> CloneOptions {
+        CloneOptions {
+            branch: Some("main".to_string()),
+            deepen_step: NonZeroUsize::new(deepen_step).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn deepens_until_a_tag_is_reachable() {
+        let remote = MockTransport::new(Fixture::new(20, &[(6, "v1.0"), (12, "v0.9")]));
+        let dir = tempfile::tempdir().unwrap();
+
+        let res = clone_repo_with(
+            &remote,
+            &target(&dir),
+            &options(3),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(remote.fetched_depths(), vec![1, 4, 7]);
+        assert_eq!(res.depth, 7);
+        assert_eq!(res.branch, "main");
+        assert_eq!(res.sha, remote.fixture.commits[0]);
+        assert_eq!(res.tag.as_deref(), Some("v1.0"));
+        assert_eq!(res.reachable_tags, vec!["v1.0"]);
+        assert_eq!(
+            res.local_repo.get_shallow_shas().await.unwrap(),
+            HashSet::from([remote.fixture.commits[6].clone()])
+        );
+    }
+
+    #[tokio::test]
+    async fn runs_git_of_the_options() {
+        let remote = MockTransport::new(Fixture::new(5, &[(2, "v1.0")]));
+        let dir = tempfile::tempdir().unwrap();
+        let opts = CloneOptions {
+            git: GitSettings {
+                git: dir.path().join("no-such-git"),
+                ..Default::default()
+            },
+            ..options(3)
+        };
+
+        let err = clone_repo_with(&remote, &target(&dir), &opts, &CancellationToken::new())
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            matches!(
+                err,
+                CloneError::Local(LocalRepoError::ExternalGitCommandSpawnFailure(_))
+            ),
+            "{}",
+            err
+        );
+
+        /* Another clone in the same process runs the default one */
+        let dir = tempfile::tempdir().unwrap();
+        clone_repo_with(
+            &remote,
+            &target(&dir),
+            &options(3),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_without_reachable_tag() {
+        let remote = MockTransport::new(Fixture::new(5, &[]));
+        let dir = tempfile::tempdir().unwrap();
+
+        let err = clone_repo_with(
+            &remote,
+            &target(&dir),
+            &options(2),
+            &CancellationToken::new(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(remote.fetched_depths(), vec![1, 3, 5]);
+        assert!(
+            matches!(&err, CloneError::NoTag { searched, .. }
+                if searched == "searched the whole history of 5 commits"),
+            "{}",
+            err
+        );
+        assert!(!target(&dir).dest.exists());
+    }
+
+    #[tokio::test]
+    async fn stops_at_max_depth_without_tag() {
+        let remote = MockTransport::new(Fixture::new(30, &[(25, "v1.0")]));
+        let dir = tempfile::tempdir().unwrap();
+        let opts = CloneOptions {
+            max_depth: 10,
+            allow_no_tag: true,
+            ..options(4)
+        };
+
+        let res = clone_repo_with(&remote, &target(&dir), &opts, &CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(remote.fetched_depths(), vec![1, 5, 9, 10]);
+        assert_eq!(res.tag, None);
+        assert!(res.reachable_tags.is_empty());
+    }
+
+    #[tokio::test]
+    async fn clones_fallback_branch() {
+        let fixture = Fixture::new(3, &[(1, "v2.0")]);
+        fixture.git(&["branch", "release/2", "HEAD~1"]);
+        let remote = MockTransport::new(fixture);
+        let dir = tempfile::tempdir().unwrap();
+        let opts = CloneOptions {
+            branch: Some("release/2.1".to_string()),
+            rules: vec![FallbackRule::parse(r"/\.[^.]*$//").unwrap()],
+            ..options(1)
+        };
+
+        let res = clone_repo_with(&remote, &target(&dir), &opts, &CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(res.branch, "release/2");
+        assert_eq!(res.sha, remote.fixture.commits[1]);
+        assert!(res.fallback.is_some());
+        assert_eq!(res.tag.as_deref(), Some("v2.0"));
+        assert_eq!(remote.fetched_depths(), vec![1]);
+    }
+
+    /* Cancels the clone when it starts deepening, and then never answers */
+    struct CancellingTransport {
+        remote: MockTransport,
+        cancel: CancellationToken,
+    }
+
+    impl Transport for CancellingTransport {
+        async fn ls_refs<T: AsRef<str> + std::fmt::Display>(
+            &self,
+            ref_prefixes: &[T],
+        ) -> Result<Vec<RefInfo>, GitClientError> {
+            self.remote.ls_refs(ref_prefixes).await
+        }
+
+        async fn fetch<T: AsRef<str>>(
+            &self,
+            local_repo: &LocalRepo,
+            wants: &[T],
+            deepen: Deepen,
+            include_tags: bool,
+        ) -> Result<(), GitClientError> {
+            if !self.remote.fetched_depths().is_empty() {
+                self.cancel.cancel();
+                std::future::pending::<()>().await;
+            }
+            self.remote
+                .fetch(local_repo, wants, deepen, include_tags)
+                .await
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelled_clone_is_removed() {
+        let cancel = CancellationToken::new();
+        let remote = CancellingTransport {
+            remote: MockTransport::new(Fixture::new(10, &[(8, "v1.0")])),
+            cancel: cancel.clone(),
+        };
+        let dir = tempfile::tempdir().unwrap();
+
+        let err = clone_repo_with(&remote, &target(&dir), &options(3), &cancel)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, CloneError::Cancelled), "{}", err);
+        assert_eq!(remote.remote.fetched_depths(), vec![1]);
+        assert!(!target(&dir).dest.exists());
+
+        /* Nothing is started with a cancelled token */
+        let remote = MockTransport::new(Fixture::new(1, &[(0, "v1.0")]));
+        let err = clone_repo_with(&remote, &target(&dir), &options(3), &cancel)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, CloneError::Cancelled), "{}", err);
+        assert!(remote.fetched_depths().is_empty());
+    }
+
+    #[tokio::test]
+    async fn finds_branch_without_fetching() {
+        let fixture = Fixture::new(3, &[]);
+        fixture.git(&["branch", "release/2", "HEAD~1"]);
+        let remote = MockTransport::new(fixture);
+        let dir = tempfile::tempdir().unwrap();
+        let opts = CloneOptions {
+            branch: Some("release/2.1".to_string()),
+            rules: vec![FallbackRule::parse(r"/\.[^.]*$//").unwrap()],
+            ..options(1)
+        };
+
+        let found = find_branch_with(&remote, &target(&dir), &opts)
+            .await
+            .unwrap();
+        assert_eq!(found.refname, "refs/heads/release/2");
+        assert_eq!(found.branch, "release/2");
+        assert_eq!(found.sha, remote.fixture.commits[1]);
+        assert!(found.fallback.is_some());
+        assert!(remote.fetched_depths().is_empty());
+        assert!(!target(&dir).dest.exists());
+
+        let opts = CloneOptions {
+            branch: Some("release/3".to_string()),
+            ..options(1)
+        };
+        let err = find_branch_with(&remote, &target(&dir), &opts)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, CloneError::NoBranch(_)), "{}", err);
+
+        let opts = CloneOptions {
+            branch: None,
+            ..options(1)
+        };
+        let err = find_branch_with(&remote, &target(&dir), &opts)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, CloneError::NoBranchGiven(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn clones_pinned_commit() {
+        let remote = MockTransport::new(Fixture::new(10, &[(6, "v1.0")]));
+        let dir = tempfile::tempdir().unwrap();
+        let target = CloneTarget {
+            pinned: Some(PinnedRef {
+                refname: "refs/heads/main".to_string(),
+                sha: remote.fixture.commits[2].clone(),
+            }),
+            ..target(&dir)
+        };
+        let opts = CloneOptions {
+            branch: Some("does-not-exist".to_string()),
+            ..options(3)
+        };
+
+        let res = clone_repo_with(&remote, &target, &opts, &CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(res.sha, remote.fixture.commits[2]);
+        assert_eq!(res.refname, "refs/heads/main");
+        assert_eq!(res.tag.as_deref(), Some("v1.0"));
+        assert_eq!(remote.fetched_depths(), vec![1, 4, 7]);
+    }
+
+    #[tokio::test]
+    async fn pinned_commit_from_ref_history() {
+        let mut remote = MockTransport::new(Fixture::new(10, &[(6, "v1.0")]));
+        remote.tips_only = true;
+        let dir = tempfile::tempdir().unwrap();
+        let target = CloneTarget {
+            pinned: Some(PinnedRef {
+                refname: "refs/heads/main".to_string(),
+                sha: remote.fixture.commits[2].clone(),
+            }),
+            ..target(&dir)
+        };
+
+        let res = clone_repo_with(&remote, &target, &options(3), &CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(res.sha, remote.fixture.commits[2]);
+        assert_eq!(res.tag.as_deref(), Some("v1.0"));
+        /* Depths from the tip of main, which is 2 commits ahead */
+        assert_eq!(remote.fetched_depths(), vec![1, 4, 7]);
+        assert_eq!(res.depth, 7);
+
+        let missing = "0".repeat(40);
+        let target = CloneTarget {
+            pinned: Some(PinnedRef {
+                refname: "refs/heads/main".to_string(),
+                sha: missing.clone(),
+            }),
+            dest: dir.path().join("missing"),
+            ..target
+        };
+        let err = clone_repo_with(&remote, &target, &options(5), &CancellationToken::new())
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            matches!(&err, CloneError::PinnedMissing { sha, .. } if *sha == missing),
+            "{}",
+            err
+        );
+        assert!(!target.dest.exists());
+    }
+}
@@ -0,0 +1,52 @@
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+
+use bytes::{Buf, Bytes};
+use tokio::sync::mpsc::Receiver;
+
+/* Makes the pack data arriving over a channel readable from a blocking
+ * thread */
+struct ChannelReader {
+    rx: Receiver<Bytes>,
+    current: Bytes,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.current.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(data) => self.current = data,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current[..n]);
+        self.current.advance(n);
+        Ok(n)
+    }
+}
+
+/* Indexes a pack into `pack_dir` (.git/objects/pack) without running git.
+ * The pack checksum is verified. Blocks, so run it with spawn_blocking. */
+pub fn index_pack(rx: Receiver<Bytes>, pack_dir: &Path) -> Result<(), String> {
+    let mut reader = std::io::BufReader::new(ChannelReader {
+        rx,
+        current: Bytes::new(),
+    });
+    let outcome = gix_pack::Bundle::write_to_directory(
+        &mut reader,
+        Some(pack_dir),
+        &mut gix_features::progress::Discard,
+        &AtomicBool::new(false),
+        None::<gix_object::find::Never>,
+        gix_pack::bundle::write::Options::default(),
+    )
+    .map_err(|e| e.to_string())?;
+
+    /* git index-pack --stdin doesn't keep the pack, neither do we */
+    if let Some(keep) = outcome.keep_path {
+        std::fs::remove_file(keep).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
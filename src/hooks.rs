@@ -0,0 +1,137 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+
+use log::{info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+
+#[derive(Debug)]
+pub enum HookError {
+    Spawn(&'static str, io::Error),
+    Failed(&'static str, ExitStatus),
+}
+
+impl fmt::Display for HookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HookError::Spawn(hook, e) => write!(f, "Could not run the {} hook: {}", hook, e),
+            HookError::Failed(hook, status) => write!(f, "The {} hook failed ({})", hook, status),
+        }
+    }
+}
+
+impl Error for HookError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HookError::Spawn(_, e) => Some(e),
+            HookError::Failed(..) => None,
+        }
+    }
+}
+
+/* The variables hooks get start with this, apart from the SLEIPNIR_*
+ * options, so git-sleipnir run by a hook doesn't take them for options */
+pub const ENV_PREFIX: &str = "SLEIPNIR_HOOK_";
+
+fn shell(cmd: &str) -> Command {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(cmd);
+    shell
+}
+
+async fn log_lines<R: AsyncRead + Unpin>(hook: &str, output: R, stderr: bool) {
+    let mut lines = BufReader::new(output).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if stderr {
+            warn!("{} hook: {}", hook, line);
+        } else {
+            info!("{} hook: {}", hook, line);
+        }
+    }
+}
+
+/* Runs `cmd` with the shell in `dir`, passing its output on to the log
+ * (attributed to the repository being cloned, if any) */
+pub async fn run(
+    hook: &'static str,
+    cmd: &str,
+    dir: &Path,
+    env: &[(&str, &str)],
+) -> Result<(), HookError> {
+    let mut child = shell(cmd)
+        .current_dir(dir)
+        .envs(env.iter().copied())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| HookError::Spawn(hook, e))?;
+
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let (status, _, _) = tokio::join!(
+        child.wait(),
+        log_lines(hook, stdout, false),
+        log_lines(hook, stderr, true)
+    );
+    let status = status.map_err(|e| HookError::Spawn(hook, e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(HookError::Failed(hook, status))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn runs_with_env_in_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        run(
+            "post-clone",
+            "echo \"$SLEIPNIR_HOOK_TAG\" > tag; pwd > dir",
+            dir.path(),
+            &[("SLEIPNIR_HOOK_TAG", "v1.2")],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("tag")).unwrap(),
+            "v1.2\n"
+        );
+        assert_eq!(
+            Path::new(
+                std::fs::read_to_string(dir.path().join("dir"))
+                    .unwrap()
+                    .trim()
+            )
+            .canonicalize()
+            .unwrap(),
+            dir.path().canonicalize().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn failing_hook() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = run("post-run", "echo oops >&2; exit 3", dir.path(), &[])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HookError::Failed("post-run", s) if s.code() == Some(3)));
+        assert_eq!(err.to_string(), "The post-run hook failed (exit status: 3)");
+    }
+}
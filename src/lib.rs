@@ -0,0 +1,57 @@
+//! Shallow clones of git repositories over smart HTTP (protocol v2),
+//! deepened until a tag is reachable, as done by the `git-sleipnir`
+//! command.
+//!
+//! [`clone::clone_repo`] does a whole clone. [`git_http_client::GitClient`]
+//! and [`local_repo::LocalRepo`] are the pieces it is built from: listing
+//! the refs of a remote and fetching from it, and the local repository
+//! fetched into.
+//!
+//! With the `blocking` feature, `git_sleipnir::blocking` has synchronous
+//! versions of these for callers without a tokio runtime.
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod branch_fallback;
+pub mod clone;
+pub mod git_http_client;
+#[cfg(feature = "gix")]
+mod gix_backend;
+pub mod local_repo;
+pub mod logging;
+pub mod packet_trace;
+pub mod pkt_line;
+pub mod rate_limit;
+pub mod reader;
+pub mod ref_format;
+pub mod refs_cache;
+pub mod tag_order;
+#[cfg(test)]
+mod test_support;
+pub mod util;
+
+/// What the server said about the history's shallow boundary
+#[derive(Debug)]
+pub enum ShallowInfo {
+    Shallow(String),
+    NotShallow(String),
+}
+
+/// A ref as listed by the server
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RefInfo {
+    pub sha: String,
+    pub refname: String,
+    /// The commit an annotated tag points to
+    pub peeled: Option<String>,
+}
+
+impl RefInfo {
+    /* The commit the ref points to. Lightweight tags aren't peeled, they
+     * point at the commit directly. */
+    pub fn commit(&self) -> &String {
+        self.peeled.as_ref().unwrap_or(&self.sha)
+    }
+}
@@ -1,55 +1,356 @@
 use futures::Stream;
 use futures::StreamExt;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::error::Error;
 use std::ffi::OsStr;
 use std::fmt;
 use std::io::Write;
+use std::ops::ControlFlow;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::ExitStatus;
 use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
 
 use bytes::Bytes;
 
-use log::warn;
+use log::{debug, info, trace, warn};
 
+use tokio::io::AsyncBufRead;
 use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncSeekExt;
 use tokio::io::AsyncWriteExt;
 use tokio::io::BufReader;
-use tokio::process::{Child, Command};
+use tokio::io::Lines;
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::task::JoinHandle;
 
-use crate::reader::GitPacketLine;
+use crate::logging;
+use crate::rate_limit::RateLimit;
 use crate::reader::GitPacketLineStream;
+use crate::reader::PacketError;
 use crate::reader::SideBand;
+use crate::reader::SideBandStream;
 
 use crate::util::read_lines_to_set;
-use crate::util::write_lines_from_set;
+use crate::util::LockFile;
 
+use crate::RefInfo;
 use crate::ShallowInfo;
 
 #[derive(Debug)]
 pub enum LocalRepoError {
     AlreadyExists(PathBuf),
+    NotARepository(PathBuf),
+    RefusingToRemove(PathBuf),
+    DirectoryRemovalError((PathBuf, std::io::Error)),
     DirectoryCreationError((PathBuf, std::io::Error)),
+    FileWriteError((PathBuf, std::io::Error)),
+    Locked(PathBuf),
+    PackStreamError(PacketError),
+    PackWriteError(std::io::Error),
+    PackSpoolError(std::io::Error),
     ExternalGitCommandSpawnFailure(std::io::Error),
-    ExternalGitCommandError(ExitStatus),
+    ExternalGitCommandReadError(std::io::Error),
+    Stalled(StallLimit),
+    /* The exit status and what the command wrote to stderr */
+    ExternalGitCommandError(ExitStatus, String),
+    #[cfg(feature = "gix")]
+    PackIndexError(String),
 }
 
+/* The oldest git known to support everything we run, `init
+ * --initial-branch` being the newest addition */
+pub const MIN_GIT_VERSION: (u32, u32) = (2, 28);
+
+/* What indexes the fetched packs */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PackBackend {
+    /* git index-pack */
+    #[default]
+    Git,
+    /* gitoxide, in-process */
+    #[cfg(feature = "gix")]
+    Gix,
+}
+
+/* A fetch receiving less than `min_bytes` within `window` is given up.
+ * Keepalives don't count, so a trickle of them can't keep a dead
+ * transfer going. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StallLimit {
+    pub window: Duration,
+    pub min_bytes: u64,
+}
+
+/* How git is run and fetched packs are received, for the repositories
+ * opened or created with them */
+#[derive(Clone, Debug)]
+pub struct GitSettings {
+    /* The git executable, "git" from PATH by default */
+    pub git: PathBuf,
+    pub pack_backend: PackBackend,
+    /* Receive packs into a temporary file first and only then index them,
+     * so an indexer slower than the network doesn't hold up the transfer */
+    pub pack_spool: bool,
+    pub stall_limit: Option<StallLimit>,
+    /* Shared by all fetches with (clones of) these settings */
+    pub rate_limit: Option<Arc<RateLimit>>,
+}
+
+impl Default for GitSettings {
+    fn default() -> Self {
+        GitSettings {
+            git: PathBuf::from("git"),
+            pack_backend: PackBackend::default(),
+            pack_spool: false,
+            stall_limit: None,
+            rate_limit: None,
+        }
+    }
+}
+
+/* Counts the bytes received in the current window of a StallLimit */
+struct StallWatchdog {
+    limit: Option<StallLimit>,
+    window_start: tokio::time::Instant,
+    bytes: u64,
+}
+
+impl StallWatchdog {
+    fn new(limit: Option<StallLimit>) -> Self {
+        StallWatchdog {
+            limit,
+            window_start: tokio::time::Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    fn received(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+
+    /* Time the transfer was held back on purpose doesn't count */
+    fn paused(&mut self, duration: Duration) {
+        self.window_start += duration;
+    }
+
+    /* Resolves when the current window is over, never without a limit */
+    async fn window_end(&self) {
+        match self.limit {
+            Some(limit) => tokio::time::sleep_until(self.window_start + limit.window).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /* Starts a new window if the current one is over and was fast enough */
+    fn check(&mut self) -> Result<()> {
+        let Some(limit) = self.limit else {
+            return Ok(());
+        };
+        let now = tokio::time::Instant::now();
+        if now < self.window_start + limit.window {
+            return Ok(());
+        }
+        if self.bytes < limit.min_bytes {
+            return Err(LocalRepoError::Stalled(limit));
+        }
+        self.window_start = now;
+        self.bytes = 0;
+        Ok(())
+    }
+}
+
+/* Where the pack data goes while it is received */
+enum PackSink {
+    Git(GitChild, ChildStdin),
+    #[cfg(feature = "gix")]
+    Gix(
+        tokio::sync::mpsc::Sender<Bytes>,
+        JoinHandle<std::result::Result<(), String>>,
+    ),
+    /* A temporary file in objects/pack, removed when dropped */
+    Spool(tokio::fs::File, tempfile::TempPath),
+}
+
+impl PackSink {
+    /* Breaks when the data can't be passed on. Without an error the
+     * indexer gave up, and finish() reports why. */
+    async fn write(&mut self, payload: &Bytes) -> ControlFlow<Option<LocalRepoError>> {
+        match self {
+            PackSink::Git(_, stdin) => {
+                if let Err(e) = stdin.write_all(payload).await {
+                    return ControlFlow::Break(Some(LocalRepoError::PackWriteError(e)));
+                }
+            }
+            #[cfg(feature = "gix")]
+            PackSink::Gix(tx, _) => {
+                if tx.send(payload.clone()).await.is_err() {
+                    return ControlFlow::Break(None);
+                }
+            }
+            PackSink::Spool(file, _) => {
+                if let Err(e) = file.write_all(payload).await {
+                    return ControlFlow::Break(Some(LocalRepoError::PackSpoolError(e)));
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /* Waits for the indexer, a spool is only removed */
+    async fn finish(self) -> Result<()> {
+        match self {
+            PackSink::Git(index_pack_cmd, stdin) => {
+                drop(stdin);
+                wait_result(index_pack_cmd, || ()).await
+            }
+            #[cfg(feature = "gix")]
+            PackSink::Gix(tx, indexer) => {
+                drop(tx);
+                indexer
+                    .await
+                    .expect("Pack indexing panicked")
+                    .map_err(LocalRepoError::PackIndexError)
+            }
+            PackSink::Spool(..) => Ok(()),
+        }
+    }
+
+    /* Without waiting for the indexer, it would complain about the
+     * truncated pack */
+    async fn stop(self) {
+        match self {
+            PackSink::Git(index_pack_cmd, _) => index_pack_cmd.stop().await,
+            /* Ends once the sender is dropped */
+            #[cfg(feature = "gix")]
+            PackSink::Gix(..) => {}
+            PackSink::Spool(..) => {}
+        }
+    }
+}
+
+/* Commands are killed along with the future running them, e.g. when a
+ * clone is interrupted, rather than left running on their own */
+fn git_command(git: &Path) -> Command {
+    let mut cmd = Command::new(git);
+    cmd.kill_on_drop(true);
+    cmd
+}
+
+fn parse_git_version(output: &str) -> Option<(u32, u32)> {
+    /* e.g. "git version 2.39.3 (Apple Git-146)" or "git version 2.45.1.windows.1" */
+    let version = output.trim().strip_prefix("git version ")?;
+    let mut parts = version.split(['.', ' ']);
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/* Makes sure `git` can be run and is recent enough, the version string
+ * is returned */
+pub fn check_git_version(git: &Path) -> std::result::Result<String, String> {
+    let out = std::process::Command::new(git)
+        .arg("version")
+        .output()
+        .map_err(|e| format!("Could not run '{}': {}", git.display(), e))?;
+    let text = String::from_utf8_lossy(&out.stdout).trim().to_string();
+    let version = parse_git_version(&text).ok_or_else(|| {
+        format!(
+            "Unexpected output from '{} version': {}",
+            git.display(),
+            text
+        )
+    })?;
+    if version < MIN_GIT_VERSION {
+        return Err(format!(
+            "{} is too old, at least git {}.{} is needed (use --git or SLEIPNIR_GIT to pick another one)",
+            text, MIN_GIT_VERSION.0, MIN_GIT_VERSION.1
+        ));
+    }
+    Ok(text)
+}
+
+/* How long to wait for someone else's lock on .git/shallow */
+const SHALLOW_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/* How many lines of git's stderr an error message includes */
+const STDERR_LINES_IN_ERROR: usize = 5;
+
 impl fmt::Display for LocalRepoError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             LocalRepoError::AlreadyExists(p) => {
                 write!(f, "Directory '{}' already exists.", p.display())
             }
+            LocalRepoError::NotARepository(p) => {
+                write!(f, "'{}' is not a git repository.", p.display())
+            }
+            LocalRepoError::RefusingToRemove(p) => {
+                write!(
+                    f,
+                    "Refusing to remove '{}', it doesn't look like a git repository.",
+                    p.display()
+                )
+            }
+            LocalRepoError::DirectoryRemovalError((p, e)) => {
+                write!(f, "Could not remove directory '{}': {}", p.display(), e)
+            }
             LocalRepoError::DirectoryCreationError((p, e)) => {
                 write!(f, "Could not create directory '{}': {}", p.display(), e)
             }
+            LocalRepoError::FileWriteError((p, e)) => {
+                write!(f, "Could not write '{}': {}", p.display(), e)
+            }
+            LocalRepoError::Locked(p) => {
+                write!(
+                    f,
+                    "'{}' is locked by another process. If no other git or git-sleipnir is running in the repository, remove '{}.lock'.",
+                    p.display(),
+                    p.display()
+                )
+            }
+            LocalRepoError::PackStreamError(e) => {
+                write!(f, "Could not receive pack: {}", e)
+            }
+            LocalRepoError::PackWriteError(e) => {
+                write!(f, "Could not pass the pack on to index-pack: {}", e)
+            }
+            LocalRepoError::PackSpoolError(e) => {
+                write!(f, "Could not spool the pack to disk: {}", e)
+            }
             LocalRepoError::ExternalGitCommandSpawnFailure(e) => {
                 write!(f, "Could not spawn git process: {}", e)
             }
-            LocalRepoError::ExternalGitCommandError(es) => {
-                write!(f, "External git process failed: {}", es)
+            LocalRepoError::ExternalGitCommandReadError(e) => {
+                write!(f, "Could not read from git process: {}", e)
+            }
+            LocalRepoError::Stalled(limit) => {
+                write!(
+                    f,
+                    "Transfer stalled (< {} bytes in {} seconds)",
+                    limit.min_bytes,
+                    limit.window.as_secs_f64()
+                )
+            }
+            LocalRepoError::ExternalGitCommandError(es, stderr) => {
+                write!(f, "External git process failed: {}", es)?;
+                let lines: Vec<&str> = stderr
+                    .lines()
+                    .filter(|l| !l.trim().is_empty())
+                    .take(STDERR_LINES_IN_ERROR)
+                    .collect();
+                if !lines.is_empty() {
+                    write!(f, ": {}", lines.join("; "))?;
+                }
+                Ok(())
+            }
+            #[cfg(feature = "gix")]
+            LocalRepoError::PackIndexError(e) => {
+                write!(f, "Could not index pack: {}", e)
             }
         }
     }
@@ -59,9 +360,21 @@ impl Error for LocalRepoError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
             LocalRepoError::AlreadyExists(_) => None,
+            LocalRepoError::NotARepository(_) => None,
+            LocalRepoError::RefusingToRemove(_) => None,
+            LocalRepoError::DirectoryRemovalError((_, e)) => Some(e),
             LocalRepoError::DirectoryCreationError((_, e)) => Some(e),
+            LocalRepoError::FileWriteError((_, e)) => Some(e),
+            LocalRepoError::Locked(_) => None,
+            LocalRepoError::PackStreamError(e) => Some(e),
+            LocalRepoError::PackWriteError(e) => Some(e),
+            LocalRepoError::PackSpoolError(e) => Some(e),
             LocalRepoError::ExternalGitCommandSpawnFailure(e) => Some(e),
-            LocalRepoError::ExternalGitCommandError(_) => None,
+            LocalRepoError::ExternalGitCommandReadError(e) => Some(e),
+            LocalRepoError::Stalled(_) => None,
+            LocalRepoError::ExternalGitCommandError(..) => None,
+            #[cfg(feature = "gix")]
+            LocalRepoError::PackIndexError(_) => None,
         }
     }
 }
@@ -70,44 +383,295 @@ type Result<T> = std::result::Result<T, LocalRepoError>;
 
 pub struct LocalRepo {
     path: PathBuf,
+    git_dir: PathBuf,
+    settings: GitSettings,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PeeledTag {
+    Peeled(String),
+    Missing(String),
+}
+
+/* A running git command. Its stderr (unless inherited) is read in the
+ * background, so the command can't block on a full pipe. */
+struct GitChild {
+    child: Child,
+    stderr: Option<JoinHandle<String>>,
+}
+
+impl GitChild {
+    fn stdout(&mut self) -> ChildStdout {
+        self.child.stdout.take().expect("Failed to capture stdout")
+    }
+
+    /* Ends a command whose remaining output isn't needed */
+    async fn stop(mut self) {
+        let _ = self.child.kill().await;
+        if let Some(reader) = self.stderr {
+            let _ = reader.await;
+        }
+    }
+}
+
+/* Limits for walking the history */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RevListOptions {
+    pub first_parent: bool,
+    pub max_count: Option<usize>,
+}
+
+fn spawn(cmd: &mut Command) -> Result<GitChild> {
+    spawn_teeing(cmd, false)
+}
+
+/* Like spawn, but with `tee` stderr is also passed on to our stderr as
+ * it arrives, for progress output */
+fn spawn_teeing(cmd: &mut Command, tee: bool) -> Result<GitChild> {
+    let mut child = cmd
+        .spawn()
+        .map_err(LocalRepoError::ExternalGitCommandSpawnFailure)?;
+    let stderr = child.stderr.take().map(|mut pipe| {
+        tokio::spawn(async move {
+            let mut buf = Vec::new();
+            let mut chunk = [0u8; 4096];
+            while let Ok(n) = pipe.read(&mut chunk).await {
+                if n == 0 {
+                    break;
+                }
+                if tee {
+                    let mut out = std::io::stderr();
+                    let _ = out.write_all(&chunk[..n]);
+                    let _ = out.flush();
+                }
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            String::from_utf8_lossy(&buf).into_owned()
+        })
+    });
+    Ok(GitChild { child, stderr })
+}
+
+async fn next_line<R: AsyncBufRead + Unpin>(lines: &mut Lines<R>) -> Result<Option<String>> {
+    lines
+        .next_line()
+        .await
+        .map_err(LocalRepoError::ExternalGitCommandReadError)
 }
 
-async fn wait_result<T, U: FnOnce() -> T>(mut child: Child, func: U) -> Result<T> {
-    let es = child.wait().await.expect("Waiting for git command");
-    if es.success() {
+/* Logs what a successful command wrote */
+fn log_output(stderr: &str) {
+    /* Progress output rewrites its line with \r, only the last version
+     * is interesting */
+    for line in stderr
+        .lines()
+        .filter_map(|l| l.rsplit('\r').find(|p| !p.trim().is_empty()))
+    {
+        debug!("git: {}", line);
+    }
+}
+
+/* Waits for the command. Whatever it wrote (stderr, and stdout unless
+ * the caller read that) is logged at debug level, or on failure kept
+ * in the error. */
+async fn wait_result<T, U: FnOnce() -> T>(child: GitChild, func: U) -> Result<T> {
+    let out = child
+        .child
+        .wait_with_output()
+        .await
+        .map_err(LocalRepoError::ExternalGitCommandReadError)?;
+    let mut captured = match child.stderr {
+        Some(reader) => reader.await.unwrap_or_default(),
+        None => String::new(),
+    };
+    captured.push_str(&String::from_utf8_lossy(&out.stdout));
+    if out.status.success() {
+        log_output(&captured);
         Ok(func())
     } else {
-        Err(LocalRepoError::ExternalGitCommandError(es))
+        Err(LocalRepoError::ExternalGitCommandError(
+            out.status, captured,
+        ))
+    }
+}
+
+/* Runs a command to completion, collecting its output */
+async fn output(cmd: &mut Command) -> Result<std::process::Output> {
+    let out = cmd
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(LocalRepoError::ExternalGitCommandSpawnFailure)?
+        .wait_with_output()
+        .await
+        .map_err(LocalRepoError::ExternalGitCommandSpawnFailure)?;
+    if out.status.success() {
+        log_output(&String::from_utf8_lossy(&out.stderr));
     }
+    Ok(out)
+}
+
+fn command_error(out: &std::process::Output) -> LocalRepoError {
+    LocalRepoError::ExternalGitCommandError(
+        out.status,
+        String::from_utf8_lossy(&out.stderr).into_owned(),
+    )
 }
 
 impl LocalRepo {
-    pub async fn init_new(path: &Path) -> Result<Self> {
+    /* `initial_branch` is what HEAD points to until it is set, naming it
+     * also keeps git from giving advice about it */
+    pub async fn init_new(
+        path: &Path,
+        bare: bool,
+        initial_branch: Option<&str>,
+        settings: &GitSettings,
+    ) -> Result<Self> {
         std::fs::create_dir(path).map_err(|e| match e.kind() {
             std::io::ErrorKind::AlreadyExists => LocalRepoError::AlreadyExists(path.into()),
             _ => LocalRepoError::DirectoryCreationError((path.into(), e)),
         })?;
 
+        let mut cmd = git_command(&settings.git);
+        cmd.arg("init")
+            .arg("-q")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if bare {
+            cmd.arg("--bare");
+        }
+        if let Some(branch) = initial_branch {
+            cmd.arg(format!("--initial-branch={}", branch));
+        }
+
+        wait_result(spawn(cmd.arg(path))?, || Self {
+            path: path.into(),
+            git_dir: if bare { path.into() } else { path.join(".git") },
+            settings: settings.clone(),
+        })
+        .await
+    }
+
+    /* Removes a previous clone. As a safety measure only directories that
+     * look like git repositories (with a .git directory, or the layout of
+     * a bare repository) are removed. */
+    pub fn remove(path: &Path) -> Result<()> {
+        /* .git is a file in linked worktrees */
+        let is_repo = path.join(".git").exists()
+            || (path.join("HEAD").is_file() && path.join("objects").is_dir());
+        if !is_repo {
+            return Err(LocalRepoError::RefusingToRemove(path.into()));
+        }
+
+        std::fs::remove_dir_all(path)
+            .map_err(|e| LocalRepoError::DirectoryRemovalError((path.into(), e)))
+    }
+
+    /* The object directory of a repository to borrow objects from, either
+     * a bare repository or one with a working tree */
+    pub fn reference_objects_dir(path: &Path) -> Option<PathBuf> {
+        [path.join(".git").join("objects"), path.join("objects")]
+            .into_iter()
+            .find(|dir| dir.is_dir())
+            .and_then(|dir| std::path::absolute(dir).ok())
+    }
+
+    fn alternates_file(&self) -> PathBuf {
+        self.git_dir.join("objects").join("info").join("alternates")
+    }
+
+    /* Objects present in any of `objects_dirs` aren't fetched again */
+    pub fn add_alternates(&self, objects_dirs: &[PathBuf]) -> Result<()> {
+        let file = self.alternates_file();
+        let mut contents = std::fs::read_to_string(&file).unwrap_or_default();
+        for dir in objects_dirs {
+            let line = dir.to_string_lossy();
+            if !contents.lines().any(|l| l == line) {
+                contents.push_str(&line);
+                contents.push('\n');
+            }
+        }
+
+        let info_dir = file.parent().unwrap();
+        std::fs::create_dir_all(info_dir)
+            .map_err(|e| LocalRepoError::DirectoryCreationError((info_dir.into(), e)))?;
+        std::fs::write(&file, contents).map_err(|e| LocalRepoError::FileWriteError((file, e)))
+    }
+
+    /* Copies the objects borrowed from alternates into the repository and
+     * stops using them */
+    pub async fn dissociate(&self) -> Result<()> {
+        let file = self.alternates_file();
+        if !file.exists() {
+            return Ok(());
+        }
         wait_result(
-            Command::new("git")
-                .arg("init")
-                .arg(path)
-                .spawn()
-                .map_err(LocalRepoError::ExternalGitCommandSpawnFailure)?,
-            || Self { path: path.into() },
+            spawn(self.git().arg("repack").arg("-a").arg("-d").arg("-q"))?,
+            || (),
         )
-        .await
+        .await?;
+        std::fs::remove_file(&file).map_err(|e| LocalRepoError::FileWriteError((file, e)))
+    }
+
+    pub async fn open(path: &Path, settings: &GitSettings) -> Result<Self> {
+        let not_a_repo = || LocalRepoError::NotARepository(path.into());
+
+        let out = git_command(&settings.git)
+            .arg("-C")
+            .arg(path)
+            .arg("rev-parse")
+            .arg("--absolute-git-dir")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(LocalRepoError::ExternalGitCommandSpawnFailure)?
+            .wait_with_output()
+            .await
+            .map_err(LocalRepoError::ExternalGitCommandSpawnFailure)?;
+        if !out.status.success() {
+            return Err(not_a_repo());
+        }
+        let git_dir = PathBuf::from(String::from_utf8_lossy(&out.stdout).trim_end());
+
+        /* Don't accept a plain directory inside some other repository */
+        let toplevel = path.canonicalize().map_err(|_| not_a_repo())?;
+        if git_dir != toplevel && git_dir != toplevel.join(".git") {
+            return Err(not_a_repo());
+        }
+
+        Ok(Self {
+            path: path.into(),
+            git_dir,
+            settings: settings.clone(),
+        })
+    }
+
+    /* Locks .git/shallow like git does, so concurrent updates (by us or
+     * by git) aren't lost */
+    async fn lock_shallow_file(&self) -> Result<LockFile> {
+        let path = self.git_dir.join("shallow");
+        LockFile::acquire(&path, SHALLOW_LOCK_TIMEOUT)
+            .await
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::TimedOut => LocalRepoError::Locked(path.clone()),
+                _ => LocalRepoError::FileWriteError((path.clone(), e)),
+            })
     }
 
-    pub async fn get_shallow_shas(&self) -> HashSet<String> {
-        let path = self.path.join(".git/shallow");
+    pub async fn get_shallow_shas(&self) -> Result<HashSet<String>> {
+        let _lock = self.lock_shallow_file().await?;
+        Ok(self.read_shallow_file().await)
+    }
+
+    async fn read_shallow_file(&self) -> HashSet<String> {
+        let path = self.git_dir.join("shallow");
         read_lines_to_set(&path)
             .await
             .unwrap_or_else(|_e| HashSet::new())
     }
 
-    pub async fn update_shallow_file(&self, info: &Vec<ShallowInfo>) {
-        let mut shallow_shas = self.get_shallow_shas().await;
+    pub async fn update_shallow_file(&self, info: &Vec<ShallowInfo>) -> Result<()> {
+        let lock = self.lock_shallow_file().await?;
+        let mut shallow_shas = self.read_shallow_file().await;
 
         for e in info {
             match e {
@@ -116,18 +680,30 @@ impl LocalRepo {
             };
         }
 
-        let path = self.path.join(".git/shallow");
-        write_lines_from_set(&path, &shallow_shas).await.unwrap();
+        let path = self.git_dir.join("shallow");
+        lock.commit_lines(&shallow_shas)
+            .await
+            .map_err(|e| LocalRepoError::FileWriteError((path, e)))
+    }
+
+    /* Sets `key` in the repository's config, replacing all its values */
+    pub async fn set_config(&self, key: &str, value: &str) -> Result<()> {
+        wait_result(
+            spawn(
+                self.git()
+                    .arg("config")
+                    .arg("--replace-all")
+                    .arg(key)
+                    .arg(value),
+            )?,
+            || (),
+        )
+        .await
     }
 
     pub async fn update_ref(&self, refname: &str, sha: &str) -> Result<()> {
         wait_result(
-            self.git()
-                .arg("update-ref")
-                .arg(refname)
-                .arg(sha)
-                .spawn()
-                .map_err(LocalRepoError::ExternalGitCommandSpawnFailure)?,
+            spawn(self.git().arg("update-ref").arg(refname).arg(sha))?,
             || (),
         )
         .await
@@ -135,72 +711,276 @@ impl LocalRepo {
 
     pub async fn update_head(&self, refname: &str) -> Result<()> {
         wait_result(
-            self.git()
-                .arg("symbolic-ref")
-                .arg("HEAD")
-                .arg(refname)
-                .spawn()
-                .map_err(LocalRepoError::ExternalGitCommandSpawnFailure)?,
+            spawn(self.git().arg("symbolic-ref").arg("HEAD").arg(refname))?,
             || (),
         )
         .await
     }
 
-    pub async fn checkout_head(&self) -> Result<()> {
-        wait_result(
-            self.git()
+    /* Checks out `rev` (a branch name, or a commit with `detach`) in a
+     * linked worktree at `path`. With `update` an existing worktree there
+     * is switched to `rev` instead, discarding local changes. */
+    pub async fn add_worktree(
+        &self,
+        path: &Path,
+        rev: &str,
+        detach: bool,
+        update: bool,
+    ) -> Result<()> {
+        if path.exists() {
+            if !update {
+                return Err(LocalRepoError::AlreadyExists(path.into()));
+            }
+            if !path.join(".git").is_file() {
+                return Err(LocalRepoError::NotARepository(path.into()));
+            }
+            let mut cmd = git_command(&self.settings.git);
+            cmd.arg("-C")
+                .arg(path)
                 .arg("checkout")
-                .arg("HEAD")
-                .spawn()
-                .map_err(LocalRepoError::ExternalGitCommandSpawnFailure)?,
+                .arg("-q")
+                .arg("-f")
+                .arg("--ignore-other-worktrees")
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+            if detach {
+                cmd.arg("--detach");
+            }
+            return wait_result(spawn(cmd.arg(rev))?, || ()).await;
+        }
+
+        /* Forget about worktrees that were removed */
+        wait_result(spawn(self.git().arg("worktree").arg("prune"))?, || ()).await?;
+
+        let path = std::path::absolute(path)
+            .map_err(|e| LocalRepoError::DirectoryCreationError((path.into(), e)))?;
+        let mut cmd = self.git();
+        /* -f as the branch is HEAD of the repository itself as well */
+        cmd.arg("worktree").arg("add").arg("-q").arg("-f");
+        if detach {
+            cmd.arg("--detach");
+        }
+        wait_result(spawn(cmd.arg(path).arg(rev))?, || ()).await
+    }
+
+    pub async fn detach_head(&self, sha: &str) -> Result<()> {
+        wait_result(
+            spawn(
+                self.git()
+                    .arg("update-ref")
+                    .arg("--no-deref")
+                    .arg("HEAD")
+                    .arg(sha),
+            )?,
             || (),
         )
         .await
     }
 
+    /* The branch HEAD points to, None if detached */
+    pub async fn head_ref(&self) -> Result<Option<String>> {
+        let out = output(self.git().arg("symbolic-ref").arg("-q").arg("HEAD")).await?;
+        match out.status.code() {
+            Some(0) => Ok(Some(
+                String::from_utf8_lossy(&out.stdout).trim_end().to_string(),
+            )),
+            Some(1) => Ok(None),
+            _ => Err(command_error(&out)),
+        }
+    }
+
+    pub async fn has_commit(&self, sha: &str) -> Result<bool> {
+        let out = output(
+            self.git()
+                .arg("cat-file")
+                .arg("-e")
+                .arg(format!("{}^{{commit}}", sha)),
+        )
+        .await?;
+        Ok(out.status.success())
+    }
+
+    pub async fn checkout_head(&self, force: bool) -> Result<()> {
+        let mut cmd = self.git();
+        cmd.arg("checkout").arg("-q");
+        if force {
+            cmd.arg("-f");
+        }
+        wait_result(spawn(cmd.arg("HEAD"))?, || ()).await
+    }
+
+    pub async fn ref_shas(&self) -> Result<HashSet<String>> {
+        let mut cmd = spawn(
+            self.git()
+                .arg("for-each-ref")
+                .arg("--format=%(objectname)")
+                .stdout(Stdio::piped()),
+        )?;
+
+        let reader = BufReader::new(cmd.stdout());
+        let mut lines = reader.lines();
+
+        let mut result = HashSet::new();
+        while let Some(line) = next_line(&mut lines).await? {
+            result.insert(line);
+        }
+
+        let mut result = wait_result(cmd, || result).await?;
+
+        /* The tips of repositories we borrow objects from count as well */
+        if self.alternates_file().exists() {
+            let mut cmd = spawn(
+                self.git()
+                    .arg("rev-list")
+                    .arg("--no-walk")
+                    .arg("--alternate-refs"),
+            )?;
+            let mut lines = BufReader::new(cmd.stdout()).lines();
+            let mut alternate = Vec::new();
+            while let Some(line) = next_line(&mut lines).await? {
+                alternate.push(line);
+            }
+            result.extend(wait_result(cmd, || alternate).await?);
+        }
+
+        Ok(result)
+    }
+
+    /* The local branches, like a remote's ls-refs lists them */
+    pub async fn list_branches(&self) -> Result<Vec<RefInfo>> {
+        self.list_refs("refs/heads").await
+    }
+
+    /* The refs below `prefix`, tags not peeled */
+    pub async fn list_refs(&self, prefix: &str) -> Result<Vec<RefInfo>> {
+        let mut cmd = spawn(
+            self.git()
+                .arg("for-each-ref")
+                .arg("--format=%(objectname) %(refname)")
+                .arg(prefix)
+                .stdout(Stdio::piped()),
+        )?;
+
+        let mut lines = BufReader::new(cmd.stdout()).lines();
+        let mut refs = Vec::new();
+        while let Some(line) = next_line(&mut lines).await? {
+            if let Some((sha, refname)) = line.split_once(' ') {
+                refs.push(RefInfo {
+                    sha: sha.to_string(),
+                    refname: refname.to_string(),
+                    peeled: None,
+                });
+            }
+        }
+        wait_result(cmd, || refs).await
+    }
+
+    /* The commit checked out */
+    pub async fn head_sha(&self) -> Result<String> {
+        let out = output(self.git().arg("rev-parse").arg("--verify").arg("HEAD")).await?;
+        if !out.status.success() {
+            return Err(command_error(&out));
+        }
+        Ok(String::from_utf8_lossy(&out.stdout).trim_end().to_string())
+    }
+
     pub async fn rev_list(&self, sha: &str) -> Result<Vec<String>> {
-        let mut cmd = self
-            .git()
-            .arg("rev-list")
-            .arg(sha)
-            .stdout(Stdio::piped())
-            .spawn()
-            .map_err(LocalRepoError::ExternalGitCommandSpawnFailure)?;
+        let mut cmd = spawn(self.git().arg("rev-list").arg(sha).stdout(Stdio::piped()))?;
 
-        let stdout = cmd.stdout.take().expect("Failed to capture stdout");
+        let stdout = cmd.stdout();
         let reader = BufReader::new(stdout);
         let mut lines = reader.lines();
 
         let mut result = Vec::new();
-        while let Some(line) = lines.next_line().await.unwrap() {
+        while let Some(line) = next_line(&mut lines).await? {
             result.push(line);
         }
 
         wait_result(cmd, || result).await
     }
 
+    /* Streams the commits reachable from any of `shas` to `visit` until it
+     * breaks. Returns how many commits were visited. */
+    pub async fn walk_commits<F>(
+        &self,
+        shas: &[&str],
+        opts: RevListOptions,
+        mut visit: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(&str) -> ControlFlow<()>,
+    {
+        let mut cmd = self.git();
+        cmd.arg("rev-list");
+        if opts.first_parent {
+            cmd.arg("--first-parent");
+        }
+        if let Some(max_count) = opts.max_count {
+            cmd.arg(format!("--max-count={}", max_count));
+        }
+        let mut cmd = spawn(cmd.args(shas))?;
+
+        let mut lines = BufReader::new(cmd.stdout()).lines();
+        let mut count = 0;
+        while let Some(line) = next_line(&mut lines).await? {
+            count += 1;
+            if visit(&line).is_break() {
+                cmd.stop().await;
+                return Ok(count);
+            }
+        }
+
+        wait_result(cmd, || count).await
+    }
+
     pub async fn distance(&self, from: &str, to: &str) -> Result<usize> {
-        let mut cmd = self
-            .git()
-            .arg("rev-list")
-            .arg(to)
-            .arg("--not")
-            .arg(from)
-            .stdout(Stdio::piped())
-            .spawn()
-            .map_err(LocalRepoError::ExternalGitCommandSpawnFailure)?;
+        let mut cmd = spawn(
+            self.git()
+                .arg("rev-list")
+                .arg(to)
+                .arg("--not")
+                .arg(from)
+                .stdout(Stdio::piped()),
+        )?;
+
+        let reader = BufReader::new(cmd.stdout());
+        let mut lines = reader.lines();
+
+        let mut result = 0;
+        while next_line(&mut lines).await?.is_some() {
+            result += 1;
+        }
+
+        wait_result(cmd, || result).await
+    }
+
+    /* Checks that every object reachable from `sha` is present. History
+     * beyond the shallow boundary isn't expected to be there, rev-list
+     * stops at it. Returns the number of objects checked. */
+    pub async fn verify_connectivity(&self, sha: &str) -> Result<usize> {
+        let mut cmd = spawn(
+            self.git()
+                .arg("rev-list")
+                .arg("--objects")
+                .arg("--missing=error")
+                .arg(sha),
+        )?;
 
-        let reader = BufReader::new(cmd.stdout.take().expect("Failed to capture stdout"));
+        let reader = BufReader::new(cmd.stdout());
         let mut lines = reader.lines();
 
         let mut result = 0;
-        while lines.next_line().await.unwrap().is_some() {
+        while next_line(&mut lines).await?.is_some() {
             result += 1;
         }
 
         wait_result(cmd, || result).await
     }
 
+    pub fn is_bare(&self) -> bool {
+        self.git_dir.file_name() != Some(OsStr::new(".git"))
+    }
+
     pub fn basename(&self) -> &OsStr {
         self.path
             .file_name()
@@ -208,30 +988,101 @@ impl LocalRepo {
     }
 
     pub async fn commit_date_iso(&self, sha: &str) -> Result<String> {
-        let out = self
-            .git()
-            .arg("log")
-            .arg("-1")
-            .arg("--pretty=format:%ci")
-            .arg(sha)
-            .stdout(Stdio::piped())
-            .spawn()
-            .map_err(LocalRepoError::ExternalGitCommandSpawnFailure)?
-            .wait_with_output()
-            .await
-            .map_err(LocalRepoError::ExternalGitCommandSpawnFailure)?;
-        let es = out.status;
-        if es.success() {
+        let out = output(
+            self.git()
+                .arg("log")
+                .arg("-1")
+                .arg("--pretty=format:%ci")
+                .arg(sha),
+        )
+        .await?;
+        if out.status.success() {
             Ok(String::from_utf8_lossy(&out.stdout).to_string())
         } else {
-            Err(LocalRepoError::ExternalGitCommandError(es))
+            Err(command_error(&out))
+        }
+    }
+
+    /* The committer time (seconds since the epoch) of each commit */
+    pub async fn commit_times(&self, shas: &[&str]) -> Result<HashMap<String, i64>> {
+        if shas.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let out = output(
+            self.git()
+                .arg("log")
+                .arg("--no-walk=unsorted")
+                .arg("--pretty=format:%H %ct")
+                .args(shas),
+        )
+        .await?;
+        if out.status.success() {
+            Ok(String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .filter_map(|l| {
+                    let (sha, time) = l.split_once(' ')?;
+                    Some((sha.to_string(), time.parse().ok()?))
+                })
+                .collect())
+        } else {
+            Err(command_error(&out))
+        }
+    }
+
+    async fn object_type(&self, sha: &str) -> Result<Option<String>> {
+        let out = output(
+            self.git()
+                .arg("cat-file")
+                .arg("-t")
+                .arg(sha)
+                .stderr(Stdio::null()),
+        )
+        .await?;
+        if out.status.success() {
+            Ok(Some(
+                String::from_utf8_lossy(&out.stdout).trim().to_string(),
+            ))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn tag_target(&self, sha: &str) -> Result<Option<String>> {
+        let out = output(self.git().arg("cat-file").arg("tag").arg(sha)).await?;
+        if out.status.success() {
+            Ok(String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .find_map(|l| l.strip_prefix("object "))
+                .map(|s| s.to_string()))
+        } else {
+            Err(command_error(&out))
+        }
+    }
+
+    /* Follows a (possibly nested) chain of annotated tags down to the
+     * first non-tag object. If some object along the chain isn't present
+     * locally, that object is reported instead. */
+    pub async fn peel_tag(&self, sha: &str) -> Result<PeeledTag> {
+        let mut current = sha.to_string();
+        loop {
+            match self.object_type(&current).await?.as_deref() {
+                None => return Ok(PeeledTag::Missing(current)),
+                Some("tag") => match self.tag_target(&current).await? {
+                    Some(target) => current = target,
+                    None => return Ok(PeeledTag::Missing(current)),
+                },
+                Some(_) => return Ok(PeeledTag::Peeled(current)),
+            }
         }
     }
 
     fn git(&self) -> tokio::process::Command {
-        let mut cmd = Command::new("git");
+        let mut cmd = git_command(&self.settings.git);
         cmd.arg("-C");
         cmd.arg(&self.path);
+        /* Output is captured and only logged, see wait_result */
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
         cmd
     }
 
@@ -240,51 +1091,1041 @@ impl LocalRepo {
         S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
         E: Into<std::io::Error>,
     {
-        let mut index_pack_cmd = self
-            .git()
-            .arg("index-pack")
-            .arg("--stdin")
-            .arg("-v")
-            .stdin(Stdio::piped())
-            .spawn()
-            .map_err(LocalRepoError::ExternalGitCommandSpawnFailure)?;
+        let settings = &self.settings;
+        self.handle_packfile_with(
+            stream,
+            settings.pack_backend,
+            settings.pack_spool,
+            settings.stall_limit,
+            settings.rate_limit.as_deref(),
+        )
+        .await
+    }
+
+    fn pack_sink(&self, backend: PackBackend) -> Result<PackSink> {
+        Ok(match backend {
+            PackBackend::Git => {
+                let mut index_pack = self.git();
+                index_pack.arg("index-pack").arg("--stdin");
+                if logging::progress_enabled() {
+                    index_pack.arg("-v");
+                }
+                let mut index_pack_cmd = spawn_teeing(
+                    index_pack.stdin(Stdio::piped()),
+                    logging::progress_enabled(),
+                )?;
+
+                let stdin = index_pack_cmd
+                    .child
+                    .stdin
+                    .take()
+                    .expect("child didn't have a stdin");
+                PackSink::Git(index_pack_cmd, stdin)
+            }
+            #[cfg(feature = "gix")]
+            PackBackend::Gix => {
+                let (tx, rx) = tokio::sync::mpsc::channel(16);
+                let pack_dir = self.git_dir.join("objects").join("pack");
+                let indexer = tokio::task::spawn_blocking(move || {
+                    crate::gix_backend::index_pack(rx, &pack_dir)
+                });
+                PackSink::Gix(tx, indexer)
+            }
+        })
+    }
+
+    fn pack_spool(&self) -> Result<PackSink> {
+        let (file, path) = tempfile::Builder::new()
+            .prefix("tmp_spool_")
+            .tempfile_in(self.git_dir.join("objects").join("pack"))
+            .map_err(LocalRepoError::PackSpoolError)?
+            .into_parts();
+        debug!("Spooling the pack to {}", path.display());
+        Ok(PackSink::Spool(tokio::fs::File::from_std(file), path))
+    }
+
+    /* Passes a completely received pack on to the indexer */
+    async fn index_spooled(
+        &self,
+        mut file: tokio::fs::File,
+        backend: PackBackend,
+    ) -> Result<(PackSink, Option<LocalRepoError>)> {
+        let mut sink = self.pack_sink(backend)?;
+        let read = async {
+            file.flush().await?;
+            file.rewind().await?;
+            let mut buf = vec![0; 1 << 16];
+            loop {
+                let n = file.read(&mut buf).await?;
+                if n == 0 {
+                    return Ok(None);
+                }
+                if let ControlFlow::Break(e) = sink.write(&Bytes::copy_from_slice(&buf[..n])).await
+                {
+                    return Ok(e);
+                }
+            }
+        };
+        let error = read
+            .await
+            .unwrap_or_else(|e| Some(LocalRepoError::PackSpoolError(e)));
+        Ok((sink, error))
+    }
 
-        let mut stdin = index_pack_cmd
-            .stdin
-            .take()
-            .expect("child didn't have a stdin");
-
-        while let Some(pkt) = stream.next().await {
-            match pkt.expect("Stream error") {
-                GitPacketLine::Data(data) => {
-                    let d: SideBand = data.into();
-                    match d {
-                        SideBand::PackData(payload) => {
-                            stdin.write_all(&payload).await.expect("write");
-                        }
-                        SideBand::Progress(msg) => {
-                            print!("{}", msg);
-                            std::io::stdout().flush().unwrap();
-                        }
-                        SideBand::ErrorMessage(msg) => {
-                            println!("remote: {}", msg);
-                        }
-                        SideBand::Unknown(b) => {
-                            let first_40 = b.slice(0..std::cmp::min(40, b.len()));
-                            warn!("unknown sideband channel data: {first_40:?}");
-                        }
+    async fn handle_packfile_with<S, E>(
+        &self,
+        stream: &mut GitPacketLineStream<S>,
+        backend: PackBackend,
+        spool: bool,
+        stall_limit: Option<StallLimit>,
+        rate_limit: Option<&RateLimit>,
+    ) -> Result<()>
+    where
+        S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+        E: Into<std::io::Error>,
+    {
+        let mut sink = if spool {
+            self.pack_spool()?
+        } else {
+            self.pack_sink(backend)?
+        };
+
+        let mut sideband = SideBandStream::new(stream);
+        let mut failure = None;
+        let mut write_error = None;
+        let mut watchdog = StallWatchdog::new(stall_limit);
+        let mut stalled = None;
+        let started = tokio::time::Instant::now();
+        loop {
+            let band = tokio::select! {
+                band = sideband.next() => band,
+                _ = watchdog.window_end() => {
+                    if let Err(e) = watchdog.check() {
+                        stalled = Some(e);
+                        break;
                     }
+                    continue;
                 }
-                GitPacketLine::Flush => {
+            };
+            let band = match band {
+                Some(Ok(band)) => band,
+                Some(Err(e)) => {
+                    failure = Some(e);
                     break;
                 }
-                GitPacketLine::Delimiter => {
-                    warn!("Unexpected delimiter");
-                    break;
+                None => break,
+            };
+            if let (SideBand::PackData(payload), Some(limit)) = (&band, rate_limit) {
+                watchdog.paused(limit.take(payload.len()).await);
+            }
+            watchdog.received(match &band {
+                SideBand::PackData(b) | SideBand::Unknown(b) => b.len(),
+                SideBand::Progress(m) | SideBand::ErrorMessage(m) => m.len(),
+            });
+            match band {
+                /* If the indexer gave up its error is reported below */
+                SideBand::PackData(payload) => {
+                    if let ControlFlow::Break(e) = sink.write(&payload).await {
+                        write_error = e;
+                        break;
+                    }
+                }
+                SideBand::Progress(msg) => {
+                    if logging::progress_enabled() {
+                        eprint!("{}", msg);
+                        let _ = std::io::stderr().flush();
+                    } else {
+                        trace!("remote: {}", msg.trim_end());
+                    }
+                }
+                SideBand::ErrorMessage(msg) => {
+                    eprintln!("remote: {}", msg);
+                }
+                SideBand::Unknown(b) => {
+                    let first_40 = b.slice(0..std::cmp::min(40, b.len()));
+                    warn!("unknown sideband channel data: {first_40:?}");
+                }
+            }
+        }
+        let elapsed = started.elapsed().as_secs_f64();
+        info!(
+            "Received {} bytes of pack data in {:.1}s ({:.0} bytes/s)",
+            sideband.pack_bytes(),
+            elapsed,
+            sideband.pack_bytes() as f64 / elapsed.max(0.001)
+        );
+
+        if let Some(e) = stalled {
+            sink.stop().await;
+            return Err(e);
+        }
+
+        /* A spooled pack is indexed once it was received completely, the
+         * file is removed afterwards */
+        let (sink, _spooled) = match sink {
+            PackSink::Spool(file, path) if failure.is_none() && write_error.is_none() => {
+                let (sink, error) = self.index_spooled(file, backend).await?;
+                write_error = error;
+                (sink, Some(path))
+            }
+            sink => (sink, None),
+        };
+        let indexed = sink.finish().await;
+        /* Why index-pack exited explains a failed write better than the
+         * broken pipe does */
+        match failure {
+            Some(e) => Err(LocalRepoError::PackStreamError(e)),
+            None => indexed.and(write_error.map_or(Ok(()), Err)),
+        }
+    }
+}
+
+/* Removes the directories of a clone that didn't finish, unless disarmed.
+ * With `keep_failed` they are renamed to "<name>.failed-<timestamp>"
+ * instead, for a look at what went wrong. */
+pub struct CleanupGuard {
+    paths: Vec<PathBuf>,
+    keep_failed: bool,
+}
+
+impl CleanupGuard {
+    pub fn new(keep_failed: bool) -> Self {
+        CleanupGuard {
+            paths: Vec::new(),
+            keep_failed,
+        }
+    }
+
+    /* Only paths created by this clone should be added */
+    pub fn add(&mut self, path: &Path) {
+        self.paths.push(path.to_path_buf());
+    }
+
+    /* The clone succeeded, everything stays */
+    pub fn disarm(mut self) {
+        self.paths.clear();
+    }
+
+    fn failed_path(path: &Path) -> PathBuf {
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".failed-{}", secs));
+        path.with_file_name(name)
+    }
+}
+
+impl Drop for CleanupGuard {
+    fn drop(&mut self) {
+        /* Worktrees are added after their repository */
+        for path in self.paths.iter().rev().filter(|p| p.exists()) {
+            if self.keep_failed {
+                let failed = Self::failed_path(path);
+                match std::fs::rename(path, &failed) {
+                    Ok(()) => warn!("Kept the failed clone as {}", failed.display()),
+                    Err(e) => warn!("Could not rename {}: {}", path.display(), e),
+                }
+            } else {
+                match LocalRepo::remove(path) {
+                    Ok(()) => info!("Removed the incomplete {}", path.display()),
+                    Err(e) => warn!("{}", e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let out = std::process::Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .output()
+            .expect("running git");
+        assert!(out.status.success(), "git {:?} failed", args);
+        String::from_utf8_lossy(&out.stdout).trim().to_string()
+    }
+
+    /* A repo with a commit, an annotated tag on it and a tag of that tag. */
+    fn nested_tag_fixture() -> (tempfile::TempDir, String, String, String) {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "-q"]);
+        git(
+            dir.path(),
+            &["commit", "-q", "--allow-empty", "-m", "first"],
+        );
+        git(
+            dir.path(),
+            &["tag", "-a", "-m", "inner", "v1.0-inner", "HEAD"],
+        );
+        git(
+            dir.path(),
+            &["tag", "-a", "-m", "outer", "v1.0", "v1.0-inner"],
+        );
+
+        let commit = git(dir.path(), &["rev-parse", "HEAD"]);
+        let inner = git(dir.path(), &["rev-parse", "refs/tags/v1.0-inner"]);
+        let outer = git(dir.path(), &["rev-parse", "refs/tags/v1.0"]);
+        (dir, commit, inner, outer)
+    }
+
+    #[tokio::test]
+    async fn peels_nested_tag() {
+        let (dir, commit, inner, outer) = nested_tag_fixture();
+        let repo = LocalRepo {
+            path: dir.path().into(),
+            git_dir: dir.path().join(".git"),
+            settings: GitSettings::default(),
+        };
+
+        assert_eq!(
+            repo.peel_tag(&outer).await.unwrap(),
+            PeeledTag::Peeled(commit.clone())
+        );
+        assert_eq!(
+            repo.peel_tag(&inner).await.unwrap(),
+            PeeledTag::Peeled(commit.clone())
+        );
+        assert_eq!(
+            repo.peel_tag(&commit).await.unwrap(),
+            PeeledTag::Peeled(commit)
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_missing_object_in_tag_chain() {
+        let (dir, _commit, inner, outer) = nested_tag_fixture();
+        let repo = LocalRepo {
+            path: dir.path().into(),
+            git_dir: dir.path().join(".git"),
+            settings: GitSettings::default(),
+        };
+
+        /* Drop the inner tag object, leaving the outer one dangling. */
+        git(dir.path(), &["tag", "-d", "v1.0-inner"]);
+        git(dir.path(), &["tag", "-d", "v1.0"]);
+        let packed = dir.path().join(".git/objects").join(&inner[..2]);
+        std::fs::remove_file(packed.join(&inner[2..])).unwrap();
+
+        assert_eq!(
+            repo.peel_tag(&outer).await.unwrap(),
+            PeeledTag::Missing(inner)
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_commit_times() {
+        let (dir, commit, _inner, _outer) = nested_tag_fixture();
+        let repo = LocalRepo {
+            path: dir.path().into(),
+            git_dir: dir.path().join(".git"),
+            settings: GitSettings::default(),
+        };
+        let expected: i64 = git(dir.path(), &["log", "-1", "--format=%ct"])
+            .parse()
+            .unwrap();
+
+        let times = repo.commit_times(&[&commit]).await.unwrap();
+        assert_eq!(times, HashMap::from([(commit, expected)]));
+        assert!(repo.commit_times(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lists_branches() {
+        let (dir, commit, _inner, _outer) = nested_tag_fixture();
+        git(dir.path(), &["branch", "release/1.4"]);
+        let repo = LocalRepo::open(dir.path(), &GitSettings::default())
+            .await
+            .unwrap();
+
+        let branches = repo.list_branches().await.unwrap();
+        let mut refnames: Vec<&str> = branches.iter().map(|b| b.refname.as_str()).collect();
+        refnames.sort();
+        let head = git(dir.path(), &["symbolic-ref", "HEAD"]);
+        assert_eq!(refnames, [head.as_str(), "refs/heads/release/1.4"]);
+        assert!(branches
+            .iter()
+            .all(|b| b.sha == commit && b.peeled.is_none()));
+        assert_eq!(repo.head_sha().await.unwrap(), commit);
+
+        let tags = repo.list_refs("refs/tags").await.unwrap();
+        let outer = tags.iter().find(|t| t.refname == "refs/tags/v1.0").unwrap();
+        assert_eq!(outer.sha, git(dir.path(), &["rev-parse", "refs/tags/v1.0"]));
+    }
+
+    #[tokio::test]
+    async fn peels_lightweight_tag() {
+        let (dir, commit, _inner, outer) = nested_tag_fixture();
+        let repo = LocalRepo {
+            path: dir.path().into(),
+            git_dir: dir.path().join(".git"),
+            settings: GitSettings::default(),
+        };
+        git(dir.path(), &["tag", "v1.0-light", "HEAD"]);
+        let light = git(dir.path(), &["rev-parse", "refs/tags/v1.0-light"]);
+
+        /* A lightweight tag is the commit itself */
+        assert_eq!(light, commit);
+        assert_eq!(
+            repo.peel_tag(&light).await.unwrap(),
+            PeeledTag::Peeled(commit.clone())
+        );
+        assert_eq!(
+            repo.peel_tag(&outer).await.unwrap(),
+            PeeledTag::Peeled(commit)
+        );
+    }
+
+    #[tokio::test]
+    async fn error_includes_git_stderr() {
+        let (dir, _commit, _inner, _outer) = nested_tag_fixture();
+        let repo = LocalRepo {
+            path: dir.path().into(),
+            git_dir: dir.path().join(".git"),
+            settings: GitSettings::default(),
+        };
+
+        let err = repo
+            .update_ref("refs/heads/broken", "not-a-sha")
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, LocalRepoError::ExternalGitCommandError(_, stderr) if stderr.contains("not-a-sha")),
+            "Got unexpected error: {:?}",
+            err
+        );
+        let msg = err.to_string();
+        assert!(
+            msg.starts_with("External git process failed: ") && msg.contains("fatal: "),
+            "Got unexpected message: {}",
+            msg
+        );
+    }
+
+    #[test]
+    fn parses_git_version() {
+        assert_eq!(parse_git_version("git version 2.20.1\n"), Some((2, 20)));
+        assert_eq!(
+            parse_git_version("git version 2.39.3 (Apple Git-146)"),
+            Some((2, 39))
+        );
+        assert_eq!(
+            parse_git_version("git version 2.45.1.windows.1"),
+            Some((2, 45))
+        );
+        assert_eq!(parse_git_version("hg version 6"), None);
+        assert!((2, 9) < MIN_GIT_VERSION);
+    }
+
+    /* Packs the fixture's history and sends it the way a server would,
+     * as sideband packet lines. Also checks that a truncated pack is
+     * rejected. */
+    async fn check_pack_backend(backend: PackBackend, spool: bool) {
+        let (src, commit, _inner, outer) = nested_tag_fixture();
+        let mut pack_objects = std::process::Command::new("git")
+            .arg("-C")
+            .arg(src.path())
+            .args([
+                "pack-objects",
+                "--revs",
+                "--stdout",
+                "--delta-base-offset",
+                "-q",
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        writeln!(pack_objects.stdin.take().unwrap(), "{}\n{}", commit, outer).unwrap();
+        let pack = pack_objects.wait_with_output().unwrap().stdout;
+
+        let sideband = |pack: &[u8]| {
+            let mut pkt = crate::pkt_line::PktLine::new();
+            for chunk in pack.chunks(1000) {
+                pkt = pkt.add(&[&[1u8], chunk].concat());
+            }
+            let data = Bytes::from(pkt.flush().take());
+            GitPacketLineStream::new(futures::stream::iter([Ok::<_, std::io::Error>(data)]))
+        };
+
+        let dest = tempfile::tempdir().unwrap();
+        let repo = LocalRepo::init_new(
+            &dest.path().join("ok"),
+            false,
+            Some("main"),
+            &GitSettings::default(),
+        )
+        .await
+        .unwrap();
+        repo.handle_packfile_with(&mut sideband(&pack), backend, spool, None, None)
+            .await
+            .unwrap();
+        git(&dest.path().join("ok"), &["cat-file", "-e", &commit]);
+        assert_eq!(
+            repo.peel_tag(&outer).await.unwrap(),
+            PeeledTag::Peeled(commit)
+        );
+
+        let repo = LocalRepo::init_new(
+            &dest.path().join("truncated"),
+            false,
+            None,
+            &GitSettings::default(),
+        )
+        .await
+        .unwrap();
+        let truncated = &pack[..pack.len() - 10];
+        assert!(repo
+            .handle_packfile_with(&mut sideband(truncated), backend, spool, None, None)
+            .await
+            .is_err());
+
+        for repo in ["ok", "truncated"] {
+            let pack_dir = dest.path().join(repo).join(".git/objects/pack");
+            let leftovers: Vec<_> = std::fs::read_dir(pack_dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .filter(|name| name.to_string_lossy().starts_with("tmp_spool_"))
+                .collect();
+            assert!(leftovers.is_empty(), "{:?}", leftovers);
+        }
+    }
+
+    /* A fetch that fails halfway, with a guard like clone_one's */
+    async fn failing_clone(path: &Path, guard: &mut CleanupGuard) -> Result<()> {
+        guard.add(path);
+        let repo = LocalRepo::init_new(path, false, None, &GitSettings::default()).await?;
+        let data = crate::pkt_line::PktLine::new()
+            .add(b"\x01PACK\0\0\0\x02\0\0\0\x05")
+            .take();
+        let mut stream =
+            GitPacketLineStream::new(futures::stream::iter([Ok::<_, std::io::Error>(
+                Bytes::from(data),
+            )]));
+        repo.handle_packfile_with(&mut stream, PackBackend::Git, false, None, None)
+            .await
+    }
+
+    #[tokio::test]
+    async fn failed_clone_is_removed() {
+        let dest = tempfile::tempdir().unwrap();
+        let path = dest.path().join("repo");
+        let mut guard = CleanupGuard::new(false);
+        assert!(failing_clone(&path, &mut guard).await.is_err());
+        assert!(path.exists());
+        drop(guard);
+        assert!(!path.exists());
+        assert_eq!(std::fs::read_dir(dest.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_clone_is_kept_aside() {
+        let dest = tempfile::tempdir().unwrap();
+        let path = dest.path().join("repo");
+        let mut guard = CleanupGuard::new(true);
+        assert!(failing_clone(&path, &mut guard).await.is_err());
+        drop(guard);
+        assert!(!path.exists());
+        let kept: Vec<String> = std::fs::read_dir(dest.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(kept.len(), 1);
+        assert!(kept[0].starts_with("repo.failed-"), "{:?}", kept);
+    }
+
+    #[tokio::test]
+    async fn disarmed_guard_keeps_clone() {
+        let dest = tempfile::tempdir().unwrap();
+        let path = dest.path().join("repo");
+        let mut guard = CleanupGuard::new(false);
+        guard.add(&path);
+        LocalRepo::init_new(&path, false, None, &GitSettings::default())
+            .await
+            .unwrap();
+        guard.disarm();
+        assert!(path.join(".git").is_dir());
+    }
+
+    /* Progress messages trickling in don't keep a transfer going that's
+     * too slow, index-pack is stopped */
+    #[tokio::test]
+    async fn stalled_transfer_is_aborted() {
+        let trickle = futures::stream::unfold((), |()| async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let pkt = crate::pkt_line::PktLine::new().add(b"\x02.").take();
+            Some((Ok::<_, std::io::Error>(Bytes::from(pkt)), ()))
+        });
+        let mut stream = GitPacketLineStream::new(Box::pin(trickle));
+
+        let dest = tempfile::tempdir().unwrap();
+        let repo = LocalRepo::init_new(
+            &dest.path().join("repo"),
+            false,
+            None,
+            &GitSettings::default(),
+        )
+        .await
+        .unwrap();
+        let limit = StallLimit {
+            window: Duration::from_millis(200),
+            min_bytes: 100,
+        };
+        let res = tokio::time::timeout(
+            Duration::from_secs(10),
+            repo.handle_packfile_with(&mut stream, PackBackend::Git, false, Some(limit), None),
+        )
+        .await
+        .expect("stall not detected");
+        match res {
+            Err(LocalRepoError::Stalled(l)) => assert_eq!(l, limit),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    /* Waiting for the rate limit isn't taken for a stall, even though
+     * the limit is below the stall speed */
+    #[tokio::test]
+    async fn rate_limited_transfer_is_not_stalled() {
+        let src = tempfile::tempdir().unwrap();
+        git(src.path(), &["init", "-q"]);
+        /* Incompressible, so the pack is about as large */
+        let mut x: u32 = 1;
+        let content: Vec<u8> = (0..4000)
+            .map(|_| {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect();
+        std::fs::write(src.path().join("file"), content).unwrap();
+        git(src.path(), &["add", "file"]);
+        git(src.path(), &["commit", "-q", "-m", "file"]);
+        let mut pack_objects = std::process::Command::new("git")
+            .arg("-C")
+            .arg(src.path())
+            .args(["pack-objects", "--revs", "--stdout", "-q"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        writeln!(pack_objects.stdin.take().unwrap(), "HEAD").unwrap();
+        let pack = pack_objects.wait_with_output().unwrap().stdout;
+        assert!(pack.len() > 4000);
+
+        let mut pkt = crate::pkt_line::PktLine::new();
+        for chunk in pack.chunks(500) {
+            pkt = pkt.add(&[&[1u8], chunk].concat());
+        }
+        let data = Bytes::from(pkt.flush().take());
+        let mut stream =
+            GitPacketLineStream::new(futures::stream::iter([Ok::<_, std::io::Error>(data)]));
+
+        let dest = tempfile::tempdir().unwrap();
+        let repo = LocalRepo::init_new(
+            &dest.path().join("repo"),
+            false,
+            None,
+            &GitSettings::default(),
+        )
+        .await
+        .unwrap();
+        let stall_limit = StallLimit {
+            window: Duration::from_millis(200),
+            min_bytes: 1000,
+        };
+        let rate_limit = RateLimit::new(std::num::NonZeroU64::new(2000).unwrap());
+        let start = tokio::time::Instant::now();
+        repo.handle_packfile_with(
+            &mut stream,
+            PackBackend::Git,
+            false,
+            Some(stall_limit),
+            Some(&rate_limit),
+        )
+        .await
+        .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[tokio::test]
+    async fn indexes_pack_with_git() {
+        check_pack_backend(PackBackend::Git, false).await;
+    }
+
+    #[tokio::test]
+    async fn indexes_spooled_pack() {
+        check_pack_backend(PackBackend::Git, true).await;
+    }
+
+    /* index-pack gives up on the header while more data is coming, its
+     * complaint is reported rather than the broken pipe */
+    #[tokio::test]
+    async fn reports_why_index_pack_exited() {
+        let mut pkt = crate::pkt_line::PktLine::new();
+        for _ in 0..1000 {
+            pkt = pkt.add(&[&[1u8], &b"NOTAPACK"[..], &[0u8; 1000]].concat());
+        }
+        let data = Bytes::from(pkt.flush().take());
+        let mut stream =
+            GitPacketLineStream::new(futures::stream::iter([Ok::<_, std::io::Error>(data)]));
+
+        let dest = tempfile::tempdir().unwrap();
+        let repo = LocalRepo::init_new(
+            &dest.path().join("repo"),
+            false,
+            None,
+            &GitSettings::default(),
+        )
+        .await
+        .unwrap();
+        match repo
+            .handle_packfile_with(&mut stream, PackBackend::Git, false, None, None)
+            .await
+        {
+            Err(LocalRepoError::ExternalGitCommandError(_, stderr)) => {
+                assert!(stderr.contains("pack signature"), "{}", stderr)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "gix")]
+    #[tokio::test]
+    async fn indexes_pack_with_gix() {
+        check_pack_backend(PackBackend::Gix, false).await;
+        check_pack_backend(PackBackend::Gix, true).await;
+    }
+
+    #[tokio::test]
+    async fn verifies_connectivity() {
+        let src = tempfile::tempdir().unwrap();
+        git(src.path(), &["init", "-q"]);
+        for content in ["one", "two"] {
+            std::fs::write(src.path().join("file"), content).unwrap();
+            git(src.path(), &["add", "file"]);
+            git(src.path(), &["commit", "-q", "-m", content]);
+        }
+
+        /* Shallow, the first commit is beyond the boundary */
+        let dest = tempfile::tempdir().unwrap();
+        let url = format!("file://{}", src.path().display());
+        git(
+            dest.path(),
+            &["clone", "-q", "--depth", "1", &url, "shallow"],
+        );
+        let repo = LocalRepo::open(&dest.path().join("shallow"), &GitSettings::default())
+            .await
+            .unwrap();
+        let head = git(&dest.path().join("shallow"), &["rev-parse", "HEAD"]);
+        /* commit, tree and blob */
+        assert_eq!(repo.verify_connectivity(&head).await.unwrap(), 3);
+
+        let repo = LocalRepo::open(src.path(), &GitSettings::default())
+            .await
+            .unwrap();
+        let blob = git(src.path(), &["rev-parse", "HEAD:file"]);
+        let objects = src.path().join(".git/objects").join(&blob[..2]);
+        std::fs::remove_file(objects.join(&blob[2..])).unwrap();
+        let err = repo.verify_connectivity("HEAD").await.unwrap_err();
+        assert!(
+            err.to_string().contains(&blob),
+            "Got unexpected error: {}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn borrows_objects_from_reference() {
+        let reference = tempfile::tempdir().unwrap();
+        git(reference.path(), &["init", "-q"]);
+        std::fs::write(reference.path().join("file"), "content").unwrap();
+        git(reference.path(), &["add", "file"]);
+        git(reference.path(), &["commit", "-q", "-m", "one"]);
+        let head = git(reference.path(), &["rev-parse", "HEAD"]);
+
+        let dest = tempfile::tempdir().unwrap();
+        let repo = LocalRepo::init_new(
+            &dest.path().join("repo"),
+            false,
+            None,
+            &GitSettings::default(),
+        )
+        .await
+        .unwrap();
+        assert!(repo.verify_connectivity(&head).await.is_err());
+
+        let objects = LocalRepo::reference_objects_dir(reference.path()).unwrap();
+        repo.add_alternates(std::slice::from_ref(&objects)).unwrap();
+        /* Adding it again doesn't duplicate it */
+        repo.add_alternates(&[objects]).unwrap();
+        let alternates = std::fs::read_to_string(repo.alternates_file()).unwrap();
+        assert_eq!(alternates.lines().count(), 1);
+
+        assert!(repo.ref_shas().await.unwrap().contains(&head));
+        assert_eq!(repo.verify_connectivity(&head).await.unwrap(), 3);
+
+        /* Repacking only keeps what is reachable from refs */
+        repo.update_ref("refs/heads/main", &head).await.unwrap();
+        repo.dissociate().await.unwrap();
+        assert!(!repo.alternates_file().exists());
+        drop(reference);
+        assert_eq!(repo.verify_connectivity(&head).await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn adds_and_updates_worktree() {
+        let src = tempfile::tempdir().unwrap();
+        git(src.path(), &["init", "-q", "-b", "main"]);
+        std::fs::write(src.path().join("file"), "one").unwrap();
+        git(src.path(), &["add", "file"]);
+        git(src.path(), &["commit", "-q", "-m", "one"]);
+        let repo = LocalRepo::open(src.path(), &GitSettings::default())
+            .await
+            .unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        let worktree = dest.path().join("wt");
+        repo.add_worktree(&worktree, "main", false, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(worktree.join("file")).unwrap(),
+            "one"
+        );
+        assert_eq!(git(&worktree, &["symbolic-ref", "HEAD"]), "refs/heads/main");
+
+        let err = repo
+            .add_worktree(&worktree, "main", false, false)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, LocalRepoError::AlreadyExists(_)));
+
+        std::fs::write(src.path().join("file"), "two").unwrap();
+        git(src.path(), &["commit", "-q", "-a", "-m", "two"]);
+        let head = git(src.path(), &["rev-parse", "HEAD"]);
+        repo.add_worktree(&worktree, &head, true, true)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(worktree.join("file")).unwrap(),
+            "two"
+        );
+
+        /* Only directories of worktrees are updated */
+        let other = dest.path().join("other");
+        std::fs::create_dir(&other).unwrap();
+        let err = repo
+            .add_worktree(&other, "main", false, true)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, LocalRepoError::NotARepository(_)));
+
+        /* A removed worktree can be added again */
+        LocalRepo::remove(&worktree).unwrap();
+        repo.add_worktree(&worktree, "main", false, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(worktree.join("file")).unwrap(),
+            "two"
+        );
+    }
+
+    #[tokio::test]
+    async fn walks_commits() {
+        let src = tempfile::tempdir().unwrap();
+        git(src.path(), &["init", "-q", "-b", "main"]);
+        git(src.path(), &["commit", "-q", "--allow-empty", "-m", "base"]);
+        git(src.path(), &["checkout", "-q", "-b", "side"]);
+        git(src.path(), &["commit", "-q", "--allow-empty", "-m", "side"]);
+        let side = git(src.path(), &["rev-parse", "HEAD"]);
+        git(src.path(), &["checkout", "-q", "main"]);
+        git(src.path(), &["commit", "-q", "--allow-empty", "-m", "main"]);
+        git(
+            src.path(),
+            &["merge", "-q", "--no-ff", "-m", "merge", "side"],
+        );
+        let repo = LocalRepo::open(src.path(), &GitSettings::default())
+            .await
+            .unwrap();
+
+        let walk = |opts| {
+            let repo = &repo;
+            async move {
+                let mut seen = Vec::new();
+                let count = repo
+                    .walk_commits(&["HEAD"], opts, |sha| {
+                        seen.push(sha.to_string());
+                        ControlFlow::Continue(())
+                    })
+                    .await
+                    .unwrap();
+                assert_eq!(count, seen.len());
+                seen
+            }
+        };
+        assert_eq!(walk(RevListOptions::default()).await.len(), 4);
+        let first_parent = RevListOptions {
+            first_parent: true,
+            ..Default::default()
+        };
+        let seen = walk(first_parent).await;
+        assert_eq!(seen.len(), 3);
+        assert!(!seen.contains(&side));
+        let limited = RevListOptions {
+            max_count: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(walk(limited).await.len(), 2);
+
+        /* Stops at the first match */
+        let count = repo
+            .walk_commits(&["HEAD"], RevListOptions::default(), |sha| {
+                if sha == side {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            })
+            .await
+            .unwrap();
+        assert!(count < 4);
+    }
+
+    #[tokio::test]
+    async fn incremental_walk_finds_same_tag_as_full_walk() {
+        let src = tempfile::tempdir().unwrap();
+        let commit = |msg: &str| {
+            git(src.path(), &["commit", "-q", "--allow-empty", "-m", msg]);
+        };
+        git(src.path(), &["init", "-q", "-b", "main"]);
+        for i in 0..4 {
+            commit(&format!("main {}", i));
+        }
+        git(src.path(), &["checkout", "-q", "-b", "side"]);
+        commit("side 0");
+        let tagged = git(src.path(), &["rev-parse", "HEAD"]);
+        commit("side 1");
+        git(src.path(), &["checkout", "-q", "main"]);
+        for i in 4..8 {
+            commit(&format!("main {}", i));
+        }
+        git(
+            src.path(),
+            &["merge", "-q", "--no-ff", "-m", "merge", "side"],
+        );
+        for i in 8..12 {
+            commit(&format!("main {}", i));
+        }
+
+        let dest = tempfile::tempdir().unwrap();
+        let url = format!("file://{}", src.path().display());
+        git(
+            dest.path(),
+            &["clone", "-q", "--depth", "2", &url, "shallow"],
+        );
+        let path = dest.path().join("shallow");
+        let repo = LocalRepo::open(&path, &GitSettings::default())
+            .await
+            .unwrap();
+
+        let mut walk_from = vec!["HEAD".to_string()];
+        loop {
+            let shallow = repo.get_shallow_shas().await.unwrap();
+            let starts: Vec<&str> = walk_from.iter().map(String::as_str).collect();
+            let mut boundary = Vec::new();
+            let mut found = false;
+            repo.walk_commits(&starts, RevListOptions::default(), |sha| {
+                if shallow.contains(sha) {
+                    boundary.push(sha.to_string());
+                }
+                if sha == tagged {
+                    found = true;
+                    return ControlFlow::Break(());
                 }
+                ControlFlow::Continue(())
+            })
+            .await
+            .unwrap();
+
+            let naive = repo.rev_list("HEAD").await.unwrap().contains(&tagged);
+            assert_eq!(found, naive);
+            if found {
+                break;
             }
+            assert!(
+                !boundary.is_empty(),
+                "history ended without finding the tag"
+            );
+            walk_from = boundary;
+            git(&path, &["fetch", "-q", "--deepen", "2", "origin"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_shallow_updates_are_kept() {
+        let dest = tempfile::tempdir().unwrap();
+        let repo = LocalRepo::init_new(
+            &dest.path().join("repo"),
+            true,
+            None,
+            &GitSettings::default(),
+        )
+        .await
+        .unwrap();
+
+        let shas: Vec<String> = (0..20).map(|i| format!("{:040x}", i)).collect();
+        let updates = shas.iter().map(|sha| {
+            let info = vec![ShallowInfo::Shallow(sha.clone())];
+            let repo = &repo;
+            async move { repo.update_shallow_file(&info).await }
+        });
+        for result in futures::future::join_all(updates).await {
+            result.unwrap();
         }
+        assert_eq!(
+            repo.get_shallow_shas().await.unwrap(),
+            shas.into_iter().collect::<HashSet<_>>()
+        );
 
-        wait_result(index_pack_cmd, || ()).await
+        /* A lock that isn't released makes others give up eventually */
+        let shallow = repo.git_dir.join("shallow");
+        let lock = repo.lock_shallow_file().await.unwrap();
+        let err = LockFile::acquire(&shallow, Duration::from_millis(100))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        drop(lock);
+        assert!(LockFile::acquire(&shallow, Duration::ZERO).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn sets_config() {
+        let dest = tempfile::tempdir().unwrap();
+        let path = dest.path().join("repo");
+        let repo = LocalRepo::init_new(&path, false, None, &GitSettings::default())
+            .await
+            .unwrap();
+
+        git(&path, &["config", "--add", "remote.origin.fetch", "one"]);
+        git(&path, &["config", "--add", "remote.origin.fetch", "two"]);
+        repo.set_config(
+            "remote.origin.fetch",
+            "+refs/heads/main:refs/remotes/origin/main",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            git(&path, &["config", "--get-all", "remote.origin.fetch"]),
+            "+refs/heads/main:refs/remotes/origin/main"
+        );
     }
 }
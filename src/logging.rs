@@ -0,0 +1,109 @@
+use std::future::Future;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+tokio::task_local! {
+    static REPO: String;
+}
+
+static PROGRESS: AtomicBool = AtomicBool::new(true);
+
+/* How log messages are written to stderr */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /* One JSON object per line, for log aggregation */
+    Json,
+}
+
+/* RUST_LOG still takes precedence over the level from the command line */
+pub fn init(level: log::LevelFilter, format: LogFormat) {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level.as_str()));
+    match format {
+        LogFormat::Text => builder.format(|buf, record| {
+            let style = buf.default_level_style(record.level());
+            write!(
+                buf,
+                "[{style}{:<5}{style:#} {}] ",
+                record.level(),
+                record.target()
+            )?;
+            if let Some(repo) = current_repo() {
+                write!(buf, "{}: ", repo)?;
+            }
+            writeln!(buf, "{}", record.args())
+        }),
+        LogFormat::Json => builder.format(|buf, record| {
+            let timestamp = buf.timestamp_millis().to_string();
+            writeln!(
+                buf,
+                "{}",
+                json_line(&timestamp, record, current_repo().as_deref())
+            )
+        }),
+    };
+    builder.init();
+}
+
+fn json_line(timestamp: &str, record: &log::Record, repo: Option<&str>) -> serde_json::Value {
+    serde_json::json!({
+        "timestamp": timestamp,
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "repo": repo,
+        "message": record.args().to_string(),
+    })
+}
+
+/* Runs `f` with `repo` as the repository context, so that log messages
+ * emitted while cloning it (also from concurrently running clones) can
+ * be told apart. */
+pub async fn with_repo<F: Future>(repo: String, f: F) -> F::Output {
+    REPO.scope(repo, f).await
+}
+
+pub fn current_repo() -> Option<String> {
+    REPO.try_with(|r| r.clone()).ok()
+}
+
+pub fn set_progress(enabled: bool) {
+    PROGRESS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn progress_enabled() -> bool {
+    PROGRESS.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_lines() {
+        let line = json_line(
+            "2024-05-01T12:00:00.000Z",
+            &log::Record::builder()
+                .level(log::Level::Warn)
+                .target("git_sleipnir::local_repo")
+                .args(format_args!("Transfer \"stalled\"\n"))
+                .build(),
+            Some("alpha"),
+        );
+        assert_eq!(
+            line.to_string(),
+            r#"{"level":"WARN","message":"Transfer \"stalled\"\n","repo":"alpha","target":"git_sleipnir::local_repo","timestamp":"2024-05-01T12:00:00.000Z"}"#
+        );
+
+        let line = json_line(
+            "2024-05-01T12:00:00.000Z",
+            &log::Record::builder()
+                .level(log::Level::Info)
+                .args(format_args!("Cloning"))
+                .build(),
+            None,
+        );
+        assert_eq!(line["repo"], serde_json::Value::Null);
+    }
+}
@@ -1,49 +1,249 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::error::Error;
+use std::ffi::OsString;
 use std::io::Write;
+use std::num::NonZeroU64;
+use std::num::NonZeroUsize;
 use std::path::Path;
-
-use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::error::ErrorKind;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use futures::stream::{self, StreamExt};
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use tokio_util::sync::CancellationToken;
 use url::Url;
 
-use log::{debug, info};
-
-mod branch_fallback;
-mod git_http_client;
-mod local_repo;
-mod pkt_line;
-mod reader;
-mod util;
-
-use crate::branch_fallback::BranchFallback;
-use crate::git_http_client::GitClient;
-use crate::local_repo::LocalRepo;
-
-#[derive(Debug)]
-pub enum ShallowInfo {
-    Shallow(String),
-    NotShallow(String),
-}
-
-#[derive(Debug)]
-struct RefInfo {
-    sha: String,
-    refname: String,
-    peeled: Option<String>,
-}
+use log::{debug, error, info, warn};
+
+mod ci_output;
+mod config;
+mod hooks;
+mod lockfile;
+mod repos_file;
+mod tag_output;
+
+use git_sleipnir::branch_fallback::{
+    self, BranchAlias, BranchFallback, FallbackRule, FallbackStrategy, ResolutionStep,
+};
+use git_sleipnir::clone::{
+    clone_repo, fetch_reachable_tags, find_branch_with, list_refs, no_branch_found, select_branch,
+    short_refname, CloneOptions, CloneResult, CloneTarget, MatchedBy, TagLookup,
+};
+use git_sleipnir::git_http_client::{Deepen, GitClient, GitClientError, TlsSettings};
+use git_sleipnir::local_repo::{self, GitSettings, LocalRepo, PackBackend, StallLimit};
+use git_sleipnir::logging::{self, LogFormat};
+use git_sleipnir::packet_trace;
+use git_sleipnir::rate_limit::{self, RateLimit};
+use git_sleipnir::refs_cache::{DiskCache, RefsCache};
+use git_sleipnir::tag_order::{TagAggregate, TagOrder, TagSelector, DEFAULT_PRERELEASE_PATTERN};
+use git_sleipnir::util::{self, masked_url};
+use git_sleipnir::RefInfo;
+
+use crate::ci_output::CiOutputs;
+use crate::repos_file::RepoEntry;
+use crate::tag_output::{TagTemplate, TagVars};
 
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Cli {
+    /// More output (-v for debug, -vv for trace)
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only print warnings and errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// How log messages are written (json: one object per line)
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Write every pkt-line sent and received to this file (- for stderr)
+    #[arg(long, global = true, value_name = "FILE")]
+    trace_packets: Option<PathBuf>,
+
+    /// The git executable to use
+    #[arg(long, global = true, env = "SLEIPNIR_GIT", default_value = "git")]
+    git: PathBuf,
+
+    /// Also mask usernames that come with a password in URLs shown (usernames alone always are)
+    #[arg(long, global = true)]
+    mask_username: bool,
+
+    /// How fetched packs are indexed
+    #[arg(long, global = true, value_enum, default_value_t = PackBackend::Git)]
+    pack_backend: PackBackend,
+
+    /// Receive packs into a temporary file and index them afterwards, instead of while receiving
+    #[arg(long, global = true)]
+    pack_spool: bool,
+
+    /// Abort a fetch that receives less than --stall-speed bytes per second for this many seconds
+    #[arg(long, global = true)]
+    stall_timeout: Option<NonZeroU64>,
+
+    /// Bytes per second below which a transfer counts as stalled
+    #[arg(
+        long,
+        global = true,
+        default_value = "1024",
+        requires = "stall_timeout"
+    )]
+    stall_speed: u64,
+
+    /// Receive pack data at most this fast, in bytes per second (K, M or G suffixes allowed), all repositories together
+    #[arg(long, global = true, value_name = "RATE", value_parser = rate_limit::parse_rate)]
+    limit_rate: Option<NonZeroU64>,
+
+    /// Keep listed refs in this directory for --refs-cache-ttl seconds, for later runs
+    #[arg(long, global = true, value_name = "DIR")]
+    refs_cache: Option<PathBuf>,
+
+    /// How long refs in the --refs-cache directory are used
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECONDS",
+        default_value = "60",
+        requires = "refs_cache"
+    )]
+    refs_cache_ttl: u64,
+
+    /// List refs every time they are needed, ignoring --refs-cache
+    #[arg(long, global = true)]
+    no_cache: bool,
+
+    /// Trust the CA certificates in this PEM file too
+    #[arg(long, global = true, value_name = "FILE")]
+    ca_bundle: Option<PathBuf>,
+
+    /// Present the certificate in this PEM file to https servers
+    #[arg(long, global = true, value_name = "FILE", requires = "client_key")]
+    client_cert: Option<PathBuf>,
+
+    /// The PKCS#8 key of --client-cert, in a PEM file
+    #[arg(long, global = true, value_name = "FILE", requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// Read option defaults from this file instead of ./sleipnir.toml or $XDG_CONFIG_HOME/sleipnir/sleipnir.toml
+    #[arg(long, global = true, value_name = "FILE", env = "SLEIPNIR_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Print the options in effect and where each was set, then exit
+    #[arg(long, global = true)]
+    show_config: bool,
+
     #[command(subcommand)]
     command: Command,
 }
 
 #[derive(Subcommand)]
 enum Command {
-    Clone(CloneArgs),
+    Clone(Box<CloneArgs>),
+    /// Find the branch a clone would check out
+    ///
+    /// With --quiet nothing is printed and the exit status tells: 0 resolved exactly or through
+    /// a fallback rule, 1 only through --default-branch, 2 nothing resolved, 3 any other error.
     FindBranch(FindBranchArgs),
+    LsRefs(LsRefsArgs),
+    MaxTag(MaxTagArgs),
+    Describe(DescribeArgs),
+    Fetch(FetchArgs),
+    Verify(VerifyArgs),
+}
+
+/* Which refs are listed and how the branch is picked among them, for
+ * clone, find-branch, max-tag and describe */
+#[derive(Args)]
+struct BranchArgs {
+    #[arg(long)]
+    branches_starting_with: Option<String>,
+
+    #[arg(long)]
+    tags_starting_with: Option<String>,
+
+    #[arg(long = "branch-fallback", action = clap::ArgAction::Append, value_parser = BranchFallback::parse)]
+    fallbacks: Vec<BranchFallback>,
+
+    #[arg(long = "branch-alias", value_parser = BranchAlias::parse)]
+    aliases: Vec<BranchAlias>,
+
+    /* --branch-fallback and --branch-alias combined, in the order given */
+    #[arg(skip)]
+    rules: Vec<FallbackRule>,
+
+    /// Read more --branch-fallback rules from a file, one per line
+    #[arg(long)]
+    branch_fallback_file: Option<PathBuf>,
+
+    #[arg(long, value_enum, default_value_t = FallbackStrategy::First)]
+    fallback_strategy: FallbackStrategy,
+
+    #[arg(long = "exclude-branch")]
+    excludes: Vec<Regex>,
+
+    #[arg(long)]
+    default_branch: Option<String>,
+}
+
+/* How far tags are searched for and which one is taken, for clone,
+ * max-tag and describe */
+#[derive(Args)]
+struct TagArgs {
+    #[arg(long, default_value = "1")]
+    depth: NonZeroUsize,
+
+    #[arg(long, default_value = "50")]
+    deepen_step: NonZeroUsize,
+
+    #[arg(long, conflicts_with = "deepen_step")]
+    deepen_exponential: bool,
+
+    #[arg(long, default_value_t = 10000)]
+    max_depth: usize,
+
+    /// Only follow the first parent of merges when looking for tags
+    #[arg(long)]
+    first_parent: bool,
+
+    /// Look for tags in at most this many commits
+    #[arg(long)]
+    max_count: Option<NonZeroUsize>,
+
+    /// How tags are compared when picking the highest or lowest one
+    #[arg(long, value_enum, default_value_t = TagOrder::Natural)]
+    tag_order: TagOrder,
+
+    /// How the tags of several repositories are combined
+    #[arg(long, value_enum, default_value_t = TagAggregate::Min)]
+    tag_aggregate: TagAggregate,
+
+    /// Only consider tags starting with this, stripped before comparing
+    #[arg(long)]
+    tag_prefix: Option<String>,
+
+    /// Report the tag including --tag-prefix
+    #[arg(long, requires = "tag_prefix")]
+    keep_tag_prefix: bool,
+
+    /// Only consider tags matching one of these
+    #[arg(long = "tag-include", allow_hyphen_values = true)]
+    tag_includes: Vec<Regex>,
+
+    /// Never consider tags matching any of these
+    #[arg(long = "tag-exclude", allow_hyphen_values = true)]
+    tag_excludes: Vec<Regex>,
+
+    /// Tags matching this are pre-releases, which are skipped by default
+    #[arg(long, default_value = DEFAULT_PRERELEASE_PATTERN)]
+    prerelease_pattern: Regex,
+
+    #[arg(long)]
+    include_prereleases: bool,
 }
 
 #[derive(Args)]
@@ -51,30 +251,183 @@ struct CloneArgs {
     #[arg(long)]
     base_url: Option<Url>,
 
+    /// Clone from the same path under this URL when a repository's server is unavailable (repeatable, tried in order)
+    #[arg(long = "mirror", value_name = "URL")]
+    mirrors: Vec<Url>,
+
+    #[arg(long, required_unless_present_any = ["tags_only", "from_lockfile", "verify_lockfile"])]
+    branch: Option<String>,
+
+    #[command(flatten)]
+    branches: BranchArgs,
+
     #[arg(long)]
-    branches_starting_with: Option<String>,
+    allow_tag_as_branch: bool,
 
     #[arg(long)]
-    tags_starting_with: Option<String>,
+    all_matching_branches: bool,
 
+    /// Use the same branch in all repositories
     #[arg(long)]
-    branch: String,
+    consistent_branch: bool,
 
-    #[arg(long = "branch-fallback", action = clap::ArgAction::Append, value_parser = BranchFallback::parse)]
-    fallbacks: Vec<BranchFallback>,
+    #[command(flatten)]
+    tags: TagArgs,
+
+    /// Fail if no tag is reachable from the branch (the default)
+    #[arg(long, overrides_with = "allow_no_tag")]
+    require_tag: bool,
+
+    /// Succeed without a tag if none is reachable from the branch
+    #[arg(long, overrides_with = "require_tag")]
+    allow_no_tag: bool,
+
+    #[arg(long, conflicts_with_all = ["tag_output_file", "tag_output_dir", "tags_output_file", "tags_starting_with"])]
+    no_tags: bool,
+
+    #[arg(long, conflicts_with_all = ["no_tags", "dry_run"])]
+    tags_only: bool,
+
+    #[arg(long, short = 'j', default_value = "1")]
+    jobs: NonZeroUsize,
 
     #[arg(long)]
-    default_branch: Option<String>,
+    keep_going: bool,
+
+    #[arg(long, requires = "keep_going")]
+    tag_from_successful: bool,
+
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Run this shell command in each cloned repository, with SLEIPNIR_HOOK_* variables describing it
+    #[arg(long, value_name = "CMD")]
+    post_clone_hook: Option<String>,
+
+    /// Run this shell command once all repositories are cloned, with the tag in SLEIPNIR_HOOK_TAG
+    #[arg(long, value_name = "CMD")]
+    post_run_hook: Option<String>,
+
+    #[arg(long)]
+    update: bool,
+
+    #[arg(long, conflicts_with = "update")]
+    force: bool,
+
+    /// Rename the directory of a failed clone to <name>.failed-<timestamp> instead of removing it
+    #[arg(long)]
+    keep_failed: bool,
+
+    #[arg(long)]
+    bare: bool,
+
+    #[arg(long)]
+    no_checkout: bool,
+
+    /// Don't configure the cloned repository as remote "origin"
+    #[arg(long)]
+    no_set_remote: bool,
+
+    /// Check out into a linked worktree at this path instead ({name} is the repository name)
+    #[arg(long, conflicts_with = "no_checkout")]
+    worktree: Option<String>,
+
+    /// Check that all objects of the fetched history are present
+    #[arg(long)]
+    verify: bool,
+
+    /// Borrow objects from a local repository, can be given multiple times
+    #[arg(long = "reference")]
+    references: Vec<PathBuf>,
+
+    /// Borrow objects from the repository with the same name in this directory
+    #[arg(long)]
+    reference_base: Option<PathBuf>,
+
+    /// Copy the borrowed objects, so the clone doesn't depend on the references
+    #[arg(long)]
+    dissociate: bool,
+
+    /// Initial branch name of new repositories (default: the checked out branch)
+    #[arg(long)]
+    initial_branch: Option<String>,
+
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    #[arg(long, requires = "output_dir")]
+    create_dirs: bool,
 
     #[arg(long)]
     tag_output_file: Option<String>,
 
+    /// End the tag output files without a newline
+    #[arg(long)]
+    no_trailing_newline: bool,
+
+    /// Write each repository's tag to <NAME>.tag in this directory, formatted like --tag-output-file
+    #[arg(long, value_name = "DIR")]
+    tag_output_dir: Option<PathBuf>,
+
+    /// Write no file to --tag-output-dir for a repository without a tag, instead of an empty one
+    #[arg(long, requires = "tag_output_dir")]
+    skip_untagged: bool,
+
+    /// Write "<name> <refname> <sha>" for every repository to this file, "<name> FAILED -" for failed ones
+    #[arg(long, value_name = "PATH")]
+    ref_output_file: Option<PathBuf>,
+
+    /// Write the checked out commit described like `git describe --tags` to this file, "<name> <description>" per repository when there are several
+    #[arg(long, value_name = "PATH")]
+    describe_output_file: Option<PathBuf>,
+
+    #[arg(long, value_parser = TagTemplate::parse, default_value = "{tag}")]
+    tag_output_format: TagTemplate,
+
+    #[arg(long, requires = "tag_output_file")]
+    tag_output_per_repo: bool,
+
+    /// Write every reachable tag of every repository to this file
+    #[arg(long)]
+    tags_output_file: Option<PathBuf>,
+
+    /// Report the highest tag reachable in all repositories
+    #[arg(long)]
+    common_tag: bool,
+
+    #[arg(long)]
+    json_output: Option<String>,
+
+    /// Append the tags and branches as step outputs to $GITHUB_OUTPUT (GitHub Actions)
+    #[arg(long)]
+    github_output: bool,
+
+    /// Write the tags and branches as KEY=value lines to this file
+    #[arg(long)]
+    env_output: Option<PathBuf>,
+
     #[arg(long)]
     manifest_output_file: Option<String>,
     #[arg(long, value_enum, default_value_t = ManifestFormat::Pretty)]
     manifest_format: ManifestFormat,
 
-    #[arg(required = true)]
+    /// Record the ref, commit and tag of each repository in this file
+    #[arg(long, value_name = "PATH")]
+    write_lockfile: Option<PathBuf>,
+
+    /// Clone the commits recorded in this lockfile instead of resolving branches
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["consistent_branch", "all_matching_branches", "dry_run", "tags_only"])]
+    from_lockfile: Option<PathBuf>,
+
+    /// Only check whether the refs and tags in this lockfile moved on the remotes
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["from_lockfile", "write_lockfile", "consistent_branch", "dry_run", "tags_only"])]
+    verify_lockfile: Option<PathBuf>,
+
+    #[arg(long)]
+    repos_file: Option<PathBuf>,
+
+    /// Repositories to clone as [NAME=]URL[|MIRROR...], taken from the lockfile if there is one and none are given
+    #[arg(required_unless_present_any = ["repos_file", "from_lockfile", "verify_lockfile"])]
     urls: Vec<String>,
 }
 
@@ -87,16 +440,207 @@ pub enum ManifestFormat {
 #[derive(Args)]
 struct FindBranchArgs {
     #[arg(long)]
-    branches_starting_with: Option<String>,
+    base_url: Option<Url>,
+
+    /// Also take tags named like the branch or a fallback candidate, when there is no such branch
+    #[arg(long)]
+    include_tags: bool,
 
     #[arg(long)]
     branch: String,
 
-    #[arg(long = "branch-fallback", action = clap::ArgAction::Append, value_parser = BranchFallback::parse)]
-    fallbacks: Vec<BranchFallback>,
+    #[command(flatten)]
+    branches: BranchArgs,
 
+    /// Print the branch with its refname, sha and what matched (exact, fallback:<rule>, default-branch) as JSON
     #[arg(long)]
-    default_branch: Option<String>,
+    json: bool,
+
+    /// Show every candidate branch that was tried
+    #[arg(long)]
+    explain: bool,
+
+    /// Fail if not all repositories resolve to the same branch
+    #[arg(long)]
+    require_consistent: bool,
+
+    /// Resolve against the branches of a repository on disk instead of a remote
+    #[arg(long, conflicts_with_all = ["repo_urls", "base_url", "include_tags", "tags_starting_with"])]
+    local: Option<PathBuf>,
+
+    #[arg(required_unless_present = "local")]
+    repo_urls: Vec<String>,
+}
+
+#[derive(Args)]
+struct LsRefsArgs {
+    #[arg(long)]
+    base_url: Option<Url>,
+
+    #[arg(long = "prefix")]
+    prefixes: Vec<String>,
+
+    #[arg(long)]
+    json: bool,
+
+    #[arg(required = true)]
+    repo_url: String,
+}
+
+/* The repositories max-tag and describe clone bare into a temporary
+ * directory, and how */
+#[derive(Args)]
+struct TemporaryCloneArgs {
+    #[arg(long)]
+    base_url: Option<Url>,
+
+    #[arg(long)]
+    branch: String,
+
+    #[command(flatten)]
+    branches: BranchArgs,
+
+    #[command(flatten)]
+    tags: TagArgs,
+
+    /// Keep the temporary clones instead of removing them
+    #[arg(long)]
+    keep: bool,
+
+    #[arg(required = true)]
+    urls: Vec<String>,
+}
+
+#[derive(Args)]
+struct MaxTagArgs {
+    #[command(flatten)]
+    clones: TemporaryCloneArgs,
+
+    /// Print the sha of the tagged commit too
+    #[arg(long)]
+    sha: bool,
+}
+
+#[derive(Args)]
+struct DescribeArgs {
+    #[command(flatten)]
+    clones: TemporaryCloneArgs,
+}
+
+impl BranchArgs {
+    /* Options resolving `branch` this way and searching for tags like
+     * `tags` says, the rest as by default */
+    fn clone_options(
+        &self,
+        branch: Option<String>,
+        tags: &TagArgs,
+        settings: &Settings,
+    ) -> CloneOptions {
+        CloneOptions {
+            branch,
+            rules: self.rules.clone(),
+            fallback_strategy: self.fallback_strategy,
+            excludes: self.excludes.clone(),
+            default_branch: self.default_branch.clone(),
+            branches_starting_with: self.branches_starting_with.clone(),
+            tags_starting_with: self.tags_starting_with.clone(),
+            depth: tags.depth,
+            deepen_step: tags.deepen_step,
+            deepen_exponential: tags.deepen_exponential,
+            max_depth: tags.max_depth,
+            first_parent: tags.first_parent,
+            max_count: tags.max_count,
+            tag_order: tags.tag_order,
+            tag_prefix: tags.tag_prefix.clone(),
+            keep_tag_prefix: tags.keep_tag_prefix,
+            tag_includes: tags.tag_includes.clone(),
+            tag_excludes: tags.tag_excludes.clone(),
+            prerelease_pattern: tags.prerelease_pattern.clone(),
+            include_prereleases: tags.include_prereleases,
+            git: settings.git.clone(),
+            refs_cache: settings.refs_cache.clone(),
+            no_cache: settings.no_cache,
+            tls: settings.tls.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+impl CloneArgs {
+    fn clone_options(&self, settings: &Settings) -> CloneOptions {
+        CloneOptions {
+            allow_tag_as_branch: self.allow_tag_as_branch,
+            all_matching_branches: self.all_matching_branches,
+            allow_no_tag: self.allow_no_tag,
+            no_tags: self.no_tags,
+            tags_only: self.tags_only,
+            update: self.update,
+            force: self.force,
+            keep_failed: self.keep_failed,
+            bare: self.bare,
+            no_checkout: self.no_checkout,
+            no_set_remote: self.no_set_remote,
+            verify: self.verify,
+            references: self.references.clone(),
+            reference_base: self.reference_base.clone(),
+            dissociate: self.dissociate,
+            initial_branch: self.initial_branch.clone(),
+            ..self
+                .branches
+                .clone_options(self.branch.clone(), &self.tags, settings)
+        }
+    }
+}
+
+impl TemporaryCloneArgs {
+    /* A bare clone without any of the outputs */
+    fn clone_options(&self, settings: &Settings) -> CloneOptions {
+        CloneOptions {
+            bare: true,
+            no_checkout: true,
+            no_set_remote: true,
+            ..self
+                .branches
+                .clone_options(Some(self.branch.clone()), &self.tags, settings)
+        }
+    }
+}
+
+#[derive(Args)]
+struct FetchArgs {
+    #[arg(long)]
+    base_url: Option<Url>,
+
+    /// Fetch the current tip of this branch
+    #[arg(long, required_unless_present = "deepen")]
+    branch: Option<String>,
+
+    /// Fetch this many more commits of history
+    #[arg(long)]
+    deepen: Option<NonZeroUsize>,
+
+    /// Point HEAD at the fetched branch and check it out
+    #[arg(long, requires = "branch")]
+    update_head: bool,
+
+    #[arg(required = true)]
+    path: PathBuf,
+
+    #[arg(required = true)]
+    repo_url: String,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    #[arg(long)]
+    base_url: Option<Url>,
+
+    /// Print the details as JSON
+    #[arg(long)]
+    json: bool,
+
+    #[arg(required = true)]
+    path: PathBuf,
 
     #[arg(required = true)]
     repo_url: String,
@@ -112,153 +656,514 @@ fn resolve_urls(base: Option<&Url>, urls: &[String]) -> Result<Vec<Url>, String>
         .collect()
 }
 
-fn masked_url(orig: &Url) -> String {
-    let mut url = orig.clone();
+/* Where --mirror has the repository at `url`: at the same path, below
+ * the mirror's */
+fn mirror_url(mirror: &Url, url: &Url) -> Result<Url, String> {
+    let mut mirror = mirror.clone();
+    if !mirror.path().ends_with('/') {
+        let path = format!("{}/", mirror.path());
+        mirror.set_path(&path);
+    }
+    mirror
+        .join(url.path().trim_start_matches('/'))
+        .map_err(|e| format!("Mirror of {}: {}", masked_url(url), e))
+}
 
-    if url.password().is_some() {
-        let _ = url.set_password(Some("XXXXXXXX"));
+/* Repositories can be given as "name=url" to override the directory
+ * name. Only a plain name is accepted before the '=', so that URLs
+ * containing '=' in e.g. the query string still work. */
+fn split_dest(entry: &str) -> (Option<&str>, &str) {
+    match entry.split_once('=') {
+        Some((name, url)) if !name.is_empty() && !name.contains(['/', ':', '?', '#']) => {
+            (Some(name), url)
+        }
+        _ => (None, entry),
     }
+}
 
-    url.to_string()
+/* For output only, so any URL will do */
+fn repo_label(url: &Url) -> String {
+    default_repo_name(url).unwrap_or_else(|_| masked_url(url))
 }
 
-struct CloneResult {
-    sha: String,
-    branch: String,
-    tag: String,
-    local_repo: LocalRepo,
+/* A repository name that can't be used as a directory name */
+#[derive(Debug)]
+struct InvalidRepoName {
+    url: String,
+    name: String,
+}
+
+impl std::fmt::Display for InvalidRepoName {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "Can't derive a repository name from '{}'", self.url)
+        } else {
+            write!(
+                f,
+                "'{}' from '{}' is not usable as a repository name",
+                self.name, self.url
+            )
+        }
+    }
 }
 
-async fn clone_one(url: &Url, opts: &CloneArgs) -> Result<CloneResult, Box<dyn Error>> {
-    let client = GitClient::new();
+impl Error for InvalidRepoName {}
 
-    let remote_repo = client.for_url(url);
+fn check_repo_name(url: &Url, name: String) -> Result<String, InvalidRepoName> {
+    if name.is_empty() || name == "." || name == ".." {
+        Err(InvalidRepoName {
+            url: masked_url(url),
+            name,
+        })
+    } else {
+        Ok(name)
+    }
+}
 
-    let mut local_repo_path = url
-        .path_segments()
-        .and_then(|mut s| s.next_back())
-        .expect("Not a proper path");
-    if let Some(stripped) = local_repo_path.strip_suffix(".git") {
-        local_repo_path = stripped;
+/* The last path component, percent-decoded and without ".git" (the
+ * query and fragment aren't part of the path). A trailing slash is
+ * ignored, and a component that would escape its directory or contains
+ * a path separator is rejected. Characters the filesystem doesn't take
+ * are replaced. */
+fn default_repo_name(url: &Url) -> Result<String, InvalidRepoName> {
+    let path = url.path();
+    let last = path
+        .strip_suffix('/')
+        .unwrap_or(path)
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+    let decoded = percent_decode_str(last).decode_utf8_lossy();
+    let name = decoded.strip_suffix(".git").unwrap_or(&decoded);
+    if name.contains(['/', '\\']) {
+        return Err(InvalidRepoName {
+            url: masked_url(url),
+            name: name.to_string(),
+        });
     }
-    info!("Creating local repo {}", local_repo_path);
+    let name = check_repo_name(url, name.to_string())?;
+    check_repo_name(url, util::portable_file_name(&name, cfg!(windows)))
+}
+
+/* The repositories given as [NAME=]URL[|MIRROR...] */
+fn repo_entries(urls: &[String]) -> Vec<RepoEntry> {
+    urls.iter()
+        .map(|entry| split_dest(entry))
+        .map(|(name, urls)| {
+            let mut urls = urls.split('|').map(str::to_string);
+            RepoEntry {
+                url: urls.next().unwrap_or_default(),
+                name: name.map(str::to_string),
+                mirrors: urls.collect(),
+                ..Default::default()
+            }
+        })
+        .collect()
+}
 
-    let local_repo = LocalRepo::init_new(Path::new(local_repo_path)).await?;
+fn clone_targets(
+    entries: Vec<RepoEntry>,
+    base_url: Option<&Url>,
+    mirrors: &[Url],
+    output_dir: Option<&Path>,
+    worktree: Option<&String>,
+    bare: bool,
+) -> Result<Vec<CloneTarget>, String> {
+    let urls: Vec<String> = entries.iter().map(|e| e.url.clone()).collect();
+    let resolved = resolve_urls(base_url, &urls)?;
+
+    /* All names are checked before anything is cloned */
+    let targets: Vec<CloneTarget> = resolved
+        .into_iter()
+        .zip(entries)
+        .map(|(url, entry)| {
+            let name = match entry.name {
+                Some(name) => check_repo_name(&url, name),
+                None => default_repo_name(&url).map(|name| {
+                    let segment = url.path().trim_end_matches('/').rsplit('/').next();
+                    if segment.map(|s| s.trim_end_matches(".git")) != Some(&name) {
+                        info!("Cloning {} as '{}'", masked_url(&url), name);
+                    }
+                    if bare {
+                        format!("{}.git", name)
+                    } else {
+                        name
+                    }
+                }),
+            }
+            .map_err(|e| e.to_string())?;
+            let dest = match output_dir {
+                Some(dir) => dir.join(&name),
+                None => PathBuf::from(&name),
+            };
+            let mut entry_mirrors = resolve_urls(base_url, &entry.mirrors)?;
+            for mirror in mirrors {
+                entry_mirrors.push(mirror_url(mirror, &url)?);
+            }
+            let worktree = entry.worktree.as_ref().or(worktree).map(|w| {
+                PathBuf::from(w.replace("{name}", name.strip_suffix(".git").unwrap_or(&name)))
+            });
+            Ok(CloneTarget {
+                name,
+                url,
+                mirrors: entry_mirrors,
+                dest,
+                worktree,
+                branch: entry.branch,
+                branches_starting_with: entry.branches_starting_with,
+                tags_starting_with: entry.tags_starting_with,
+                pinned: None,
+                refs: None,
+            })
+        })
+        .collect::<Result<_, String>>()?;
 
-    let mut wanted_refs = Vec::new();
-    match &opts.branches_starting_with {
-        Some(branches_starting_with) => {
-            wanted_refs.push(format!("refs/heads/{}", branches_starting_with))
+    let mut by_dest = HashMap::<&Path, Vec<&Url>>::new();
+    for t in &targets {
+        by_dest.entry(&t.dest).or_default().push(&t.url);
+        if let Some(worktree) = &t.worktree {
+            by_dest.entry(worktree).or_default().push(&t.url);
         }
-        None => wanted_refs.push("refs/heads/".to_string()),
     }
-    match &opts.tags_starting_with {
-        Some(tags_starting_with) => wanted_refs.push(format!("refs/tags/{}", tags_starting_with)),
-        None => wanted_refs.push("refs/tags/".to_string()),
+    let mut collisions: Vec<String> = by_dest
+        .iter()
+        .filter(|(_, urls)| urls.len() > 1)
+        .map(|(dest, urls)| {
+            let urls: Vec<String> = urls.iter().map(|u| masked_url(u)).collect();
+            format!("'{}' <- {}", dest.display(), urls.join(", "))
+        })
+        .collect();
+    if !collisions.is_empty() {
+        collisions.sort();
+        return Err(format!(
+            "Multiple repositories would be cloned into the same directory (use name=url to disambiguate): {}",
+            collisions.join("; ")
+        ));
     }
 
-    debug!("Listing remote refs (wanted refs: {:?})", wanted_refs);
-    let refs = remote_repo.ls_refs(&wanted_refs).await?;
+    Ok(targets)
+}
 
-    let mut tagged_commits = HashSet::new();
-    let mut available_branches = HashMap::<&str, &RefInfo>::new();
-    for r in &refs {
-        if let Some(sha) = &r.peeled {
-            tagged_commits.insert(sha);
-        }
+/* find-branch --quiet and verify tell by their exit status how things
+ * are, any failure has a status of its own instead of the usual ones */
+fn failure_status(args: &[OsString]) -> Option<i32> {
+    let matches = Cli::command()
+        .ignore_errors(true)
+        .try_get_matches_from(args)
+        .ok()?;
+    match matches.subcommand_name()? {
+        "find-branch" if matches.get_flag("quiet") => Some(EXIT_FIND_BRANCH_FAILED),
+        "verify" => Some(EXIT_VERIFY_FAILED),
+        _ => None,
+    }
+}
 
-        if let Some(branchname) = r.refname.strip_prefix("refs/heads/") {
-            available_branches.insert(branchname, r);
+fn exit_on_error(e: clap::Error, failure: Option<i32>) -> ! {
+    match failure {
+        /* Not for --help and --version */
+        Some(status) if e.use_stderr() => {
+            let _ = e.print();
+            std::process::exit(status)
         }
+        _ => e.exit(),
     }
+}
 
-    let mut branch: Option<&RefInfo> =
-        branch_fallback::resolve(&opts.branch, &opts.fallbacks, &available_branches);
-    debug!("Found branch: {:?}", branch);
-    if branch.is_none() && opts.default_branch.is_some() {
-        branch = available_branches
-            .get(opts.default_branch.as_ref().unwrap().as_str())
-            .map(|v| &**v);
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let merged = config::merge(&Cli::command(), args.clone()).unwrap_or_else(|e| {
+        let e = Cli::command().error(ErrorKind::InvalidValue, e);
+        exit_on_error(e, failure_status(&args))
+    });
+    let failure = failure_status(&merged.args);
+    let matches = Cli::command()
+        .try_get_matches_from(&merged.args)
+        .unwrap_or_else(|e| exit_on_error(e, failure));
+    if matches
+        .subcommand()
+        .is_some_and(|(_, sub)| sub.get_flag("show_config"))
+    {
+        print!("{}", merged.show(&Cli::command(), &matches));
+        return Ok(());
     }
-    if branch.is_none() {
-        panic!("No suitable branch found");
+    let mut opts = Cli::from_arg_matches(&matches).unwrap_or_else(|e| exit_on_error(e, failure));
+    if let Some((_, sub)) = matches.subcommand() {
+        let branches = match &mut opts.command {
+            Command::Clone(args) => Some(&mut args.branches),
+            Command::FindBranch(args) => Some(&mut args.branches),
+            Command::MaxTag(args) => Some(&mut args.clones.branches),
+            Command::Describe(args) => Some(&mut args.clones.branches),
+            Command::LsRefs(_) | Command::Fetch(_) | Command::Verify(_) => None,
+        };
+        if let Some(branches) = branches {
+            branches.rules = fallback_rules(sub, &branches.fallbacks, &branches.aliases);
+        }
     }
 
-    let branch = branch.unwrap();
-    debug!("Using branch: {} (sha: {})", branch.refname, branch.sha);
-
-    info!("Getting: {}", branch.refname);
-
-    let mut depth = 1;
-    let mut commits;
-    loop {
-        remote_repo
-            .shallow_fetch(&local_repo, &branch.sha, depth)
-            .await?;
-
-        local_repo.update_ref(&branch.refname, &branch.sha).await?;
-        local_repo.update_head(&branch.refname).await?;
+    let result = run(opts).await;
+    if let (Err(e), Some(status)) = (&result, failure) {
+        error!("{}", e);
+        std::process::exit(status);
+    }
+    result
+}
 
-        commits = local_repo.rev_list(&branch.sha).await?;
-        if commits.iter().any(|sha| tagged_commits.contains(sha)) {
-            break;
-        }
+async fn run(opts: Cli) -> Result<(), Box<dyn Error>> {
+    let level = match (opts.quiet, opts.verbose) {
+        (true, _) => log::LevelFilter::Warn,
+        (false, 0) => log::LevelFilter::Info,
+        (false, 1) => log::LevelFilter::Debug,
+        (false, _) => log::LevelFilter::Trace,
+    };
+    logging::init(level, opts.log_format);
+    /* The server's progress lines would break the one object per line */
+    if opts.quiet || opts.log_format == LogFormat::Json {
+        logging::set_progress(false);
+    }
 
-        depth += 50;
-        info!("Could not find tag in shallow clone. Deepening... (depth={depth})");
+    util::set_mask_username(opts.mask_username);
+    if let Some(path) = &opts.trace_packets {
+        packet_trace::set_output(path)
+            .map_err(|e| format!("Can't write to {}: {}", path.display(), e))?;
+    }
+    let settings = Settings {
+        git: GitSettings {
+            git: opts.git.clone(),
+            pack_backend: opts.pack_backend,
+            pack_spool: opts.pack_spool,
+            stall_limit: opts.stall_timeout.map(|timeout| StallLimit {
+                window: std::time::Duration::from_secs(timeout.get()),
+                min_bytes: opts.stall_speed.saturating_mul(timeout.get()),
+            }),
+            rate_limit: opts.limit_rate.map(|rate| Arc::new(RateLimit::new(rate))),
+        },
+        refs_cache: opts.refs_cache.as_ref().map(|dir| DiskCache {
+            dir: dir.clone(),
+            ttl: std::time::Duration::from_secs(opts.refs_cache_ttl),
+        }),
+        /* verify is about what the remote says right now */
+        no_cache: opts.no_cache || matches!(opts.command, Command::Verify(_)),
+        tls: TlsSettings {
+            ca_bundle: opts.ca_bundle.clone(),
+            client_cert: opts.client_cert.clone(),
+            client_key: opts.client_key.clone(),
+        },
+    };
+    let needs_git = match &opts.command {
+        Command::Clone(args) => !args.dry_run && !args.tags_only,
+        Command::MaxTag(_) | Command::Describe(_) | Command::Fetch(_) | Command::Verify(_) => true,
+        Command::FindBranch(_) | Command::LsRefs(_) => false,
+    };
+    if needs_git {
+        let version = local_repo::check_git_version(&opts.git)?;
+        debug!("Using {} ({})", version, opts.git.display());
     }
 
-    let interesting_commits: HashSet<&str> = commits.iter().map(|s| s.as_str()).collect();
-    let mut reachable_tags = Vec::new();
-    for r in &refs {
-        if let (Some(sha), Some(tagname)) = (&r.peeled, r.refname.strip_prefix("refs/tags/")) {
-            if interesting_commits.contains(sha.as_str()) {
-                reachable_tags.push(tagname);
-                local_repo.update_ref(&r.refname, &r.sha).await?;
+    match opts.command {
+        Command::Clone(args) => main_clone(*args, &settings).await,
+        Command::FindBranch(args) => {
+            let status = main_findbranch(args, opts.quiet, &settings).await?;
+            if opts.quiet {
+                std::process::exit(status as i32);
             }
+            Ok(())
         }
+        Command::LsRefs(args) => main_lsrefs(args, &settings).await,
+        Command::MaxTag(args) => main_maxtag(args, &settings).await,
+        Command::Describe(args) => main_describe(args, &settings).await,
+        Command::Fetch(args) => main_fetch(args, &settings).await,
+        Command::Verify(args) => std::process::exit(main_verify(args, &settings).await? as i32),
     }
-
-    local_repo.checkout_head().await?;
-
-    let maxtag = reachable_tags
-        .iter()
-        .max_by(|a, b| natord::compare(a, b))
-        .map(|t| t.to_string())
-        .unwrap();
-
-    Ok(CloneResult {
-        sha: branch.sha.clone(),
-        branch: branch
-            .refname
-            .strip_prefix("refs/heads/")
-            .unwrap()
-            .to_string(),
-        tag: maxtag,
-        local_repo,
-    })
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
-        .format_timestamp(None)
-        .init();
-
-    let opts = Cli::parse();
+/* The global options the subcommands hand on to the library */
+struct Settings {
+    git: GitSettings,
+    refs_cache: Option<DiskCache>,
+    no_cache: bool,
+    tls: TlsSettings,
+}
 
-    match opts.command {
-        Command::Clone(args) => main_clone(args).await,
-        Command::FindBranch(args) => main_findbranch(args).await,
+impl Settings {
+    fn git_client(&self) -> Result<GitClient, GitClientError> {
+        GitClient::with_settings(
+            RefsCache::new(!self.no_cache, self.refs_cache.clone()),
+            &self.tls,
+        )
     }
 }
 
+/* Listing refs is cheap for the servers, more of that runs at once than
+ * --jobs allows clones */
+const LIST_CONCURRENCY: usize = 16;
+
+/* How find-branch resolved, the exit status with --quiet */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FindBranchStatus {
+    /* Exactly or through a fallback rule, in all repositories */
+    Resolved = 0,
+    /* Only through --default-branch, in some repository */
+    DefaultBranch = 1,
+    Unresolved = 2,
+}
+
+/* Listing the refs failed, or anything else did */
+const EXIT_FIND_BRANCH_FAILED: i32 = 3;
+
+/* How a clone compares to its remote, the exit status of verify. The
+ * worst one found is reported. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum VerifyStatus {
+    UpToDate = 0,
+    Behind = 1,
+    BranchGone = 2,
+    /* The branch was rewritten, or a tag moved or is gone */
+    Diverged = 3,
+}
+
+impl VerifyStatus {
+    fn name(self) -> &'static str {
+        match self {
+            VerifyStatus::UpToDate => "up-to-date",
+            VerifyStatus::Behind => "behind",
+            VerifyStatus::BranchGone => "branch-gone",
+            VerifyStatus::Diverged => "diverged",
+        }
+    }
+}
+
+/* Opening the clone or listing the refs failed, or anything else did */
+const EXIT_VERIFY_FAILED: i32 = 4;
+
+/* The conventional exit status after SIGINT */
+const EXIT_INTERRUPTED: i32 = 130;
+
+/* Once a Ctrl-C handler was installed the default behaviour is gone for
+ * good, this restores it (minus the cleanup of running clones) */
+fn exit_on_ctrl_c() {
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+    });
+}
+
+/* Merges --branch-fallback and --branch-alias in command line order */
+fn fallback_rules(
+    matches: &ArgMatches,
+    fallbacks: &[BranchFallback],
+    aliases: &[BranchAlias],
+) -> Vec<FallbackRule> {
+    let regexes = matches
+        .indices_of("fallbacks")
+        .into_iter()
+        .flatten()
+        .zip(fallbacks.iter().cloned().map(FallbackRule::Regex));
+    let aliases = matches
+        .indices_of("aliases")
+        .into_iter()
+        .flatten()
+        .zip(aliases.iter().cloned().map(FallbackRule::Alias));
+    let mut rules: Vec<(usize, FallbackRule)> = regexes.chain(aliases).collect();
+    rules.sort_by_key(|(idx, _)| *idx);
+    rules.into_iter().map(|(_, rule)| rule).collect()
+}
+
+/* Writes `contents` to the file `path`, or to stdout if `path` is "-" */
+fn write_output(path: &str, contents: &str) -> std::io::Result<()> {
+    if path == "-" {
+        println!("{}", contents);
+        Ok(())
+    } else {
+        std::fs::write(path, format!("{}\n", contents))
+    }
+}
+
+/* Makes `contents` a text file ending in a newline, or strips the
+ * newlines at the end */
+fn terminate_line(contents: &mut String, newline: bool) {
+    if !newline {
+        contents.truncate(contents.trim_end_matches('\n').len());
+    } else if !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+}
+
+fn tag_vars<'a>(res: &'a CloneResult, selector: &TagSelector) -> TagVars<'a> {
+    TagVars {
+        tag: res
+            .tag
+            .as_deref()
+            .map(|t| selector.output_name(t))
+            .unwrap_or(""),
+        sha: &res.sha,
+        repo: &res.name,
+        branch: &res.branch,
+    }
+}
+
+/* Runs in the working tree, or the repository itself when bare */
+async fn run_post_clone_hook(
+    cmd: &str,
+    res: &CloneResult,
+    options: &CloneOptions,
+) -> Result<(), hooks::HookError> {
+    let selector = options.tag_selector();
+    let path = res.worktree.as_ref().unwrap_or(&res.path);
+    let path = std::path::absolute(path).map_err(|e| hooks::HookError::Spawn("post-clone", e))?;
+    hooks::run(
+        "post-clone",
+        cmd,
+        &path,
+        &[
+            ("SLEIPNIR_HOOK_REPO_NAME", &res.name),
+            ("SLEIPNIR_HOOK_REPO_PATH", &path.to_string_lossy()),
+            ("SLEIPNIR_HOOK_REPO_URL", &res.url),
+            ("SLEIPNIR_HOOK_BRANCH", &res.branch),
+            ("SLEIPNIR_HOOK_SHA", &res.sha),
+            (
+                "SLEIPNIR_HOOK_TAG",
+                res.tag
+                    .as_deref()
+                    .map_or("", |tag| selector.output_name(tag)),
+            ),
+        ],
+    )
+    .await
+}
+
+/* The aggregate tag, each repository's tag and branch, and the
+ * repositories that failed with --keep-going */
+fn ci_outputs(
+    results: &[CloneResult],
+    failed: &[&str],
+    aggregate_tag: Option<&String>,
+    selector: &TagSelector,
+) -> CiOutputs {
+    let mut outputs = CiOutputs::default();
+    outputs.set(
+        "tag",
+        aggregate_tag.map_or("", |tag| selector.output_name(tag)),
+    );
+    for r in results {
+        outputs.set(
+            &format!("tag_{}", r.name),
+            r.tag.as_deref().map_or("", |tag| selector.output_name(tag)),
+        );
+        outputs.set(&format!("branch_{}", r.name), &r.branch);
+    }
+    outputs.set("failed_repos", &failed.join(","));
+    outputs
+}
+
 async fn write_manifest(
     results: &Vec<CloneResult>,
-    path: String,
+    selector: &TagSelector<'_>,
+    path: &str,
     format: ManifestFormat,
 ) -> Result<(), Box<dyn Error>> {
-    let mut file = std::fs::File::create(&path)?;
+    let mut file = std::fs::File::create(path)?;
 
     let max_repo_branch_len = results
         .iter()
@@ -267,17 +1172,10 @@ async fn write_manifest(
         .unwrap_or(0);
 
     for r in results {
-        let dist = r.local_repo.distance(&r.tag, &r.sha).await?;
-
         let timestamp = r.local_repo.commit_date_iso(&r.sha).await?;
         let repo = r.local_repo.basename().to_string_lossy();
 
-        let desc = if dist == 0 {
-            &r.tag
-        } else {
-            let shortsha = &r.sha[..7];
-            &format!("{}-{}-g{}", r.tag, dist, shortsha)
-        };
+        let desc = r.describe(selector).await?;
 
         match format {
             ManifestFormat::Pretty => {
@@ -306,70 +1204,1296 @@ async fn write_manifest(
     Ok(())
 }
 
-async fn main_clone(opts: CloneArgs) -> Result<(), Box<dyn Error>> {
-    let resolved = resolve_urls(opts.base_url.as_ref(), &opts.urls)?;
+async fn main_clone(mut opts: CloneArgs, settings: &Settings) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = &opts.branches.branch_fallback_file {
+        opts.branches.rules.extend(branch_fallback::load(path)?);
+    }
+    let lockfile_path = opts
+        .from_lockfile
+        .as_ref()
+        .or(opts.verify_lockfile.as_ref());
+    let lockfile = match lockfile_path {
+        Some(path) => Some(lockfile::load(path)?),
+        None => None,
+    };
+    if let Some(lockfile) = &lockfile {
+        if opts.urls.is_empty() && opts.repos_file.is_none() {
+            opts.urls = lockfile.entries();
+        }
+    }
+    let mut entries = repo_entries(&opts.urls);
+    if let Some(path) = &opts.repos_file {
+        entries.extend(repos_file::load(path)?);
+    }
+    let mut targets = clone_targets(
+        entries,
+        opts.base_url.as_ref(),
+        &opts.mirrors,
+        opts.output_dir.as_deref(),
+        opts.worktree.as_ref(),
+        opts.bare,
+    )?;
+    let options = opts.clone_options(settings);
+    let client = options.git_client()?;
+
+    if let Some(lockfile) = &lockfile {
+        lockfile.pin(&mut targets)?;
+    }
+    if let (Some(path), Some(lockfile)) = (&opts.verify_lockfile, &lockfile) {
+        return verify_lockfile(&client, &targets, lockfile, path).await;
+    }
+
+    /* The refs are listed all at once up front: that is mostly waiting
+     * for the servers, and a repository without a suitable branch shows
+     * up before anything is downloaded */
+    let mut unresolved = list_all_refs(&client, &mut targets, &options).await;
+    if !unresolved.is_empty() && (opts.consistent_branch || opts.dry_run || opts.tags_only) {
+        return Err(unresolved_error(&targets, &unresolved));
+    }
 
-    let mut results = Vec::new();
-    for url in &resolved {
-        info!("=+============================================================");
-        info!(" - {}", masked_url(url));
-        let res = clone_one(url, &opts).await?;
-        info!(
-            " - Done cloning. Branch: {} Tag: {} Sha: {}",
-            res.branch, res.tag, res.sha
+    if opts.consistent_branch {
+        pick_consistent_branch(&mut targets, &options)?;
+    }
+
+    if opts.dry_run {
+        return dry_run(&targets, &options);
+    }
+    if opts.tags_only {
+        return list_tags(&targets, &opts);
+    }
+
+    for (idx, target) in targets.iter().enumerate() {
+        if target.refs.is_none() {
+            continue;
+        }
+        if let Err(e) = find_branch_with(&client.for_url(&target.url), target, &options).await {
+            unresolved.push((idx, format!("{}: {}", masked_url(&target.url), e)));
+        }
+    }
+    unresolved.sort_by_key(|(idx, _)| *idx);
+    if !unresolved.is_empty() && !opts.keep_going {
+        return Err(unresolved_error(&targets, &unresolved));
+    }
+    let mut failures = Vec::new();
+    for (idx, e) in unresolved {
+        error!("{}: {}", targets[idx].name, e);
+        failures.push((idx, e));
+    }
+    let unresolved: HashSet<usize> = failures.iter().map(|(idx, _)| *idx).collect();
+
+    if let Some(dir) = &opts.output_dir {
+        if !dir.is_dir() {
+            if !opts.create_dirs {
+                return Err(format!(
+                    "Output directory '{}' does not exist (use --create-dirs to create it)",
+                    dir.display()
+                )
+                .into());
+            }
+            info!("Creating output directory {}", dir.display());
+            std::fs::create_dir_all(dir)?;
+        }
+    }
+
+    let jobs = opts.jobs.get();
+    if jobs > 1 {
+        /* Interleaved progress output from several repos is unreadable */
+        logging::set_progress(false);
+    }
+
+    let mut outcomes = Box::pin(
+        stream::iter(targets.iter().enumerate())
+            .filter(|(idx, _)| {
+                let skip = unresolved.contains(idx);
+                async move { !skip }
+            })
+            .map(|(idx, target)| {
+                let opts = &opts;
+                let options = &options;
+                logging::with_repo(target.name.clone(), async move {
+                    if jobs == 1 {
+                        info!("=+============================================================");
+                    }
+                    info!(" - {}", masked_url(&target.url));
+                    /* Ctrl-C drops the running clones rather than cancelling them */
+                    let res = clone_repo(target, options, &CancellationToken::new())
+                        .await
+                        .map_err(Box::<dyn Error>::from);
+                    if let Ok(res) = &res {
+                        info!(
+                            " - Done cloning. Branch: {} Tag: {} Sha: {}{}",
+                            res.branch,
+                            res.tag.as_deref().unwrap_or("<none>"),
+                            res.sha,
+                            if opts.no_checkout && !opts.bare {
+                                " (working tree not checked out)"
+                            } else {
+                                ""
+                            }
+                        );
+                    }
+                    let res = match (res, &opts.post_clone_hook) {
+                        (Ok(res), Some(cmd)) => run_post_clone_hook(cmd, &res, options)
+                            .await
+                            .map(|_| res)
+                            .map_err(|e| e.into()),
+                        (res, _) => res,
+                    };
+                    let res = res.map_err(|e| format!("{}: {}", masked_url(&target.url), e));
+                    (idx, res)
+                })
+            })
+            .buffer_unordered(jobs),
+    );
+
+    let mut successes = Vec::new();
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let mut interrupted = false;
+    loop {
+        let (idx, res) = tokio::select! {
+            outcome = outcomes.next() => match outcome {
+                Some(outcome) => outcome,
+                None => break,
+            },
+            _ = &mut ctrl_c => {
+                interrupted = true;
+                break;
+            }
+        };
+        match res {
+            Ok(r) => successes.push((idx, r)),
+            Err(e) if opts.keep_going => {
+                error!("{}: {}", targets[idx].name, e);
+                failures.push((idx, e));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    /* The handler replaced the default one, so Ctrl-C has to be handled
+     * from now on as well */
+    exit_on_ctrl_c();
+    if interrupted {
+        warn!("Interrupted, cleaning up (press Ctrl-C again to exit right away)");
+    }
+    /* Clones still running are cancelled: their git processes are killed
+     * and what they created is removed */
+    drop(outcomes);
+    successes.sort_by_key(|(idx, _)| *idx);
+    failures.sort_by_key(|(idx, _)| *idx);
+
+    if opts.keep_going || interrupted {
+        let width = targets.iter().map(|t| t.name.len()).max().unwrap_or(0);
+        info!("Summary:");
+        for (idx, r) in &successes {
+            info!(
+                "  {:width$}  ok      {}",
+                targets[*idx].name,
+                r.tag.as_deref().unwrap_or("<no tag>")
+            );
+        }
+        for (idx, e) in &failures {
+            error!("  {:width$}  FAILED  {}", targets[*idx].name, e);
+        }
+        if interrupted {
+            let finished: HashSet<usize> = successes
+                .iter()
+                .map(|(idx, _)| *idx)
+                .chain(failures.iter().map(|(idx, _)| *idx))
+                .collect();
+            for (_, target) in targets
+                .iter()
+                .enumerate()
+                .filter(|(idx, _)| !finished.contains(idx))
+            {
+                warn!("  {:width$}  INTERRUPTED", target.name);
+            }
+        }
+    }
+    if interrupted {
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+
+    let results: Vec<CloneResult> = successes.into_iter().map(|(_, r)| r).collect();
+    let selector = options.tag_selector();
+    let common_tag = if opts.common_tag {
+        common_tag(&results, &selector)?
+    } else {
+        None
+    };
+    let aggregate = aggregate_result(&results, &selector, opts.tags.tag_aggregate)?;
+    let aggregate_tag = match &common_tag {
+        Some(tag) => Some(tag),
+        None => aggregate.and_then(|r| r.tag.as_ref()),
+    };
+    let aggregate_how = if opts.common_tag {
+        "common".to_string()
+    } else {
+        format!("{:?}", opts.tags.tag_aggregate).to_lowercase()
+    };
+    if results.len() > 1 {
+        if let Some(tag) = aggregate_tag {
+            info!("Tag for all repositories: {} ({})", tag, aggregate_how);
+        }
+    }
+
+    if let Some(path) = &opts.tag_output_file {
+        if failures.is_empty() || opts.tag_from_successful {
+            let mut contents = if opts.tag_output_per_repo {
+                results
+                    .iter()
+                    .map(|r| {
+                        format!(
+                            "{}\n",
+                            opts.tag_output_format.render(&tag_vars(r, &selector))
+                        )
+                    })
+                    .collect()
+            } else {
+                let aggregate = aggregate.ok_or("No repository produced a tag")?;
+                let mut vars = tag_vars(aggregate, &selector);
+                if let Some(tag) = &common_tag {
+                    vars.tag = selector.output_name(tag);
+                }
+                opts.tag_output_format.render(&vars)
+            };
+            terminate_line(&mut contents, !opts.no_trailing_newline);
+            if path == "-" {
+                print!("{}", contents);
+            } else {
+                util::write_atomically(Path::new(path), &contents).await?;
+                debug!("Wrote tag output to {path}");
+            }
+        } else {
+            warn!("Not writing {path} since some repositories failed");
+        }
+    }
+
+    /* Each repository's own, so failures elsewhere don't matter */
+    let mut tag_files = Vec::new();
+    if let Some(dir) = &opts.tag_output_dir {
+        for r in &results {
+            let path = dir.join(format!("{}.tag", r.name));
+            let mut contents = String::new();
+            if r.tag.is_some() {
+                contents = opts.tag_output_format.render(&tag_vars(r, &selector));
+                terminate_line(&mut contents, !opts.no_trailing_newline);
+            } else if opts.skip_untagged {
+                /* Not the tag of an earlier run either */
+                match tokio::fs::remove_file(&path).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => continue,
+                }
+            }
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            util::write_atomically(&path, &contents).await?;
+            debug!("Wrote the tag of {} to {}", r.name, path.display());
+            tag_files.push(path);
+        }
+    }
+
+    if let Some(path) = &opts.tags_output_file {
+        if failures.is_empty() || opts.tag_from_successful {
+            let mut contents = String::new();
+            for r in &results {
+                let selector = TagSelector {
+                    times: Some(&r.tag_times),
+                    ..selector
+                };
+                let mut tags: Vec<&String> = r.reachable_tags.iter().collect();
+                tags.sort_by(|a, b| selector.compare(a, b));
+                for tag in tags {
+                    contents.push_str(&format!("{}\t{}\t{}\n", r.name, tag, r.tag_commits[tag]));
+                }
+            }
+            util::write_atomically(path, &contents).await?;
+            debug!("Wrote reachable tags to {}", path.display());
+        } else {
+            warn!(
+                "Not writing {} since some repositories failed",
+                path.display()
+            );
+        }
+    }
+
+    if let Some(path) = &opts.manifest_output_file {
+        write_manifest(&results, &selector, path, opts.manifest_format).await?;
+    }
+
+    if let Some(path) = &opts.ref_output_file {
+        let mut contents = String::new();
+        for target in &targets {
+            match results.iter().find(|r| r.name == target.name) {
+                Some(r) => contents.push_str(&format!("{} {} {}\n", r.name, r.refname, r.sha)),
+                None => contents.push_str(&format!("{} FAILED -\n", target.name)),
+            }
+        }
+        util::write_atomically(path, &contents).await?;
+        debug!("Wrote the cloned refs to {}", path.display());
+    }
+
+    if let Some(path) = &opts.describe_output_file {
+        let mut contents = String::new();
+        for target in &targets {
+            let description = match results.iter().find(|r| r.name == target.name) {
+                Some(r) => describe(r, &selector).await?,
+                None => "FAILED".to_string(),
+            };
+            if targets.len() == 1 {
+                contents.push_str(&format!("{}\n", description));
+            } else {
+                contents.push_str(&format!("{} {}\n", target.name, description));
+            }
+        }
+        util::write_atomically(path, &contents).await?;
+        debug!("Wrote the descriptions to {}", path.display());
+    }
+
+    if let Some(path) = &opts.write_lockfile {
+        if failures.is_empty() {
+            let lockfile = lockfile::Lockfile::from_results(&results, &targets);
+            util::write_atomically(path, &lockfile.format()).await?;
+            debug!("Wrote lockfile {}", path.display());
+        } else {
+            warn!(
+                "Not writing {} since some repositories failed",
+                path.display()
+            );
+        }
+    }
+
+    if let Some(path) = &opts.json_output {
+        let failed: Vec<serde_json::Value> = failures
+            .iter()
+            .map(|(idx, e)| {
+                serde_json::json!({
+                    "name": targets[*idx].name,
+                    "url": masked_url(&targets[*idx].url),
+                    "error": e,
+                })
+            })
+            .collect();
+        let summary = serde_json::json!({
+            "tag": aggregate_tag,
+            "tag_aggregate": aggregate_how,
+            "repos": results,
+            "failed": failed,
+            "tag_files": tag_files,
+        });
+        write_output(path, &serde_json::to_string_pretty(&summary)?)?;
+        debug!("Wrote JSON summary to {path}");
+    }
+
+    let failed: Vec<&str> = failures
+        .iter()
+        .map(|(idx, _)| targets[*idx].name.as_str())
+        .collect();
+    if opts.github_output || opts.env_output.is_some() {
+        let outputs = ci_outputs(&results, &failed, aggregate_tag, &selector);
+        if opts.github_output {
+            match std::env::var_os("GITHUB_OUTPUT") {
+                Some(path) => {
+                    outputs.append_github(Path::new(&path))?;
+                    debug!("Wrote step outputs to {}", path.to_string_lossy());
+                }
+                None => warn!("Not writing step outputs, GITHUB_OUTPUT isn't set"),
+            }
+        }
+        if let Some(path) = &opts.env_output {
+            util::write_atomically(path, &outputs.env_format()).await?;
+            debug!("Wrote outputs to {}", path.display());
+        }
+    }
+
+    if let Some(cmd) = &opts.post_run_hook {
+        let tag = aggregate_tag.map_or("", |tag| selector.output_name(tag));
+        hooks::run(
+            "post-run",
+            cmd,
+            Path::new("."),
+            &[
+                ("SLEIPNIR_HOOK_TAG", tag),
+                ("SLEIPNIR_HOOK_FAILED_REPOS", &failed.join(",")),
+            ],
+        )
+        .await?;
+    }
+
+    if !failures.is_empty() {
+        return Err(format!(
+            "{} of {} repositories failed",
+            failures.len(),
+            targets.len()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/* Lists the refs of every repository, several at once, into
+ * `CloneTarget::refs`. Returns the repositories that couldn't be
+ * listed. */
+async fn list_all_refs(
+    client: &GitClient,
+    targets: &mut [CloneTarget],
+    options: &CloneOptions,
+) -> Vec<(usize, String)> {
+    let listings: Vec<_> = stream::iter(targets.iter().enumerate())
+        .map(|(idx, target)| {
+            logging::with_repo(target.name.clone(), async move {
+                (idx, list_refs(client, target, options).await)
+            })
+        })
+        .buffer_unordered(LIST_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut failed = Vec::new();
+    for (idx, listing) in listings {
+        match listing {
+            Ok(refs) => targets[idx].refs = Some(refs),
+            Err(e) => failed.push((idx, format!("{}: {}", masked_url(&targets[idx].url), e))),
+        }
+    }
+    failed.sort_by_key(|(idx, _)| *idx);
+    failed
+}
+
+/* A single repository's error as it is, or all of them logged */
+fn unresolved_error(targets: &[CloneTarget], unresolved: &[(usize, String)]) -> Box<dyn Error> {
+    if let [(_, e)] = unresolved {
+        return e.clone().into();
+    }
+    for (_, e) in unresolved {
+        error!("{}", e);
+    }
+    let names: Vec<&str> = unresolved
+        .iter()
+        .map(|(idx, _)| targets[*idx].name.as_str())
+        .collect();
+    format!("No suitable branch found for: {}", names.join(", ")).into()
+}
+
+/* Picks the first candidate along the fallback chain that exists in all
+ * repositories (falling back to --default-branch) and makes every target
+ * use it. Repositories with an explicit branch of their own are left
+ * alone. */
+fn pick_consistent_branch(
+    targets: &mut [CloneTarget],
+    opts: &CloneOptions,
+) -> Result<(), Box<dyn Error>> {
+    let wanted = opts
+        .branch
+        .as_deref()
+        .ok_or("--consistent-branch needs --branch")?;
+    let listings: Vec<(&str, &[RefInfo])> = targets
+        .iter()
+        .filter(|t| t.branch.is_none())
+        .map(|t| (t.name.as_str(), t.refs.as_deref().unwrap_or_default()))
+        .collect();
+
+    /* Branches present in every repository */
+    let mut common: HashMap<&str, &()> = HashMap::new();
+    if let Some((_, first)) = listings.first() {
+        for name in first
+            .iter()
+            .filter_map(|r| r.refname.strip_prefix("refs/heads/"))
+        {
+            let everywhere = listings.iter().all(|(_, refs)| {
+                refs.iter()
+                    .any(|r| r.refname.strip_prefix("refs/heads/") == Some(name))
+            });
+            if everywhere && !opts.excludes.iter().any(|re| re.is_match(name)) {
+                common.insert(name, &());
+            }
+        }
+    }
+
+    let target_branch = wanted.strip_prefix("refs/heads/").unwrap_or(wanted);
+    let (found, steps) = branch_fallback::resolve_traced(
+        target_branch,
+        &opts.rules,
+        &common,
+        opts.fallback_strategy,
+    );
+    let chosen = match found {
+        Some(_) => steps
+            .iter()
+            .find(|step| step.chosen)
+            .map(|step| step.candidate.to_string()),
+        None => opts
+            .default_branch
+            .clone()
+            .filter(|d| common.contains_key(d.as_str())),
+    };
+
+    match chosen {
+        Some(chosen) => {
+            info!("Using branch {} in all repositories", chosen);
+            for target in targets.iter_mut().filter(|t| t.branch.is_none()) {
+                target.branch = Some(chosen.clone());
+            }
+            Ok(())
+        }
+        None => {
+            let width = listings.iter().map(|(n, _)| n.len()).max().unwrap_or(0);
+            error!("No branch common to all repositories, they resolve to:");
+            for (name, refs) in &listings {
+                let branch = select_branch(
+                    refs,
+                    wanted,
+                    &opts.rules,
+                    opts.fallback_strategy,
+                    &opts.excludes,
+                    opts.default_branch.as_deref(),
+                    TagLookup::No,
+                    &mut Vec::new(),
+                );
+                error!(
+                    "  {:width$}  {}",
+                    name,
+                    branch
+                        .map(|(b, _)| short_refname(&b.refname).to_string())
+                        .unwrap_or_else(|| "<none>".to_string())
+                );
+            }
+            Err("No consistent branch across repositories".into())
+        }
+    }
+}
+
+/* The highest tag reachable in every repository. If there is none, the
+ * error tells which repositories lack each repository's own max tag. */
+fn common_tag(results: &[CloneResult], selector: &TagSelector) -> Result<Option<String>, String> {
+    let Some((first, rest)) = results.split_first() else {
+        return Ok(None);
+    };
+    /* Every common tag is reachable in the first repository */
+    let selector = TagSelector {
+        times: Some(&first.tag_times),
+        ..*selector
+    };
+    let common = selector.max(
+        first
+            .reachable_tags
+            .iter()
+            .filter(|t| rest.iter().all(|r| r.reachable_tags.contains(t)))
+            .map(String::as_str),
+    );
+    if let Some(tag) = common {
+        return Ok(Some(tag.to_string()));
+    }
+
+    let mut problems = Vec::new();
+    for r in results {
+        let Some(tag) = &r.tag else {
+            problems.push(format!("no tag in {}", r.name));
+            continue;
+        };
+        let missing: Vec<&str> = results
+            .iter()
+            .filter(|o| !o.reachable_tags.contains(tag))
+            .map(|o| o.name.as_str())
+            .collect();
+        problems.push(format!(
+            "tag {} present in {} but not in {}",
+            tag,
+            r.name,
+            missing.join(", ")
+        ));
+    }
+    Err(format!(
+        "No tag common to all repositories: {}",
+        problems.join("; ")
+    ))
+}
+
+/* The repository whose tag represents the whole set of repositories,
+ * normally the one with the lowest tag */
+fn aggregate_result<'a>(
+    results: &'a [CloneResult],
+    selector: &TagSelector,
+    how: TagAggregate,
+) -> Result<Option<&'a CloneResult>, String> {
+    let times: HashMap<String, i64> = results
+        .iter()
+        .filter_map(|r| {
+            let tag = r.tag.as_ref()?;
+            Some((tag.clone(), *r.tag_times.get(tag)?))
+        })
+        .collect();
+    let selector = TagSelector {
+        times: Some(&times),
+        ..*selector
+    };
+    let tags = results.iter().filter_map(|r| r.tag.as_deref());
+    let tag = match how {
+        TagAggregate::Min => selector.min(tags),
+        TagAggregate::Max => selector.max(tags),
+        TagAggregate::Same => {
+            let distinct: HashSet<&str> = tags.clone().collect();
+            if distinct.len() > 1 {
+                let per_repo: Vec<String> = results
+                    .iter()
+                    .map(|r| format!("{}: {}", r.name, r.tag.as_deref().unwrap_or("<no tag>")))
+                    .collect();
+                return Err(format!(
+                    "Repositories selected different tags: {}",
+                    per_repo.join(", ")
+                ));
+            }
+            distinct.into_iter().next()
+        }
+    };
+    Ok(tag.and_then(|tag| results.iter().find(|r| r.tag.as_deref() == Some(tag))))
+}
+
+fn dry_run(targets: &[CloneTarget], opts: &CloneOptions) -> Result<(), Box<dyn Error>> {
+    let mut unresolved = Vec::new();
+    for target in targets {
+        let refs = target.refs.as_deref().unwrap_or_default();
+
+        let target_branch = target.wanted_branch(opts)?;
+        let mut steps = Vec::new();
+        let branch = select_branch(
+            refs,
+            target_branch,
+            &opts.rules,
+            opts.fallback_strategy,
+            &opts.excludes,
+            opts.default_branch.as_deref(),
+            TagLookup::exact_if(opts.allow_tag_as_branch),
+            &mut steps,
         );
-        results.push(res);
+
+        match branch {
+            Some((branch, _)) => {
+                let commit = branch.commit();
+                let tip_tags: Vec<&str> = refs
+                    .iter()
+                    .filter(|r| r.commit() == commit)
+                    .filter_map(|r| r.refname.strip_prefix("refs/tags/"))
+                    .collect();
+                println!("{}: {} {}", target.name, branch.refname, commit);
+                println!(
+                    "{}: tags at branch tip: {}",
+                    target.name,
+                    if tip_tags.is_empty() {
+                        "<none>".to_string()
+                    } else {
+                        tip_tags.join(" ")
+                    }
+                );
+            }
+            None => {
+                println!(
+                    "{}: {}",
+                    target.name,
+                    no_branch_found(refs, target_branch, &steps)
+                );
+                unresolved.push(target.name.as_str());
+            }
+        }
+    }
+
+    info!("Dry run: tags further back than the branch tip can only be found by fetching");
+
+    if unresolved.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("No suitable branch found for: {}", unresolved.join(", ")).into())
+    }
+}
+
+/* Compares the refs of each repository with what the lockfile recorded,
+ * without fetching anything */
+async fn verify_lockfile(
+    client: &GitClient,
+    targets: &[CloneTarget],
+    lockfile: &lockfile::Lockfile,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let mut drifted = Vec::new();
+    for target in targets {
+        /* Lockfile::pin made sure every target is in there */
+        let Some(locked) = lockfile.find(&target.url) else {
+            continue;
+        };
+        let remote_repo = client.for_url(&target.url);
+        let refs = remote_repo
+            .ls_refs(&locked.wanted_refs())
+            .await
+            .map_err(|e| format!("{}: {}", masked_url(&target.url), e))?;
+        let drift = locked.drift(&refs);
+        if drift.is_empty() {
+            println!("{}: up to date", target.name);
+        } else {
+            for d in &drift {
+                println!("{}: {}", target.name, d);
+            }
+            drifted.push(target.name.as_str());
+        }
+    }
+
+    if drifted.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "The remotes no longer match {} for: {}",
+            path.display(),
+            drifted.join(", ")
+        )
+        .into())
     }
+}
+
+/* Lists the tags of each repository along with the commit they point
+ * to, without fetching anything */
+fn list_tags(targets: &[CloneTarget], opts: &CloneArgs) -> Result<(), Box<dyn Error>> {
+    let mut repos = Vec::new();
+    for target in targets {
+        let refs = target.refs.as_deref().unwrap_or_default();
 
-    if let Some(path) = opts.tag_output_file {
-        let tag = results
+        let mut tags: Vec<(&str, &str)> = refs
             .iter()
-            .map(|r| &r.tag)
-            .min_by(|a, b| natord::compare(a, b))
-            .unwrap();
-        let mut file = std::fs::File::create(&path)?;
-        file.write_all(tag.as_bytes())?;
-        debug!("Wrote tag {tag} to {path}");
+            .filter_map(|r| {
+                let tagname = r.refname.strip_prefix("refs/tags/")?;
+                Some((tagname, r.commit().as_str()))
+            })
+            .collect();
+        tags.sort_by(|a, b| opts.tags.tag_order.compare(a.0, b.0));
+
+        if opts.json_output.as_deref() != Some("-") {
+            for (tag, commit) in &tags {
+                println!("{}: {} {}", target.name, tag, commit);
+            }
+        }
+        repos.push(serde_json::json!({
+            "name": target.name,
+            "url": masked_url(&target.url),
+            "tags": tags
+                .iter()
+                .map(|(tag, commit)| serde_json::json!({ "tag": tag, "sha": commit }))
+                .collect::<Vec<_>>(),
+        }));
     }
 
-    if let Some(path) = opts.manifest_output_file {
-        write_manifest(&results, path, opts.manifest_format).await?;
+    if let Some(path) = &opts.json_output {
+        let summary = serde_json::json!({ "repos": repos });
+        write_output(path, &serde_json::to_string_pretty(&summary)?)?;
     }
 
     Ok(())
 }
 
-async fn main_findbranch(opts: FindBranchArgs) -> Result<(), Box<dyn Error>> {
+async fn main_findbranch(
+    mut opts: FindBranchArgs,
+    quiet: bool,
+    settings: &Settings,
+) -> Result<FindBranchStatus, Box<dyn Error>> {
+    if let Some(path) = &opts.branches.branch_fallback_file {
+        opts.branches.rules.extend(branch_fallback::load(path)?);
+    }
     let wanted_ref = opts
+        .branches
         .branches_starting_with
+        .as_ref()
         .map(|b| format!("refs/heads/{}", b))
         .unwrap_or_else(|| "refs/heads/".to_string());
+    let wanted_tags = opts
+        .branches
+        .tags_starting_with
+        .as_ref()
+        .map(|t| format!("refs/tags/{}", t))
+        .unwrap_or_else(|| "refs/tags/".to_string());
+
+    /* The refs of each repository, with its label and how errors name it */
+    let mut listed = Vec::new();
+    if let Some(path) = &opts.local {
+        debug!("Listing local branches of {}", path.display());
+        let mut refs = LocalRepo::open(path, &settings.git)
+            .await?
+            .list_branches()
+            .await
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        refs.retain(|r| r.refname.starts_with(&wanted_ref));
+        let label = path.display().to_string();
+        listed.push((label.clone(), label, refs));
+    } else {
+        let client = settings.git_client()?;
+        for url in resolve_urls(opts.base_url.as_ref(), &opts.repo_urls)? {
+            let remote_repo = client.for_url(&url);
+
+            /* Tags too, fallback rules may point there */
+            debug!("Listing remote refs (wanted ref: {:?})", wanted_ref);
+            let refs = remote_repo
+                .ls_refs(&[wanted_ref.as_str(), wanted_tags.as_str()])
+                .await
+                .map_err(|e| format!("{}: {}", masked_url(&url), e))?;
+            listed.push((repo_label(&url), masked_url(&url), refs));
+        }
+    }
 
-    let client = GitClient::new();
-    let remote_repo = client.for_url(&Url::parse(&opts.repo_url)?);
+    let mut found = Vec::new();
+    let mut unresolved = Vec::new();
+    for (label, shown, refs) in &listed {
+        let mut steps = Vec::new();
+        let branch = select_branch(
+            refs,
+            &opts.branch,
+            &opts.branches.rules,
+            opts.branches.fallback_strategy,
+            &opts.branches.excludes,
+            opts.branches.default_branch.as_deref(),
+            if opts.include_tags {
+                TagLookup::Candidates
+            } else {
+                TagLookup::No
+            },
+            &mut steps,
+        );
+        if opts.explain && !opts.json {
+            let prefix = if listed.len() > 1 {
+                format!("{}: ", label)
+            } else {
+                String::new()
+            };
+            println!(
+                "{}strategy: {}",
+                prefix,
+                format!("{:?}", opts.branches.fallback_strategy).to_lowercase()
+            );
+            for step in &steps {
+                println!(
+                    "{}{} {}{}",
+                    prefix,
+                    step.candidate,
+                    match (step.found, step.chosen) {
+                        (_, true) => "selected",
+                        (true, false) => "found",
+                        (false, _) => "not found",
+                    },
+                    step.rule
+                        .as_ref()
+                        .map(|r| format!(" (from {})", r))
+                        .unwrap_or_default()
+                );
+            }
+            if let Some((b, MatchedBy::DefaultBranch)) = &branch {
+                println!("{}{} default branch", prefix, short_refname(&b.refname));
+            }
+        }
+        match branch {
+            Some((branch, matched_by)) => {
+                /* Tags (from "tag:" rules or --include-tags) are shown fully qualified */
+                let name = branch
+                    .refname
+                    .strip_prefix("refs/heads/")
+                    .unwrap_or(&branch.refname);
+                found.push((
+                    label.clone(),
+                    name.to_string(),
+                    branch.clone(),
+                    matched_by,
+                    steps,
+                ));
+            }
+            None => unresolved.push((shown.clone(), no_branch_found(refs, &opts.branch, &steps))),
+        }
+    }
 
-    debug!("Listing remote refs (wanted ref: {:?})", wanted_ref);
-    let refs = remote_repo.ls_refs(&[wanted_ref]).await?;
+    let json_entry = |repo: Option<&str>,
+                      name: &str,
+                      branch: &RefInfo,
+                      matched_by: &MatchedBy,
+                      steps: &[ResolutionStep]| {
+        let mut entry = serde_json::json!({
+            "branch": name,
+            "refname": branch.refname,
+            "sha": branch.sha,
+            "matched_by": matched_by.to_string(),
+        });
+        if let Some(repo) = repo {
+            entry["repo"] = repo.into();
+        }
+        if opts.explain {
+            entry["resolution"] = serde_json::json!(steps);
+        }
+        entry
+    };
+
+    if quiet {
+        /* Only the exit status tells */
+    } else if listed.len() == 1 {
+        /* A single repository keeps the plain output format */
+        if let Some((_, name, branch, matched_by, steps)) = found.first() {
+            if opts.json {
+                println!("{}", json_entry(None, name, branch, matched_by, steps));
+            } else {
+                println!("{}", name);
+            }
+        }
+    } else if opts.json {
+        let repos: Vec<serde_json::Value> = found
+            .iter()
+            .map(|(repo, name, branch, matched_by, steps)| {
+                json_entry(Some(repo), name, branch, matched_by, steps)
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&repos)?);
+    } else {
+        for (repo, name, ..) in &found {
+            println!("{} {}", repo, name);
+        }
+    }
 
-    let mut available_branches = HashMap::<&str, &RefInfo>::new();
-    for r in &refs {
-        if let Some(branchname) = r.refname.strip_prefix("refs/heads/") {
-            available_branches.insert(branchname, r);
+    if quiet && !unresolved.is_empty() {
+        for (url, e) in &unresolved {
+            debug!("{}: {}", url, e);
         }
+        return Ok(FindBranchStatus::Unresolved);
+    }
+    if let [(url, e)] = &unresolved[..] {
+        return Err(format!("{}: {}", url, e).into());
+    }
+    if !unresolved.is_empty() {
+        for (url, e) in &unresolved {
+            error!("{}: {}", url, e);
+        }
+        let urls: Vec<&str> = unresolved.iter().map(|(url, _)| url.as_str()).collect();
+        return Err(format!("No suitable branch found for: {}", urls.join(", ")).into());
     }
 
-    let mut branch: Option<&RefInfo> =
-        branch_fallback::resolve(&opts.branch, &opts.fallbacks, &available_branches);
-    debug!("Found branch: {:?}", branch);
-    if branch.is_none() && opts.default_branch.is_some() {
-        branch = available_branches
-            .get(opts.default_branch.as_ref().unwrap().as_str())
-            .map(|v| &**v);
+    if opts.require_consistent {
+        let branches: HashSet<&str> = found.iter().map(|(_, name, ..)| name.as_str()).collect();
+        if branches.len() > 1 {
+            let mut branches: Vec<&str> = branches.into_iter().collect();
+            branches.sort();
+            return Err(format!(
+                "Repositories resolved to different branches: {}",
+                branches.join(", ")
+            )
+            .into());
+        }
     }
+
+    let by_default = found
+        .iter()
+        .any(|(.., matched_by, _)| *matched_by == MatchedBy::DefaultBranch);
+    Ok(if by_default {
+        FindBranchStatus::DefaultBranch
+    } else {
+        FindBranchStatus::Resolved
+    })
+}
+
+async fn main_lsrefs(opts: LsRefsArgs, settings: &Settings) -> Result<(), Box<dyn Error>> {
+    let url = resolve_urls(opts.base_url.as_ref(), &[opts.repo_url])?.remove(0);
+
+    let client = settings.git_client()?;
+    let remote_repo = client.for_url(&url);
+
+    debug!("Listing remote refs (prefixes: {:?})", opts.prefixes);
+    let refs = remote_repo
+        .ls_refs(&opts.prefixes)
+        .await
+        .map_err(|e| format!("{}: {}", masked_url(&url), e))?;
+
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&refs)?);
+    } else {
+        for r in &refs {
+            match &r.peeled {
+                Some(peeled) => println!("{} {} {}", r.sha, r.refname, peeled),
+                None => println!("{} {}", r.sha, r.refname),
+            }
+        }
+    }
+    Ok(())
+}
+
+/* The bare clones of max-tag and describe, in a temporary directory
+ * that has to be kept as long as they are used */
+async fn clone_temporarily(
+    opts: &mut TemporaryCloneArgs,
+    settings: &Settings,
+) -> Result<(tempfile::TempDir, CloneOptions, Vec<CloneResult>), Box<dyn Error>> {
+    if let Some(path) = &opts.branches.branch_fallback_file {
+        opts.branches.rules.extend(branch_fallback::load(path)?);
+    }
+    let tmpdir = tempfile::Builder::new().prefix("git-sleipnir-").tempdir()?;
+    let targets = clone_targets(
+        repo_entries(&opts.urls),
+        opts.base_url.as_ref(),
+        &[],
+        Some(tmpdir.path()),
+        None,
+        true,
+    )?;
+    let options = opts.clone_options(settings);
+
+    let mut results = Vec::new();
+    for target in &targets {
+        let res = logging::with_repo(
+            target.name.clone(),
+            clone_repo(target, &options, &CancellationToken::new()),
+        )
+        .await
+        .map_err(|e| format!("{}: {}", masked_url(&target.url), e))?;
+        debug!("{}: {:?}", res.name, res.tag);
+        results.push(res);
+    }
+    Ok((tmpdir, options, results))
+}
+
+/* Notes when a nearer tag may be beyond the history searched */
+async fn describe(r: &CloneResult, selector: &TagSelector<'_>) -> Result<String, Box<dyn Error>> {
+    let description = r.describe(selector).await?;
+    if let Some(depth) = description.searched_depth {
+        info!(
+            "{}: searched to depth {}, a nearer tag beyond it would have been missed",
+            r.name, depth
+        );
+    }
+    Ok(description.to_string())
+}
+
+async fn main_maxtag(mut opts: MaxTagArgs, settings: &Settings) -> Result<(), Box<dyn Error>> {
+    let (tmpdir, options, results) = clone_temporarily(&mut opts.clones, settings).await?;
+
+    let selector = options.tag_selector();
+    let aggregate = aggregate_result(&results, &selector, opts.clones.tags.tag_aggregate)?
+        .ok_or("No repository produced a tag")?;
+    let tag = selector.output_name(aggregate.tag.as_ref().unwrap());
+    if opts.sha {
+        println!("{} {}", tag, aggregate.tag_sha.as_ref().unwrap());
+    } else {
+        println!("{}", tag);
+    }
+
+    if opts.clones.keep {
+        info!("Keeping clones in {}", tmpdir.keep().display());
+    }
+    Ok(())
+}
+
+async fn main_describe(mut opts: DescribeArgs, settings: &Settings) -> Result<(), Box<dyn Error>> {
+    let (tmpdir, options, results) = clone_temporarily(&mut opts.clones, settings).await?;
+    let selector = options.tag_selector();
+
+    for r in &results {
+        let description = describe(r, &selector).await?;
+        /* A single repository keeps the plain output format */
+        if results.len() == 1 {
+            println!("{}", description);
+        } else {
+            println!("{} {}", r.name, description);
+        }
+    }
+
+    if opts.clones.keep {
+        info!("Keeping clones in {}", tmpdir.keep().display());
+    }
+    Ok(())
+}
+
+async fn main_fetch(opts: FetchArgs, settings: &Settings) -> Result<(), Box<dyn Error>> {
+    let url = resolve_urls(opts.base_url.as_ref(), &[opts.repo_url])?.remove(0);
+    let local_repo = LocalRepo::open(&opts.path, &settings.git).await?;
+
+    let client = settings.git_client()?;
+    let remote_repo = client.for_url(&url);
+
+    let mut wanted_refs = vec!["refs/tags/".to_string()];
+    if let Some(branch) = &opts.branch {
+        wanted_refs.push(format!("refs/heads/{}", branch));
+    }
+    debug!("Listing remote refs (wanted refs: {:?})", wanted_refs);
+    let refs = remote_repo
+        .ls_refs(&wanted_refs)
+        .await
+        .map_err(|e| format!("{}: {}", masked_url(&url), e))?;
+
+    let branch = match &opts.branch {
+        Some(name) => {
+            let refname = format!("refs/heads/{}", name);
+            let branch = refs
+                .iter()
+                .find(|r| r.refname == refname)
+                .ok_or_else(|| format!("{}: no branch '{}'", masked_url(&url), name))?;
+            Some(branch)
+        }
+        None => None,
+    };
+
     if let Some(branch) = branch {
-        println!("{}", branch.refname.strip_prefix("refs/heads/").unwrap());
-        Ok(())
+        /* Like git, don't move the branch from under a checked out tree */
+        if !opts.update_head
+            && !local_repo.is_bare()
+            && local_repo.head_ref().await?.as_deref() == Some(branch.refname.as_str())
+        {
+            return Err(format!(
+                "Refusing to update checked out branch {} (use --update-head)",
+                branch.refname
+            )
+            .into());
+        }
+    }
+
+    let tip = match branch {
+        Some(branch) => branch.sha.clone(),
+        None => local_repo
+            .head_sha()
+            .await
+            .map_err(|e| format!("HEAD does not point to a commit: {}", e))?,
+    };
+
+    let deepen = match opts.deepen {
+        Some(n) => Deepen::Relative(n.get()),
+        None => Deepen::Unchanged,
+    };
+    info!("Fetching {} ({:?})", tip, deepen);
+    remote_repo
+        .shallow_fetch(&local_repo, &[&tip], deepen, true)
+        .await?;
+
+    if let Some(branch) = branch {
+        local_repo.update_ref(&branch.refname, &branch.sha).await?;
+    }
+
+    let commits = local_repo.rev_list(&tip).await?;
+    let reachable_tags = fetch_reachable_tags(
+        &remote_repo,
+        &local_repo,
+        &refs,
+        &commits,
+        Deepen::Unchanged,
+    )
+    .await?;
+    debug!("Reachable tags: {:?}", reachable_tags);
+
+    if opts.update_head {
+        if let Some(branch) = branch {
+            local_repo.update_head(&branch.refname).await?;
+            if !local_repo.is_bare() {
+                local_repo.checkout_head(false).await?;
+            }
+        }
+    }
+
+    info!(
+        "Done fetching. {} commits, {} reachable tags",
+        commits.len(),
+        reachable_tags.len()
+    );
+    Ok(())
+}
+
+async fn main_verify(
+    opts: VerifyArgs,
+    settings: &Settings,
+) -> Result<VerifyStatus, Box<dyn Error>> {
+    let url = resolve_urls(opts.base_url.as_ref(), &[opts.repo_url])?.remove(0);
+    let local_repo = LocalRepo::open(&opts.path, &settings.git).await?;
+    let head = local_repo.head_ref().await?;
+    let sha = local_repo.head_sha().await?;
+    let local_tags = local_repo.list_refs("refs/tags").await?;
+
+    let client = settings.git_client()?;
+    let mut wanted_refs = vec!["refs/tags/".to_string()];
+    wanted_refs.extend(head.clone());
+    debug!("Listing remote refs (wanted refs: {:?})", wanted_refs);
+    let refs = client
+        .for_url(&url)
+        .ls_refs(&wanted_refs)
+        .await
+        .map_err(|e| format!("{}: {}", masked_url(&url), e))?;
+
+    let mut status = VerifyStatus::UpToDate;
+    let mut remote_sha = None;
+    let (mut behind, mut ahead) = (None, None);
+    let mut lines = Vec::new();
+    match &head {
+        None => lines.push(format!("HEAD detached at {}", sha)),
+        Some(refname) => match refs.iter().find(|r| r.refname == *refname) {
+            None => {
+                status = VerifyStatus::BranchGone;
+                lines.push(format!("{} gone from the remote", refname));
+            }
+            Some(remote) if remote.sha == sha => {
+                remote_sha = Some(&remote.sha);
+                (behind, ahead) = (Some(0), Some(0));
+                lines.push(format!("{} up to date at {}", refname, sha));
+            }
+            /* A commit never fetched is taken as the branch having moved
+             * on, whether it was rewritten can't be told without it */
+            Some(remote) if !local_repo.has_commit(&remote.sha).await? => {
+                remote_sha = Some(&remote.sha);
+                status = VerifyStatus::Behind;
+                lines.push(format!("{} behind: {} -> {}", refname, sha, remote.sha));
+            }
+            Some(remote) => {
+                remote_sha = Some(&remote.sha);
+                let commits_behind = local_repo.distance(&sha, &remote.sha).await?;
+                let commits_ahead = local_repo.distance(&remote.sha, &sha).await?;
+                (behind, ahead) = (Some(commits_behind), Some(commits_ahead));
+                if commits_ahead == 0 {
+                    status = VerifyStatus::Behind;
+                    lines.push(format!(
+                        "{} behind by {} commits: {} -> {}",
+                        refname, commits_behind, sha, remote.sha
+                    ));
+                } else {
+                    status = VerifyStatus::Diverged;
+                    lines.push(format!(
+                        "{} diverged, {} commits behind and {} ahead: {} -> {}",
+                        refname, commits_behind, commits_ahead, sha, remote.sha
+                    ));
+                }
+            }
+        },
+    }
+
+    let mut mismatched_tags = Vec::new();
+    for tag in &local_tags {
+        let remote = refs.iter().find(|r| r.refname == tag.refname);
+        match remote {
+            Some(remote) if remote.sha == tag.sha => continue,
+            Some(remote) => lines.push(format!(
+                "{} moved: {} -> {}",
+                tag.refname, tag.sha, remote.sha
+            )),
+            None => lines.push(format!("{} gone from the remote", tag.refname)),
+        }
+        status = status.max(VerifyStatus::Diverged);
+        mismatched_tags.push(serde_json::json!({
+            "refname": tag.refname,
+            "sha": tag.sha,
+            "remote_sha": remote.map(|r| &r.sha),
+        }));
+    }
+
+    if opts.json {
+        let details = serde_json::json!({
+            "path": opts.path,
+            "url": masked_url(&url),
+            "status": status.name(),
+            "head": head,
+            "sha": sha,
+            "remote_sha": remote_sha,
+            "behind": behind,
+            "ahead": ahead,
+            "tags_checked": local_tags.len(),
+            "mismatched_tags": mismatched_tags,
+        });
+        println!("{}", serde_json::to_string_pretty(&details)?);
     } else {
-        Err("No suitable branch found".into())
+        for line in &lines {
+            println!("{}", line);
+        }
     }
+    Ok(status)
 }
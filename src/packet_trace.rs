@@ -0,0 +1,191 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use crate::logging;
+use crate::reader::GitPacketLine;
+
+/* Where --trace-packets writes, unset when not tracing */
+static OUTPUT: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+
+/* How much of a packet's payload is shown */
+const PREVIEW_LEN: usize = 120;
+
+/* "-" traces to stderr, keeping stdout for the command's output */
+pub fn set_output(path: &Path) -> io::Result<()> {
+    let out: Box<dyn Write + Send> = if path == Path::new("-") {
+        Box::new(io::stderr())
+    } else {
+        Box::new(File::create(path)?)
+    };
+    OUTPUT.set(Mutex::new(out)).ok();
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    OUTPUT.get().is_some()
+}
+
+fn write_line(line: &str) {
+    if let Some(out) = OUTPUT.get() {
+        let line = match logging::current_repo() {
+            Some(repo) => format!("{}: {}\n", repo, line),
+            None => format!("{}\n", line),
+        };
+        let _ = out.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Direction {
+    Sent,
+    Received,
+}
+
+/* One packet as traced: direction, length as on the wire and payload.
+ * Within a packfile section the sideband pack data isn't shown. */
+fn describe(direction: Direction, pkt: &GitPacketLine, in_pack: bool) -> String {
+    let marker = match direction {
+        Direction::Sent => '>',
+        Direction::Received => '<',
+    };
+    match pkt {
+        GitPacketLine::Flush => format!("{} 0000", marker),
+        GitPacketLine::Delimiter => format!("{} 0001", marker),
+        GitPacketLine::ResponseEnd => format!("{} 0002", marker),
+        GitPacketLine::Data(data) if in_pack && data.first() == Some(&1) => format!(
+            "{} {:04x} {} bytes of pack data",
+            marker,
+            data.len() + 4,
+            data.len() - 1
+        ),
+        GitPacketLine::Data(data) => {
+            let shown = &data[..data.len().min(PREVIEW_LEN)];
+            format!(
+                "{} {:04x} {}{}",
+                marker,
+                data.len() + 4,
+                shown.escape_ascii(),
+                if shown.len() < data.len() { "..." } else { "" }
+            )
+        }
+    }
+}
+
+/* Follows a response, so that pack data is recognized */
+#[derive(Default)]
+pub struct PacketTracer {
+    in_pack: bool,
+}
+
+impl PacketTracer {
+    pub fn received(&mut self, pkt: &GitPacketLine) {
+        write_line(&describe(Direction::Received, pkt, self.in_pack));
+        match pkt {
+            GitPacketLine::Data(data)
+                if data.strip_suffix(b"\n").unwrap_or(data) == b"packfile" =>
+            {
+                self.in_pack = true
+            }
+            GitPacketLine::Data(_) => {}
+            _ => self.in_pack = false,
+        }
+    }
+}
+
+/* Traces the pkt-lines in `data`, which holds whole lines as encoded by
+ * PktLine */
+pub fn sent(data: &[u8]) {
+    if !enabled() {
+        return;
+    }
+    for pkt in split_lines(data) {
+        write_line(&describe(Direction::Sent, &pkt, false));
+    }
+}
+
+fn split_lines(mut data: &[u8]) -> Vec<GitPacketLine> {
+    let mut lines = Vec::new();
+    while data.len() >= 4 {
+        let len = std::str::from_utf8(&data[..4])
+            .ok()
+            .and_then(|s| usize::from_str_radix(s, 16).ok());
+        let (pkt, consumed) = match len {
+            Some(0) => (GitPacketLine::Flush, 4),
+            Some(1) => (GitPacketLine::Delimiter, 4),
+            Some(2) => (GitPacketLine::ResponseEnd, 4),
+            Some(len) if (4..=data.len()).contains(&len) => {
+                (GitPacketLine::Data(data[4..len].to_vec().into()), len)
+            }
+            _ => break,
+        };
+        lines.push(pkt);
+        data = &data[consumed..];
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pkt_line::PktLine;
+    use bytes::Bytes;
+
+    #[test]
+    fn describes_packets() {
+        let data = GitPacketLine::Data(Bytes::from_static(b"command=ls-refs\n"));
+        assert_eq!(
+            describe(Direction::Sent, &data, false),
+            "> 0014 command=ls-refs\\n"
+        );
+        assert_eq!(
+            describe(Direction::Received, &GitPacketLine::Delimiter, false),
+            "< 0001"
+        );
+
+        let pack = GitPacketLine::Data(Bytes::from_static(b"\x01PACK\0\0\0\x02"));
+        assert_eq!(
+            describe(Direction::Received, &pack, true),
+            "< 000d 8 bytes of pack data"
+        );
+        let progress = GitPacketLine::Data(Bytes::from_static(b"\x02Counting objects\r"));
+        assert_eq!(
+            describe(Direction::Received, &progress, true),
+            "< 0016 \\x02Counting objects\\r"
+        );
+
+        let long = GitPacketLine::Data(vec![b'a'; PREVIEW_LEN + 1].into());
+        assert!(describe(Direction::Received, &long, false).ends_with("aaa..."));
+    }
+
+    #[test]
+    fn splits_encoded_lines() {
+        let data = PktLine::new()
+            .add_str("command=fetch")
+            .delimit()
+            .add_str("done\n")
+            .flush()
+            .take();
+        assert_eq!(
+            split_lines(&data),
+            vec![
+                GitPacketLine::Data(Bytes::from_static(b"command=fetch")),
+                GitPacketLine::Delimiter,
+                GitPacketLine::Data(Bytes::from_static(b"done\n")),
+                GitPacketLine::Flush,
+            ]
+        );
+    }
+
+    #[test]
+    fn tracer_follows_packfile_section() {
+        let mut tracer = PacketTracer::default();
+        tracer.received(&GitPacketLine::Data(Bytes::from_static(b"packfile\n")));
+        assert!(tracer.in_pack);
+        tracer.received(&GitPacketLine::Data(Bytes::from_static(b"\x01PACK")));
+        assert!(tracer.in_pack);
+        tracer.received(&GitPacketLine::Flush);
+        assert!(!tracer.in_pack);
+    }
+}
@@ -1,5 +1,20 @@
+use std::fmt;
+use std::future::Future;
 use std::io::Write;
+use std::mem;
 
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::SinkExt;
+
+/* The longest pkt-line git allows, including the 4 byte length */
+pub const MAX_PKT_LEN: usize = 65520;
+pub const MAX_PKT_PAYLOAD: usize = MAX_PKT_LEN - 4;
+
+/* How much encoded data a PktLineSink collects before passing it on */
+const SINK_CHUNK_LEN: usize = 64 * 1024;
+
+#[derive(Default)]
 pub struct PktLine {
     data: Vec<u8>,
 }
@@ -9,12 +24,55 @@ impl PktLine {
         PktLine { data: vec![] }
     }
 
+    /* Builder style, like the other add_* methods */
+    #[allow(clippy::should_implement_trait)]
     pub fn add(mut self, data: &[u8]) -> Self {
+        assert!(
+            data.len() <= MAX_PKT_PAYLOAD,
+            "pkt-line payload of {} bytes exceeds the maximum of {}",
+            data.len(),
+            MAX_PKT_PAYLOAD
+        );
         write!(self.data, "{:04x}", data.len() + 4).unwrap();
         self.data.extend_from_slice(data);
         self
     }
 
+    pub fn add_str(self, data: &str) -> Self {
+        self.add(data.as_bytes())
+    }
+
+    /* Formats straight into the buffer, for format_args!() */
+    pub fn add_fmt(mut self, args: fmt::Arguments) -> Self {
+        let start = self.data.len();
+        self.data.extend_from_slice(b"0000");
+        self.data.write_fmt(args).unwrap();
+        let len = self.data.len() - start;
+        assert!(
+            len <= MAX_PKT_LEN,
+            "pkt-line payload of {} bytes exceeds the maximum of {}",
+            len - 4,
+            MAX_PKT_PAYLOAD
+        );
+        write!(&mut self.data[start..start + 4], "{:04x}", len).unwrap();
+        self
+    }
+
+    /* Adds `data` as as many pkt-lines as it takes. With a separator the
+     * lines end right after one where possible. */
+    #[allow(dead_code)] /* nothing sends payloads this large yet */
+    pub fn add_chunked(mut self, mut data: &[u8], separator: Option<u8>) -> Self {
+        while data.len() > MAX_PKT_PAYLOAD {
+            let split = separator
+                .and_then(|sep| data[..MAX_PKT_PAYLOAD].iter().rposition(|&b| b == sep))
+                .map(|pos| pos + 1)
+                .unwrap_or(MAX_PKT_PAYLOAD);
+            self = self.add(&data[..split]);
+            data = &data[split..];
+        }
+        self.add(data)
+    }
+
     pub fn flush(mut self) -> Self {
         self.data.extend_from_slice(b"0000");
         self
@@ -28,9 +86,83 @@ impl PktLine {
     }
 }
 
+/* The receiving end of a PktLineSink */
+pub type PktLineBody = mpsc::Receiver<Bytes>;
+
+/* Encodes the same way as PktLine, but passes the encoded data on in
+ * chunks while lines are still being added, e.g. to a request in flight.
+ * Once the PktLineBody is gone (the request failed) lines are dropped. */
+pub struct PktLineSink {
+    pkt: PktLine,
+    tx: mpsc::Sender<Bytes>,
+}
+
+impl PktLineSink {
+    pub fn channel() -> (PktLineSink, PktLineBody) {
+        let (tx, rx) = mpsc::channel(1);
+        (
+            PktLineSink {
+                pkt: PktLine::new(),
+                tx,
+            },
+            rx,
+        )
+    }
+
+    fn encode(&mut self, f: impl FnOnce(PktLine) -> PktLine) {
+        self.pkt = f(mem::replace(&mut self.pkt, PktLine::new()));
+    }
+
+    async fn send(&mut self) {
+        let data = mem::replace(&mut self.pkt, PktLine::new()).take();
+        if !data.is_empty() && !self.tx.is_closed() {
+            self.tx.send(Bytes::from(data)).await.ok();
+        }
+    }
+
+    async fn send_full(&mut self) {
+        if self.pkt.data.len() >= SINK_CHUNK_LEN {
+            self.send().await;
+        }
+    }
+
+    pub async fn add(&mut self, data: &[u8]) {
+        self.encode(|pkt| pkt.add(data));
+        self.send_full().await
+    }
+
+    pub async fn add_str(&mut self, data: &str) {
+        self.add(data.as_bytes()).await
+    }
+
+    /* fmt::Arguments isn't Send, so it's encoded before the returned
+     * future is created */
+    pub fn add_fmt(&mut self, args: fmt::Arguments) -> impl Future<Output = ()> + '_ {
+        self.encode(|pkt| pkt.add_fmt(args));
+        self.send_full()
+    }
+
+    pub async fn flush(&mut self) {
+        self.encode(PktLine::flush);
+        self.send_full().await
+    }
+
+    pub async fn delimit(&mut self) {
+        self.encode(PktLine::delimit);
+        self.send_full().await
+    }
+
+    /* Sends what's left, the PktLineBody ends after that */
+    pub async fn finish(mut self) {
+        self.send().await
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures::executor::block_on;
+    use futures::StreamExt;
 
     #[test]
     fn test() {
@@ -49,4 +181,89 @@ mod tests {
             b"0005x00010008abcd0000"
         );
     }
+
+    #[test]
+    fn add_str_and_fmt() {
+        let sha = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(
+            PktLine::new()
+                .add_str("done\n")
+                .add_fmt(format_args!("want {}", sha))
+                .take(),
+            PktLine::new()
+                .add(b"done\n")
+                .add(format!("want {}", sha).as_bytes())
+                .take()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds the maximum")]
+    fn oversized_fmt_payload() {
+        let long = "x".repeat(MAX_PKT_PAYLOAD);
+        PktLine::new().add_fmt(format_args!("{}!", long));
+    }
+
+    #[test]
+    fn longest_payload() {
+        let pkt = PktLine::new().add(&[b'x'; MAX_PKT_PAYLOAD]).take();
+        assert_eq!(&pkt[..4], b"fff0");
+        assert_eq!(pkt.len(), MAX_PKT_LEN);
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds the maximum")]
+    fn oversized_payload() {
+        PktLine::new().add(&[b'x'; MAX_PKT_PAYLOAD + 1]);
+    }
+
+    fn logical_lines() -> Vec<String> {
+        (0..5000)
+            .map(|i| format!("have {:040x}\n", i * 7919))
+            .collect()
+    }
+
+    #[test]
+    fn sink_matches_buffered() {
+        let lines = logical_lines();
+
+        let mut buffered = PktLine::new().add_str("command=fetch").delimit();
+        for line in &lines {
+            buffered = buffered.add_str(line);
+        }
+        let buffered = buffered.add(b"done\n").flush().take();
+
+        let (mut sink, body) = PktLineSink::channel();
+        let write = async {
+            sink.add_str("command=fetch").await;
+            sink.delimit().await;
+            for (i, line) in lines.iter().enumerate() {
+                if i % 2 == 0 {
+                    sink.add_str(line).await;
+                } else {
+                    sink.add_fmt(format_args!("{}", line)).await;
+                }
+            }
+            sink.add(b"done\n").await;
+            sink.flush().await;
+            sink.finish().await;
+        };
+        let ((), chunks) = block_on(async { futures::join!(write, body.collect::<Vec<_>>()) });
+
+        /* Large enough to be passed on in several chunks */
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), buffered);
+    }
+
+    #[test]
+    fn sink_drops_lines_without_body() {
+        let (mut sink, body) = PktLineSink::channel();
+        drop(body);
+        block_on(async {
+            for line in logical_lines() {
+                sink.add_str(&line).await;
+            }
+            sink.finish().await;
+        });
+    }
 }
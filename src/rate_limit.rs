@@ -0,0 +1,111 @@
+//! A cap on how fast pack data is received, shared by all fetches
+//! using the same [`RateLimit`]
+
+use std::num::NonZeroU64;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Bytes per second, optionally with a K, M or G suffix (powers of 1024)
+/// like curl's `--limit-rate`
+pub fn parse_rate(s: &str) -> Result<NonZeroU64, String> {
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+        _ => (s, 'B'),
+    };
+    let multiplier: u64 = match unit {
+        'B' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => return Err(format!("Unknown unit '{}' in '{}'", unit, s)),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .and_then(NonZeroU64::new)
+        .ok_or_else(|| format!("Not a rate in bytes per second: '{}'", s))
+}
+
+/* A token bucket holding up to a second's worth of bytes. Receiving more
+ * than there are tokens goes into debt, which is waited off. */
+#[derive(Debug)]
+pub struct RateLimit {
+    bytes_per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_sec: NonZeroU64) -> Self {
+        let bytes_per_sec = bytes_per_sec.get() as f64;
+        RateLimit {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /* Waits until `bytes` more are within the limit, returning how long */
+    pub async fn take(&self, bytes: usize) -> Duration {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.updated).as_secs_f64() * self.bytes_per_sec;
+            bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_sec);
+            bucket.updated = now;
+            bucket.tokens -= bytes as f64;
+            Duration::from_secs_f64((-bucket.tokens / self.bytes_per_sec).max(0.0))
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rate() {
+        assert_eq!(parse_rate("1500").unwrap().get(), 1500);
+        assert_eq!(parse_rate("100k").unwrap().get(), 100 * 1024);
+        assert_eq!(parse_rate("2M").unwrap().get(), 2 * 1024 * 1024);
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("10X").is_err());
+        assert!(parse_rate("M").is_err());
+    }
+
+    #[tokio::test]
+    async fn limits_rate() {
+        let limit = RateLimit::new(NonZeroU64::new(1_000_000).unwrap());
+        let start = Instant::now();
+        /* A second's worth is there right away */
+        for _ in 0..10 {
+            assert!(limit.take(100_000).await.is_zero());
+        }
+        let mut waited = Duration::ZERO;
+        for _ in 0..5 {
+            waited += limit.take(100_000).await;
+        }
+        let elapsed = start.elapsed();
+        assert!(waited >= Duration::from_millis(400), "{:?}", waited);
+        assert!(
+            elapsed >= Duration::from_millis(400) && elapsed < Duration::from_secs(2),
+            "{:?}",
+            elapsed
+        );
+    }
+}
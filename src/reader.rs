@@ -1,22 +1,82 @@
 use bytes::{Bytes, BytesMut};
 use futures::Stream;
+use futures::StreamExt;
+use log::{debug, warn};
 use std::{
-    io,
+    error::Error,
+    fmt, io,
     pin::Pin,
     task::{Context, Poll},
 };
 
-#[derive(Debug, PartialEq)]
+use crate::packet_trace::{self, PacketTracer};
+use crate::pkt_line::MAX_PKT_LEN;
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum GitPacketLine {
     Data(Bytes),
     Flush,
     Delimiter,
+    /* 0002, ends a protocol v2 response */
+    ResponseEnd,
+}
+
+/* How much of the data after a response is shown in the log */
+const TRAILING_PREVIEW_LEN: usize = 64;
+
+/* What went wrong reading packets, with the offset into the stream */
+#[derive(Debug)]
+pub enum PacketError {
+    /* The length isn't 4 hex digits */
+    InvalidLength { bytes: [u8; 4], offset: u64 },
+    /* A length no packet can have */
+    InvalidFrame { len: usize, offset: u64 },
+    /* The stream ended within a packet */
+    UnexpectedEof { offset: u64 },
+    Underlying(io::Error),
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PacketError::InvalidLength { bytes, offset } => write!(
+                f,
+                "Invalid pkt-line length {:?} at byte {}",
+                String::from_utf8_lossy(bytes),
+                offset
+            ),
+            PacketError::InvalidFrame { len, offset } if *len > MAX_PKT_LEN => write!(
+                f,
+                "pkt-line length {} at byte {} exceeds the maximum of {}",
+                len, offset, MAX_PKT_LEN
+            ),
+            PacketError::InvalidFrame { len, offset } => {
+                write!(f, "Invalid pkt-line length {} at byte {}", len, offset)
+            }
+            PacketError::UnexpectedEof { offset } => {
+                write!(f, "Unexpected end of data in a pkt-line at byte {}", offset)
+            }
+            PacketError::Underlying(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for PacketError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PacketError::Underlying(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 pub struct GitPacketLineStream<S> {
     inner: S,
     buffer: BytesMut,
     len: Option<usize>,
+    /* Where in the stream the buffer starts */
+    offset: u64,
+    tracer: Option<PacketTracer>,
 }
 
 impl<S> GitPacketLineStream<S> {
@@ -25,8 +85,18 @@ impl<S> GitPacketLineStream<S> {
             inner,
             buffer: BytesMut::new(),
             len: None,
+            offset: 0,
+            tracer: None,
         }
     }
+
+    /* Traces the packets read with --trace-packets */
+    pub fn traced(mut self) -> Self {
+        if packet_trace::enabled() {
+            self.tracer = Some(PacketTracer::default());
+        }
+        self
+    }
 }
 
 impl<S, E> Stream for GitPacketLineStream<S>
@@ -34,59 +104,87 @@ where
     S: Stream<Item = Result<Bytes, E>> + Unpin,
     E: Into<std::io::Error>,
 {
-    type Item = Result<GitPacketLine, io::Error>;
+    type Item = Result<GitPacketLine, PacketError>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
+        let res = this.poll_packet(cx);
+        if let (Some(tracer), Poll::Ready(Some(Ok(pkt)))) = (&mut this.tracer, &res) {
+            tracer.received(pkt);
+        }
+        res
+    }
+}
+
+impl<S, E> GitPacketLineStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<std::io::Error>,
+{
+    fn poll_packet(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<GitPacketLine, PacketError>>> {
+        let this = self;
 
         loop {
             match this.len {
                 None if this.buffer.len() >= 4 => {
-                    match usize::from_str_radix(
-                        std::str::from_utf8(&this.buffer.split_to(4)).map_err(|_| {
-                            io::Error::new(io::ErrorKind::InvalidData, "Invalid hex length")
-                        })?,
-                        16,
-                    ) {
-                        Ok(0) => {
+                    let header = this.buffer.split_to(4);
+                    let offset = this.offset;
+                    this.offset += 4;
+                    /* from_str_radix would accept a leading '+' */
+                    let len = std::str::from_utf8(&header)
+                        .ok()
+                        .filter(|s| s.bytes().all(|b| b.is_ascii_hexdigit()))
+                        .and_then(|s| usize::from_str_radix(s, 16).ok());
+                    match len {
+                        Some(0) => {
                             return Poll::Ready(Some(Ok(GitPacketLine::Flush)));
                         }
-                        Ok(1) => {
+                        Some(1) => {
                             return Poll::Ready(Some(Ok(GitPacketLine::Delimiter)));
                         }
-                        Ok(n) if n >= 4 => {
+                        Some(2) => {
+                            return Poll::Ready(Some(Ok(GitPacketLine::ResponseEnd)));
+                        }
+                        Some(n) if (4..=MAX_PKT_LEN).contains(&n) => {
                             this.len = Some(n - 4);
                         }
-                        Ok(_) => {
-                            return Poll::Ready(Some(Err(io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                "Invalid frame",
-                            ))));
+                        Some(len) => {
+                            return Poll::Ready(Some(Err(PacketError::InvalidFrame {
+                                len,
+                                offset,
+                            })));
                         }
-                        Err(_) => {
-                            return Poll::Ready(Some(Err(io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                "Invalid hex length",
-                            ))));
+                        None => {
+                            return Poll::Ready(Some(Err(PacketError::InvalidLength {
+                                bytes: [header[0], header[1], header[2], header[3]],
+                                offset,
+                            })));
                         }
                     }
                 }
                 Some(n) if this.buffer.len() >= n => {
                     let data = this.buffer.split_to(n);
+                    this.offset += n as u64;
                     this.len = None;
                     return Poll::Ready(Some(Ok(GitPacketLine::Data(data.into()))));
                 }
                 _ => match Pin::new(&mut this.inner).poll_next(cx) {
                     Poll::Ready(Some(Ok(chunk))) => this.buffer.extend_from_slice(&chunk),
-                    Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e.into()))),
+                    Poll::Ready(Some(Err(e))) => {
+                        return Poll::Ready(Some(Err(PacketError::Underlying(e.into()))))
+                    }
                     Poll::Ready(None) => {
-                        if this.buffer.is_empty() {
+                        if this.buffer.is_empty() && this.len.is_none() {
                             return Poll::Ready(None);
                         } else {
-                            return Poll::Ready(Some(Err(io::Error::new(
-                                io::ErrorKind::UnexpectedEof,
-                                "Unexpected EOF in packet line",
-                            ))));
+                            let offset = this.offset + this.buffer.len() as u64;
+                            /* Reported once, the stream ends after it */
+                            this.buffer.clear();
+                            this.len = None;
+                            return Poll::Ready(Some(Err(PacketError::UnexpectedEof { offset })));
                         }
                     }
                     Poll::Pending => return Poll::Pending,
@@ -96,6 +194,37 @@ where
     }
 }
 
+impl<S, E> GitPacketLineStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<std::io::Error>,
+{
+    /* Ends a response once its last packet was read. Anything the server
+     * (or a proxy) sends after it is drained and logged, not parsed. */
+    pub async fn finish(&mut self) {
+        let mut preview = std::mem::take(&mut self.buffer);
+        let mut total = preview.len();
+        self.len = None;
+        while let Some(chunk) = self.inner.next().await {
+            let Ok(chunk) = chunk else {
+                break;
+            };
+            total += chunk.len();
+            if preview.len() < TRAILING_PREVIEW_LEN {
+                let n = chunk.len().min(TRAILING_PREVIEW_LEN - preview.len());
+                preview.extend_from_slice(&chunk[..n]);
+            }
+        }
+        if total > 0 {
+            preview.truncate(TRAILING_PREVIEW_LEN);
+            debug!(
+                "Ignoring {} bytes after the end of the response: {:?}",
+                total, preview
+            );
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum SideBand {
     PackData(Bytes),
@@ -104,6 +233,78 @@ pub enum SideBand {
     Unknown(Bytes),
 }
 
+/* The sideband multiplexed packfile section of a fetch response, up to
+ * the flush that ends it */
+pub struct SideBandStream<S> {
+    inner: S,
+    pack_bytes: u64,
+    done: bool,
+}
+
+impl<S> SideBandStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            pack_bytes: 0,
+            done: false,
+        }
+    }
+
+    /* How much pack data was received so far */
+    pub fn pack_bytes(&self) -> u64 {
+        self.pack_bytes
+    }
+}
+
+impl<S> Stream for SideBandStream<S>
+where
+    S: Stream<Item = Result<GitPacketLine, PacketError>> + Unpin,
+{
+    type Item = Result<SideBand, PacketError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        let pkt = match Pin::new(&mut this.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(pkt))) => pkt,
+            Poll::Ready(Some(Err(e))) => {
+                this.done = true;
+                return Poll::Ready(Some(Err(e)));
+            }
+            Poll::Ready(None) => {
+                this.done = true;
+                return Poll::Ready(Some(Err(PacketError::Underlying(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Packfile ended without a flush",
+                )))));
+            }
+            Poll::Pending => return Poll::Pending,
+        };
+
+        match pkt {
+            GitPacketLine::Data(data) => {
+                let band = SideBand::from(data);
+                if let SideBand::PackData(payload) = &band {
+                    this.pack_bytes += payload.len() as u64;
+                }
+                Poll::Ready(Some(Ok(band)))
+            }
+            GitPacketLine::Flush | GitPacketLine::ResponseEnd => {
+                this.done = true;
+                Poll::Ready(None)
+            }
+            GitPacketLine::Delimiter => {
+                warn!("Unexpected delimiter");
+                this.done = true;
+                Poll::Ready(None)
+            }
+        }
+    }
+}
+
 impl From<Bytes> for SideBand {
     fn from(data: bytes::Bytes) -> Self {
         if data.is_empty() {
@@ -210,6 +411,61 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_sideband_stream() {
+        let pkt = PktLine::new()
+            .add(b"\x02counting\n")
+            .add(b"\x01PACK")
+            .add(b"\x01data")
+            .flush()
+            .add(b"after")
+            .take();
+        let data = vec![pkt.as_ref()];
+        let mut packets = GitPacketLineStream::new(make_stream(&data));
+        let mut sideband = SideBandStream::new(&mut packets);
+
+        let mut pack = Vec::new();
+        let mut progress = Vec::new();
+        while let Some(band) = sideband.next().await {
+            match band.unwrap() {
+                SideBand::PackData(d) => pack.extend_from_slice(&d),
+                SideBand::Progress(p) => progress.push(p),
+                other => panic!("Unexpected {:?}", other),
+            }
+        }
+        assert_eq!(pack, b"PACKdata");
+        assert_eq!(progress, ["counting\n"]);
+        assert_eq!(sideband.pack_bytes(), 8);
+
+        /* The rest of the response is left alone */
+        assert_eq!(
+            packets.next().await.unwrap().unwrap(),
+            GitPacketLine::Data(Bytes::from("after"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sideband_stream_errors() {
+        let data = vec![b"0009\x01PACK000a\x01da".as_ref()];
+        let mut packets = GitPacketLineStream::new(make_stream(&data));
+        let mut sideband = SideBandStream::new(&mut packets);
+        assert!(sideband.next().await.unwrap().is_ok());
+        let err = sideband.next().await.unwrap().unwrap_err();
+        assert!(
+            matches!(err, PacketError::UnexpectedEof { offset: 16 }),
+            "{:?}",
+            err
+        );
+        assert!(sideband.next().await.is_none());
+
+        /* Ending without the flush isn't a complete pack either */
+        let data = vec![b"0009\x01PACK".as_ref()];
+        let mut packets = GitPacketLineStream::new(make_stream(&data));
+        let mut sideband = SideBandStream::new(&mut packets);
+        assert!(sideband.next().await.unwrap().is_ok());
+        assert!(sideband.next().await.unwrap().is_err());
+    }
+
     #[tokio::test]
     async fn test_invalid_length_non_hex() {
         let data = vec![b"zzzzfoobar".as_ref()];
@@ -219,11 +475,24 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_too_short_length_0002() {
+    async fn test_response_end_0002() {
         let data = vec![b"0002".as_ref()];
         let mut stream = GitPacketLineStream::new(make_stream(&data));
-        let result = stream.next().await;
-        assert!(result.unwrap().is_err());
+        let result = stream.next().await.unwrap().unwrap();
+        assert_eq!(result, GitPacketLine::ResponseEnd);
+    }
+
+    #[tokio::test]
+    async fn test_finish_drains_trailing_data() {
+        let data = vec![b"0007foo0000zz".as_ref(), b"garbage".as_ref()];
+        let mut stream = GitPacketLineStream::new(make_stream(&data));
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            GitPacketLine::Data(Bytes::from("foo"))
+        );
+        assert_eq!(stream.next().await.unwrap().unwrap(), GitPacketLine::Flush);
+        stream.finish().await;
+        assert!(stream.next().await.is_none());
     }
 
     #[tokio::test]
@@ -234,6 +503,36 @@ mod tests {
         assert!(result.unwrap().is_err());
     }
 
+    #[tokio::test]
+    async fn test_longest_length_65520() {
+        let payload = vec![b'x'; 65516];
+        let pkt = [b"fff0".as_ref(), &payload].concat();
+        let data = vec![pkt.as_ref()];
+        let mut stream = GitPacketLineStream::new(make_stream(&data));
+        let result = stream.next().await.unwrap().unwrap();
+        assert_eq!(result, GitPacketLine::Data(Bytes::from(payload)));
+    }
+
+    #[tokio::test]
+    async fn test_too_long_length_65521() {
+        /* Rejected right away instead of waiting for the data */
+        let data = vec![b"fff1".as_ref()];
+        let mut stream = GitPacketLineStream::new(make_stream(&data));
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert!(
+            matches!(
+                err,
+                PacketError::InvalidFrame {
+                    len: 65521,
+                    offset: 0
+                }
+            ),
+            "{:?}",
+            err
+        );
+        assert!(err.to_string().contains("65521"), "{}", err);
+    }
+
     #[tokio::test]
     async fn test_unexpected_eof() {
         let data = vec![b"000afoo".as_ref()];
@@ -242,6 +541,76 @@ mod tests {
         assert!(result.unwrap().is_err());
     }
 
+    #[tokio::test]
+    async fn test_errors_have_offsets() {
+        let data = vec![b"0007fo".as_ref(), b"o00000+0a".as_ref()];
+        let mut stream = GitPacketLineStream::new(make_stream(&data));
+        assert!(stream.next().await.unwrap().is_ok());
+        assert!(stream.next().await.unwrap().is_ok());
+        match stream.next().await.unwrap().unwrap_err() {
+            PacketError::InvalidLength { bytes, offset } => {
+                assert_eq!(&bytes, b"0+0a");
+                assert_eq!(offset, 11);
+            }
+            e => panic!("Unexpected {:?}", e),
+        }
+
+        /* Nothing of the data arrived yet */
+        let data = vec![b"0004000a".as_ref()];
+        let mut stream = GitPacketLineStream::new(make_stream(&data));
+        assert!(stream.next().await.unwrap().is_ok());
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert!(
+            matches!(err, PacketError::UnexpectedEof { offset: 8 }),
+            "{:?}",
+            err
+        );
+        assert!(stream.next().await.is_none());
+    }
+
+    async fn collect_data(pkt: Vec<u8>) -> Vec<Bytes> {
+        let data = vec![pkt.as_ref()];
+        GitPacketLineStream::new(make_stream(&data))
+            .map(|x| match x.unwrap() {
+                GitPacketLine::Data(d) => d,
+                other => panic!("Unexpected {:?}", other),
+            })
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_chunked_round_trip() {
+        let payload: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let lines = collect_data(PktLine::new().add_chunked(&payload, None).take()).await;
+
+        assert_eq!(lines.len(), 4);
+        assert!(lines.iter().all(|l| l.len() <= MAX_PKT_LEN - 4));
+        assert_eq!(lines.concat(), payload);
+    }
+
+    #[tokio::test]
+    async fn test_chunked_at_separator() {
+        let words: Vec<String> = (0..20_000).map(|i| format!("word{}", i)).collect();
+        let payload = words.join(" ");
+        let lines = collect_data(
+            PktLine::new()
+                .add_chunked(payload.as_bytes(), Some(b' '))
+                .take(),
+        )
+        .await;
+
+        assert!(lines.len() > 1);
+        for line in &lines[..lines.len() - 1] {
+            assert!(line.ends_with(b" "));
+        }
+        assert_eq!(lines.concat(), payload.as_bytes());
+
+        /* Short data stays a single line */
+        let lines = collect_data(PktLine::new().add_chunked(b"a b", Some(b' ')).take()).await;
+        assert_eq!(lines, [Bytes::from("a b")]);
+    }
+
     #[tokio::test]
     async fn test_multiple_packet_lines_builder() {
         let pkt = PktLine::new()
@@ -268,3 +637,91 @@ mod tests {
         );
     }
 }
+
+#[cfg(test)]
+mod proptests {
+    use super::*;
+    use crate::pkt_line::PktLine;
+    use futures::{executor::block_on, stream};
+    use proptest::prelude::*;
+
+    fn packet_line() -> impl Strategy<Value = GitPacketLine> {
+        prop_oneof![
+            4 => prop::collection::vec(any::<u8>(), 0..300)
+                .prop_map(|d| GitPacketLine::Data(Bytes::from(d))),
+            1 => Just(GitPacketLine::Flush),
+            1 => Just(GitPacketLine::Delimiter),
+        ]
+    }
+
+    fn encode(lines: &[GitPacketLine]) -> Vec<u8> {
+        lines
+            .iter()
+            .fold(PktLine::new(), |pkt, line| match line {
+                GitPacketLine::Data(d) => pkt.add(d),
+                GitPacketLine::Flush => pkt.flush(),
+                GitPacketLine::Delimiter => pkt.delimit(),
+                GitPacketLine::ResponseEnd => unreachable!(),
+            })
+            .take()
+    }
+
+    /* Cuts `data` into chunks of the given sizes (the last size repeating),
+     * 0 makes an empty chunk */
+    fn rechunk(data: &[u8], sizes: &[usize]) -> Vec<Result<Bytes, io::Error>> {
+        let mut chunks = Vec::new();
+        let mut rest = data;
+        let mut sizes = sizes.iter().chain(std::iter::repeat(sizes.last().unwrap()));
+        while !rest.is_empty() {
+            let n = (*sizes.next().unwrap()).min(rest.len());
+            chunks.push(Ok(Bytes::copy_from_slice(&rest[..n])));
+            rest = &rest[n..];
+        }
+        chunks
+    }
+
+    fn decode(chunks: Vec<Result<Bytes, io::Error>>) -> Vec<GitPacketLine> {
+        block_on(
+            GitPacketLineStream::new(stream::iter(chunks))
+                .map(|pkt| pkt.unwrap())
+                .collect(),
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn any_chunking_decodes_the_same(
+            lines in prop::collection::vec(packet_line(), 0..20),
+            sizes in prop::collection::vec(0..12usize, 1..40),
+        ) {
+            let data = encode(&lines);
+            /* At least one byte per chunk at the end, so it terminates */
+            let mut sizes = sizes;
+            sizes.push(1);
+            prop_assert_eq!(decode(rechunk(&data, &sizes)), lines);
+        }
+
+        #[test]
+        fn single_byte_chunks_decode_the_same(
+            lines in prop::collection::vec(packet_line(), 0..20),
+        ) {
+            let data = encode(&lines);
+            prop_assert_eq!(decode(rechunk(&data, &[1])), lines);
+        }
+
+        #[test]
+        fn empty_chunks_are_ignored(
+            lines in prop::collection::vec(packet_line(), 0..10),
+            empties in 1..5usize,
+        ) {
+            let data = encode(&lines);
+            let mut chunks: Vec<Result<Bytes, io::Error>> = Vec::new();
+            for chunk in rechunk(&data, &[3]) {
+                chunks.extend((0..empties).map(|_| Ok(Bytes::new())));
+                chunks.push(chunk);
+            }
+            chunks.push(Ok(Bytes::new()));
+            prop_assert_eq!(decode(chunks), lines);
+        }
+    }
+}
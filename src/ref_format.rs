@@ -0,0 +1,225 @@
+use std::error::Error;
+use std::fmt;
+
+/* Object names and refnames from the server end up as git arguments and
+ * in files under .git, so only well formed ones are accepted */
+#[derive(Debug, PartialEq)]
+pub enum InvalidRef {
+    Sha(String),
+    /* The escaped refname and the rule it breaks */
+    Refname(String, &'static str),
+}
+
+impl fmt::Display for InvalidRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidRef::Sha(raw) => write!(f, "Invalid object name \"{}\"", raw),
+            InvalidRef::Refname(raw, reason) => {
+                write!(f, "Invalid refname \"{}\": {}", raw, reason)
+            }
+        }
+    }
+}
+
+impl Error for InvalidRef {}
+
+fn escaped(raw: &[u8]) -> String {
+    raw.escape_ascii().to_string()
+}
+
+/* 40 (sha1) or 64 (sha256) lowercase hex digits */
+pub fn check_sha(raw: &[u8]) -> Result<String, InvalidRef> {
+    let valid =
+        matches!(raw.len(), 40 | 64) && raw.iter().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    if valid {
+        Ok(String::from_utf8_lossy(raw).into_owned())
+    } else {
+        Err(InvalidRef::Sha(escaped(raw)))
+    }
+}
+
+/* Why `name` isn't a valid refname, following `git check-ref-format`
+ * (with --allow-onelevel, ls-refs can return HEAD). A leading '-' is
+ * refused too, the name would be taken for an option. */
+fn refname_problem(name: &[u8]) -> Option<&'static str> {
+    if name.is_empty() {
+        return Some("empty");
+    }
+    if name == b"@" {
+        return Some("is '@'");
+    }
+    if name[0] == b'-' {
+        return Some("starts with '-'");
+    }
+    if name.ends_with(b".") {
+        return Some("ends with '.'");
+    }
+    if std::str::from_utf8(name).is_err() {
+        return Some("not UTF-8");
+    }
+    if name
+        .iter()
+        .any(|b| b.is_ascii_control() || b" ~^:?*[\\".contains(b))
+    {
+        return Some("contains a control character, space or one of ~^:?*[\\");
+    }
+    if name.windows(2).any(|w| w == b".." || w == b"@{") {
+        return Some("contains '..' or '@{'");
+    }
+    for component in name.split(|&b| b == b'/') {
+        if component.is_empty() {
+            return Some("has an empty path component");
+        }
+        if component[0] == b'.' {
+            return Some("has a path component starting with '.'");
+        }
+        if component.ends_with(b".lock") {
+            return Some("has a path component ending with '.lock'");
+        }
+    }
+    None
+}
+
+pub fn check_refname(raw: &[u8]) -> Result<String, InvalidRef> {
+    match refname_problem(raw) {
+        None => Ok(String::from_utf8_lossy(raw).into_owned()),
+        Some(reason) => Err(InvalidRef::Refname(escaped(raw), reason)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn valid_shas() {
+        let sha1 = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(check_sha(sha1.as_bytes()).unwrap(), sha1);
+        let sha256 = "ab".repeat(32);
+        assert_eq!(check_sha(sha256.as_bytes()).unwrap(), sha256);
+    }
+
+    #[test]
+    fn invalid_shas() {
+        for raw in [
+            &b""[..],
+            b"0123456789abcdef0123456789abcdef0123456",
+            b"0123456789abcdef0123456789abcdef012345678",
+            b"0123456789ABCDEF0123456789ABCDEF01234567",
+            b"0123456789abcdef0123456789abcdef0123456g",
+            b"--upload-pack=touch /tmp/pwned0123456789",
+            b"0123456789abcdef0123456789abcdef0123456 ",
+            b"0123456789abcdef0123456789abcdef0123456\n",
+            b"0123456789abcdef0123456789abcdef0123456\0",
+            b"0123456789abcdef0123456789abcdef0123456\xff",
+        ] {
+            assert!(
+                check_sha(raw).is_err(),
+                "{:?}",
+                raw.escape_ascii().to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn valid_refnames() {
+        for name in [
+            "HEAD",
+            "refs/heads/main",
+            "refs/heads/feature/x-1.2",
+            "refs/tags/v1.0",
+            "refs/tags/v1.0-rc.1+build",
+            "refs/heads/ünïcode",
+            "refs/heads/a@b",
+            "refs/heads/has-dash",
+        ] {
+            assert_eq!(check_refname(name.as_bytes()).unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn invalid_refnames() {
+        for raw in [
+            &b""[..],
+            b"@",
+            b"-rf",
+            b"--upload-pack=evil",
+            b"refs/heads/a b",
+            b"refs/heads/a\tb",
+            b"refs/heads/a\nrefs/heads/b",
+            b"refs/heads/a\0b",
+            b"refs/heads/a\x7fb",
+            b"refs/heads/a..b",
+            b"refs/heads/../../config",
+            b"refs/heads/.hidden",
+            b"refs/heads/a.lock",
+            b"refs/heads/a.lock/b",
+            b"refs/heads/a.",
+            b"refs/heads/a/",
+            b"/refs/heads/a",
+            b"refs//heads/a",
+            b"refs/heads/a@{1}",
+            b"refs/heads/a~1",
+            b"refs/heads/a^",
+            b"refs/heads/a:b",
+            b"refs/heads/a?",
+            b"refs/heads/a*",
+            b"refs/heads/a[b",
+            b"refs/heads/a\\b",
+            b"refs/heads/\xff\xfe",
+        ] {
+            assert!(
+                check_refname(raw).is_err(),
+                "{:?}",
+                raw.escape_ascii().to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn errors_show_escaped_bytes() {
+        assert_eq!(
+            check_refname(b"refs/heads/a\nb\x1b[31m")
+                .unwrap_err()
+                .to_string(),
+            "Invalid refname \"refs/heads/a\\nb\\x1b[31m\": contains a control character, space or one of ~^:?*[\\"
+        );
+        assert_eq!(
+            check_sha(b"-x\0").unwrap_err().to_string(),
+            "Invalid object name \"-x\\x00\""
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn accepted_refnames_are_safe(raw in prop::collection::vec(any::<u8>(), 0..40)) {
+            if let Ok(name) = check_refname(&raw) {
+                prop_assert!(!name.starts_with('-'));
+                prop_assert!(!name.contains(".."));
+                prop_assert!(!name.chars().any(|c| c.is_ascii_control() || c == ' '));
+                prop_assert_eq!(name.as_bytes(), &raw[..]);
+            }
+        }
+
+        #[test]
+        fn nasty_byte_is_rejected(
+            prefix in "[a-z]{1,8}",
+            nasty in prop::sample::select(&b"\0\t\n\r \x7f~^:?*[\\"[..]),
+            suffix in "[a-z]{0,8}",
+        ) {
+            let mut raw = format!("refs/heads/{}", prefix).into_bytes();
+            raw.push(nasty);
+            raw.extend_from_slice(suffix.as_bytes());
+            prop_assert!(check_refname(&raw).is_err());
+        }
+
+        #[test]
+        fn accepted_shas_are_hex(raw in prop::collection::vec(any::<u8>(), 38..66)) {
+            if let Ok(sha) = check_sha(&raw) {
+                prop_assert!(sha.len() == 40 || sha.len() == 64);
+                prop_assert!(sha.bytes().all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase()));
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Remembered ls-refs responses, so the same refs aren't listed twice:
+//! in memory for the life of a [`GitClient`](crate::git_http_client::GitClient),
+//! and optionally on disk for a short while, across runs.
+
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::util;
+use crate::RefInfo;
+
+/// Where responses are kept across runs, and for how long
+#[derive(Clone, Debug)]
+pub struct DiskCache {
+    pub dir: PathBuf,
+    pub ttl: Duration,
+}
+
+/* What was asked: the repository and the ref prefixes */
+pub struct Key {
+    url: String,
+    prefixes: Vec<String>,
+    /* Of the credentials too, they might see other refs */
+    hash: u64,
+}
+
+impl Key {
+    pub fn new<T: AsRef<str>>(
+        url: &Url,
+        username: Option<&str>,
+        password: Option<&str>,
+        prefixes: &[T],
+    ) -> Key {
+        let url = util::anonymous_url(url);
+        let prefixes: Vec<String> = prefixes.iter().map(|p| p.as_ref().to_string()).collect();
+        /* Stable within a build of the command, which is plenty for
+         * entries this short-lived */
+        let mut hasher = DefaultHasher::new();
+        (&url, username, password, &prefixes).hash(&mut hasher);
+        Key {
+            url,
+            prefixes,
+            hash: hasher.finish(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    url: String,
+    prefixes: Vec<String>,
+    refs: Vec<RefInfo>,
+}
+
+pub struct RefsCache {
+    enabled: bool,
+    memory: Mutex<HashMap<u64, Vec<RefInfo>>>,
+    disk: Option<DiskCache>,
+}
+
+impl RefsCache {
+    /// Without `enabled` the refs are listed every time, with `disk`
+    /// they are kept across runs as well
+    pub fn new(enabled: bool, disk: Option<DiskCache>) -> Self {
+        RefsCache {
+            enabled,
+            memory: Mutex::new(HashMap::new()),
+            disk,
+        }
+    }
+
+    fn path(dir: &Path, key: &Key) -> PathBuf {
+        dir.join(format!("{:016x}.json", key.hash))
+    }
+
+    pub async fn get(&self, key: &Key) -> Option<Vec<RefInfo>> {
+        if !self.enabled {
+            return None;
+        }
+        if let Some(refs) = self.memory.lock().unwrap().get(&key.hash) {
+            debug!("Using the refs of {} listed before", key.url);
+            return Some(refs.clone());
+        }
+
+        let disk = self.disk.as_ref()?;
+        let path = Self::path(&disk.dir, key);
+        let age = tokio::fs::metadata(&path)
+            .await
+            .ok()?
+            .modified()
+            .ok()?
+            .elapsed()
+            .unwrap_or_default();
+        if age >= disk.ttl {
+            return None;
+        }
+        let contents = tokio::fs::read(&path).await.ok()?;
+        let entry: Entry = match serde_json::from_slice(&contents) {
+            Ok(entry) => entry,
+            Err(e) => {
+                debug!("Ignoring {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        if entry.url != key.url || entry.prefixes != key.prefixes {
+            return None;
+        }
+        debug!(
+            "Using the refs of {} cached {}s ago",
+            key.url,
+            age.as_secs()
+        );
+        self.memory
+            .lock()
+            .unwrap()
+            .insert(key.hash, entry.refs.clone());
+        Some(entry.refs)
+    }
+
+    /* A cache that can't be written is only warned about */
+    pub async fn put(&self, key: &Key, refs: &[RefInfo]) {
+        if !self.enabled {
+            return;
+        }
+        self.memory.lock().unwrap().insert(key.hash, refs.to_vec());
+
+        let Some(disk) = &self.disk else {
+            return;
+        };
+        let entry = Entry {
+            url: key.url.clone(),
+            prefixes: key.prefixes.clone(),
+            refs: refs.to_vec(),
+        };
+        let path = Self::path(&disk.dir, key);
+        let written = match tokio::fs::create_dir_all(&disk.dir).await {
+            Ok(()) => {
+                let contents = serde_json::to_string(&entry).expect("refs serialize");
+                util::write_atomically(&path, &contents).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            warn!("Could not write {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refs(sha: char) -> Vec<RefInfo> {
+        vec![RefInfo {
+            sha: sha.to_string().repeat(40),
+            refname: "refs/heads/main".to_string(),
+            peeled: None,
+        }]
+    }
+
+    fn key(password: Option<&str>, prefix: &str) -> Key {
+        let url = Url::parse("https://example.com/repo.git").unwrap();
+        Key::new(&url, Some("user"), password, &[prefix])
+    }
+
+    #[tokio::test]
+    async fn remembers_refs() {
+        let dir = tempfile::tempdir().unwrap();
+        let disk = DiskCache {
+            dir: dir.path().join("cache"),
+            ttl: Duration::from_secs(60),
+        };
+        let cache = RefsCache::new(true, Some(disk.clone()));
+        assert!(cache.get(&key(Some("pw"), "refs/heads/")).await.is_none());
+        cache.put(&key(Some("pw"), "refs/heads/"), &refs('a')).await;
+        assert_eq!(
+            cache.get(&key(Some("pw"), "refs/heads/")).await.unwrap()[0].sha,
+            "a".repeat(40)
+        );
+        assert!(cache.get(&key(Some("pw"), "refs/tags/")).await.is_none());
+        assert!(cache
+            .get(&key(Some("other"), "refs/heads/"))
+            .await
+            .is_none());
+
+        /* Another run, from disk */
+        let cache = RefsCache::new(true, Some(disk.clone()));
+        assert!(cache.get(&key(Some("pw"), "refs/heads/")).await.is_some());
+        let contents = std::fs::read_dir(&disk.dir)
+            .unwrap()
+            .map(|e| std::fs::read_to_string(e.unwrap().path()).unwrap())
+            .collect::<String>();
+        assert!(!contents.contains("pw"));
+
+        let expired = RefsCache::new(
+            true,
+            Some(DiskCache {
+                ttl: Duration::ZERO,
+                ..disk.clone()
+            }),
+        );
+        assert!(expired.get(&key(Some("pw"), "refs/heads/")).await.is_none());
+
+        let disabled = RefsCache::new(false, Some(disk));
+        assert!(disabled
+            .get(&key(Some("pw"), "refs/heads/"))
+            .await
+            .is_none());
+        disabled.put(&key(None, "refs/heads/"), &refs('b')).await;
+        assert!(disabled.get(&key(None, "refs/heads/")).await.is_none());
+    }
+}
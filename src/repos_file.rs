@@ -0,0 +1,137 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RepoEntry {
+    pub url: String,
+    /* Tried in order when the server of `url` is unavailable */
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    pub name: Option<String>,
+    pub branch: Option<String>,
+    pub branches_starting_with: Option<String>,
+    pub tags_starting_with: Option<String>,
+    pub worktree: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReposFile {
+    #[serde(default)]
+    repo: Vec<toml::Value>,
+}
+
+pub fn parse(contents: &str) -> Result<Vec<RepoEntry>, String> {
+    let file: ReposFile =
+        toml::from_str(contents).map_err(|e| e.to_string().trim_end().to_string())?;
+
+    file.repo
+        .into_iter()
+        .enumerate()
+        .map(|(idx, value)| {
+            /* Name the entry as well as possible in case it is broken */
+            let label = ["name", "url"]
+                .iter()
+                .find_map(|k| value.get(k).and_then(|v| v.as_str()))
+                .map(|s| format!(" ('{}')", s))
+                .unwrap_or_default();
+            value.try_into().map_err(|e| {
+                format!(
+                    "repo entry #{}{}: {}",
+                    idx + 1,
+                    label,
+                    e.to_string().trim_end()
+                )
+            })
+        })
+        .collect()
+}
+
+pub fn load(path: &Path) -> Result<Vec<RepoEntry>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read '{}': {}", path.display(), e))?;
+    parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_entries() {
+        let entries = parse(
+            r#"
+            [[repo]]
+            url = "platform/core.git"
+            name = "platform-core"
+
+            [[repo]]
+            url = "https://git.example.com/tools/core.git"
+            branch = "main"
+            branches_starting_with = "ma"
+            tags_starting_with = "v"
+            worktree = "src/core"
+            mirrors = ["https://mirror.example.com/tools/core.git"]
+            "#,
+        )
+        .expect("should parse");
+
+        assert_eq!(
+            entries,
+            [
+                RepoEntry {
+                    url: "platform/core.git".to_string(),
+                    name: Some("platform-core".to_string()),
+                    ..Default::default()
+                },
+                RepoEntry {
+                    url: "https://git.example.com/tools/core.git".to_string(),
+                    branch: Some("main".to_string()),
+                    branches_starting_with: Some("ma".to_string()),
+                    tags_starting_with: Some("v".to_string()),
+                    worktree: Some("src/core".to_string()),
+                    mirrors: vec!["https://mirror.example.com/tools/core.git".to_string()],
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_file_has_no_entries() {
+        assert_eq!(parse("").expect("should parse"), []);
+    }
+
+    #[test]
+    fn error_names_entry() {
+        let err = parse(
+            r#"
+            [[repo]]
+            url = "a.git"
+
+            [[repo]]
+            url = "b.git"
+            brnach = "main"
+            "#,
+        )
+        .unwrap_err();
+        assert!(
+            err.starts_with("repo entry #2 ('b.git'): "),
+            "Got unexpected error: {}",
+            err
+        );
+        assert!(err.contains("brnach"), "Got unexpected error: {}", err);
+    }
+
+    #[test]
+    fn error_on_missing_url() {
+        let err = parse("[[repo]]\nname = \"x\"\n").unwrap_err();
+        assert!(
+            err.starts_with("repo entry #1 ('x'): "),
+            "Got unexpected error: {}",
+            err
+        );
+    }
+}
@@ -0,0 +1,374 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use log::warn;
+use regex::Regex;
+
+/* How tags are ordered when picking the highest or lowest one */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TagOrder {
+    /* Natural sort order of the names */
+    #[default]
+    Natural,
+    /* Semantic version precedence, tags that aren't versions come last */
+    Semver,
+    /* Committer date of the tagged commit, newest is highest */
+    CommitterDate,
+}
+
+/* Tags looking like this are pre-releases, e.g. v1.2-rc1, v1.2.0-beta.2
+ * or 1.2.dev3 */
+pub const DEFAULT_PRERELEASE_PATTERN: &str = r"[-.](rc|alpha|beta|dev)(\.?\d+)*$";
+
+/* How the tags of several repositories are combined into one */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TagAggregate {
+    /* The lowest tag */
+    #[default]
+    Min,
+    /* The highest tag */
+    Max,
+    /* All repositories must have the same tag */
+    Same,
+}
+
+fn parse_semver(tag: &str) -> Option<semver::Version> {
+    semver::Version::parse(tag.strip_prefix('v').unwrap_or(tag)).ok()
+}
+
+impl TagOrder {
+    /* Dates aren't known from the names alone, so CommitterDate compares
+     * like Natural here. TagSelector knows the dates. */
+    pub fn compare(self, a: &str, b: &str) -> Ordering {
+        match self {
+            TagOrder::Natural | TagOrder::CommitterDate => natord::compare(a, b),
+            TagOrder::Semver => match (parse_semver(a), parse_semver(b)) {
+                /* Build metadata doesn't affect precedence, fall back to
+                 * the name to keep the order total */
+                (Some(va), Some(vb)) => va.cmp_precedence(&vb).then_with(|| natord::compare(a, b)),
+                (Some(_), None) => Ordering::Greater,
+                (None, Some(_)) => Ordering::Less,
+                (None, None) => natord::compare(a, b),
+            },
+        }
+    }
+
+    /* Warns about the tags that can't take part in the ordering */
+    pub fn check<'a>(self, tags: impl IntoIterator<Item = &'a str>) {
+        if self != TagOrder::Semver {
+            return;
+        }
+        for tag in tags {
+            if parse_semver(tag).is_none() {
+                warn!("Tag {tag} is not a semantic version, ordering it last");
+            }
+        }
+    }
+
+    pub fn max<'a>(self, tags: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+        tags.into_iter().max_by(|a, b| self.compare(a, b))
+    }
+
+    /* The lowest tag, but tags that aren't versions are only picked if
+     * there is nothing else */
+    pub fn min<'a>(self, tags: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+        tags.into_iter().min_by(|a, b| {
+            if self == TagOrder::Semver {
+                let unparseable = |t: &str| parse_semver(t).is_none();
+                match (unparseable(a), unparseable(b)) {
+                    (true, false) => return Ordering::Greater,
+                    (false, true) => return Ordering::Less,
+                    _ => {}
+                }
+            }
+            self.compare(a, b)
+        })
+    }
+}
+
+/* Which tags take part in the selection and how they are compared. The
+ * include/exclude patterns match the full tag name, the prefix is
+ * stripped before comparing. */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TagSelector<'a> {
+    pub order: TagOrder,
+    pub prefix: Option<&'a str>,
+    pub keep_prefix: bool,
+    pub includes: &'a [Regex],
+    pub excludes: &'a [Regex],
+    /* Pre-releases are skipped if this is set */
+    pub prerelease: Option<&'a Regex>,
+    /* Commit times of the tags, for TagOrder::CommitterDate */
+    pub times: Option<&'a HashMap<String, i64>>,
+}
+
+impl TagSelector<'_> {
+    /* Why the tag isn't considered, if it isn't */
+    pub fn rejection(&self, tag: &str) -> Option<String> {
+        if let Some(prefix) = self.prefix {
+            if !tag.starts_with(prefix) {
+                return Some(format!("doesn't start with '{}'", prefix));
+            }
+        }
+        if !self.includes.is_empty() && !self.includes.iter().any(|re| re.is_match(tag)) {
+            return Some("doesn't match any --tag-include".to_string());
+        }
+        if let Some(re) = self.excludes.iter().find(|re| re.is_match(tag)) {
+            return Some(format!("matches --tag-exclude '{}'", re));
+        }
+        if let Some(re) = self.prerelease.filter(|re| re.is_match(tag)) {
+            return Some(format!("is a pre-release (matches '{}')", re));
+        }
+        None
+    }
+
+    /* The name used for comparison, None if the tag isn't considered */
+    fn key<'t>(&self, tag: &'t str) -> Option<&'t str> {
+        if self.rejection(tag).is_some() {
+            return None;
+        }
+        Some(self.strip(tag))
+    }
+
+    fn strip<'t>(&self, tag: &'t str) -> &'t str {
+        self.prefix.and_then(|p| tag.strip_prefix(p)).unwrap_or(tag)
+    }
+
+    /* The tag name as written to the outputs */
+    pub fn output_name<'t>(&self, tag: &'t str) -> &'t str {
+        match self.key(tag) {
+            Some(key) if !self.keep_prefix => key,
+            _ => tag,
+        }
+    }
+
+    fn keyed<'t>(&self, tags: impl IntoIterator<Item = &'t str>) -> Vec<(&'t str, &'t str)> {
+        tags.into_iter()
+            .filter_map(|tag| Some((self.key(tag)?, tag)))
+            .collect()
+    }
+
+    /* Orders by commit time, ties (and tags without a known time) by name */
+    fn compare_times(&self, a: &(&str, &str), b: &(&str, &str)) -> Ordering {
+        let time = |tag: &str| self.times.and_then(|times| times.get(tag));
+        time(a.1)
+            .cmp(&time(b.1))
+            .then_with(|| natord::compare(a.0, b.0))
+    }
+
+    /* Compares any two tags, including those that aren't considered */
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        let (a, b) = ((self.strip(a), a), (self.strip(b), b));
+        match self.order {
+            TagOrder::CommitterDate => self.compare_times(&a, &b),
+            order => order.compare(a.0, b.0),
+        }
+    }
+
+    pub fn max<'t>(&self, tags: impl IntoIterator<Item = &'t str>) -> Option<&'t str> {
+        let keyed = self.keyed(tags);
+        if self.order == TagOrder::CommitterDate {
+            return keyed
+                .iter()
+                .max_by(|a, b| self.compare_times(a, b))
+                .map(|(_, tag)| *tag);
+        }
+        self.order.check(keyed.iter().map(|(key, _)| *key));
+        let best = self.order.max(keyed.iter().map(|(key, _)| *key))?;
+        keyed
+            .iter()
+            .find(|(key, _)| *key == best)
+            .map(|(_, tag)| *tag)
+    }
+
+    pub fn min<'t>(&self, tags: impl IntoIterator<Item = &'t str>) -> Option<&'t str> {
+        let keyed = self.keyed(tags);
+        if self.order == TagOrder::CommitterDate {
+            return keyed
+                .iter()
+                .min_by(|a, b| self.compare_times(a, b))
+                .map(|(_, tag)| *tag);
+        }
+        let best = self.order.min(keyed.iter().map(|(key, _)| *key))?;
+        keyed
+            .iter()
+            .find(|(key, _)| *key == best)
+            .map(|(_, tag)| *tag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn natural_order() {
+        assert_eq!(
+            TagOrder::Natural.compare("v1.10", "v1.9"),
+            Ordering::Greater
+        );
+        assert_eq!(
+            TagOrder::Natural.max(["v1.0", "v1.10", "v1.9"]),
+            Some("v1.10")
+        );
+    }
+
+    #[test]
+    fn semver_prerelease_is_lower() {
+        let order = TagOrder::Semver;
+        assert_eq!(order.compare("v1.0.0-rc.1", "v1.0.0"), Ordering::Less);
+        assert_eq!(
+            order.compare("1.0.0-alpha", "1.0.0-alpha.1"),
+            Ordering::Less
+        );
+        assert_eq!(order.compare("1.0.0-rc.2", "1.0.0-rc.10"), Ordering::Less);
+        assert_eq!(
+            order.compare("1.0.0-beta", "1.0.0-alpha"),
+            Ordering::Greater
+        );
+        assert_eq!(
+            order.max(["v1.2.0-rc.1", "v1.1.0", "v1.2.0-beta"]),
+            Some("v1.2.0-rc.1")
+        );
+        /* Natural order gets this one wrong */
+        assert_eq!(
+            TagOrder::Natural.compare("v1.0.0-rc.1", "v1.0.0"),
+            Ordering::Greater
+        );
+    }
+
+    #[test]
+    fn semver_ignores_build_metadata() {
+        let order = TagOrder::Semver;
+        assert_eq!(order.compare("1.0.0+build.5", "1.0.1"), Ordering::Less);
+        assert_eq!(
+            order.compare("1.0.0+build.5", "1.0.0-rc.1"),
+            Ordering::Greater
+        );
+        /* Equal precedence, still a consistent order */
+        assert_eq!(order.compare("1.0.0+b", "1.0.0+a"), Ordering::Greater);
+        assert_eq!(order.compare("1.0.0+a", "1.0.0+a"), Ordering::Equal);
+    }
+
+    #[test]
+    fn semver_unparseable_tags_last() {
+        let order = TagOrder::Semver;
+        assert_eq!(order.compare("nightly", "v0.0.1"), Ordering::Less);
+        assert_eq!(order.compare("v1.2", "v0.0.1"), Ordering::Less);
+        assert_eq!(order.max(["nightly", "v0.1.0", "latest"]), Some("v0.1.0"));
+        assert_eq!(order.max(["nightly", "latest"]), Some("nightly"));
+        assert_eq!(order.min(["nightly", "v1.0.0", "v0.9.0"]), Some("v0.9.0"));
+        assert_eq!(order.min(["nightly", "latest"]), Some("latest"));
+    }
+
+    #[test]
+    fn prefix_filters_and_strips() {
+        let selector = TagSelector {
+            order: TagOrder::Semver,
+            prefix: Some("myproduct-"),
+            ..Default::default()
+        };
+        let tags = ["jenkins-build-9981", "myproduct-4.17.2", "myproduct-4.9.0"];
+        assert_eq!(selector.max(tags), Some("myproduct-4.17.2"));
+        assert_eq!(selector.min(tags), Some("myproduct-4.9.0"));
+        assert!(selector.rejection("jenkins-build-9981").is_some());
+        assert_eq!(selector.output_name("myproduct-4.17.2"), "4.17.2");
+        assert_eq!(selector.max(["jenkins-build-9981"]), None);
+
+        let keep = TagSelector {
+            keep_prefix: true,
+            ..selector
+        };
+        assert_eq!(keep.output_name("myproduct-4.17.2"), "myproduct-4.17.2");
+    }
+
+    #[test]
+    fn include_and_exclude_patterns() {
+        let includes = [Regex::new(r"^v\d+\.\d+\.\d+").unwrap()];
+        let excludes = [Regex::new("-rc").unwrap(), Regex::new("-nightly").unwrap()];
+        let selector = TagSelector {
+            includes: &includes,
+            excludes: &excludes,
+            ..Default::default()
+        };
+        let tags = ["v1.3.0-rc.1", "v1.2.0", "v1.4.0-nightly", "build-77"];
+        assert_eq!(selector.max(tags), Some("v1.2.0"));
+        assert_eq!(
+            selector.rejection("build-77").as_deref(),
+            Some("doesn't match any --tag-include")
+        );
+        assert_eq!(
+            selector.rejection("v1.3.0-rc.1").as_deref(),
+            Some("matches --tag-exclude '-rc'")
+        );
+        assert_eq!(selector.rejection("v1.2.0"), None);
+    }
+
+    #[test]
+    fn committer_date_order() {
+        let times = HashMap::from([
+            ("2024.1".to_string(), 300),
+            ("v9-typo".to_string(), 100),
+            ("2023.12".to_string(), 200),
+            ("same-b".to_string(), 300),
+        ]);
+        let selector = TagSelector {
+            order: TagOrder::CommitterDate,
+            times: Some(&times),
+            ..Default::default()
+        };
+        assert_eq!(
+            selector.max(["v9-typo", "2024.1", "2023.12"]),
+            Some("2024.1")
+        );
+        assert_eq!(
+            selector.min(["v9-typo", "2024.1", "2023.12"]),
+            Some("v9-typo")
+        );
+        /* Same time, the name decides */
+        assert_eq!(selector.max(["2024.1", "same-b"]), Some("same-b"));
+        /* Unknown times are oldest */
+        assert_eq!(selector.min(["2024.1", "unknown"]), Some("unknown"));
+    }
+
+    #[test]
+    fn compares_all_tags() {
+        let selector = TagSelector {
+            order: TagOrder::Semver,
+            prefix: Some("p-"),
+            ..Default::default()
+        };
+        let mut tags = vec!["p-1.10.0", "other", "p-1.2.0", "p-1.2.0-rc.1"];
+        tags.sort_by(|a, b| selector.compare(a, b));
+        assert_eq!(tags, ["other", "p-1.2.0-rc.1", "p-1.2.0", "p-1.10.0"]);
+    }
+
+    #[test]
+    fn skips_prereleases() {
+        let pattern = Regex::new(DEFAULT_PRERELEASE_PATTERN).unwrap();
+        for tag in [
+            "v1.2-rc1",
+            "v1.2-rc",
+            "v1.2.0-rc.2",
+            "v2-alpha",
+            "v2.0-beta.3",
+            "1.2.dev3",
+        ] {
+            assert!(pattern.is_match(tag), "{} should be a pre-release", tag);
+        }
+        for tag in ["v1.2", "v1.2.0", "release-candidate-2", "devtools-1.0"] {
+            assert!(!pattern.is_match(tag), "{} should be a release", tag);
+        }
+
+        let selector = TagSelector {
+            prerelease: Some(&pattern),
+            ..Default::default()
+        };
+        assert_eq!(selector.max(["v1.2.0", "v1.3.0-rc1"]), Some("v1.2.0"));
+        assert_eq!(selector.max(["v1.3.0-rc1", "v1.3.0-beta"]), None);
+        assert_eq!(
+            TagSelector::default().max(["v1.2.0", "v1.3.0-rc1"]),
+            Some("v1.3.0-rc1")
+        );
+    }
+}
@@ -0,0 +1,134 @@
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Tag,
+    Sha,
+    Repo,
+    Branch,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TagTemplate {
+    segments: Vec<Segment>,
+}
+
+pub struct TagVars<'a> {
+    pub tag: &'a str,
+    pub sha: &'a str,
+    pub repo: &'a str,
+    pub branch: &'a str,
+}
+
+impl TagTemplate {
+    pub fn parse(s: &str) -> Result<TagTemplate, String> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('n') => literal.push('\n'),
+                    Some('t') => literal.push('\t'),
+                    Some('\\') => literal.push('\\'),
+                    Some('{') => literal.push('{'),
+                    Some(c) => return Err(format!("Unknown escape sequence '\\{}'", c)),
+                    None => return Err("Trailing escape character".to_string()),
+                },
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("Unterminated placeholder '{{{}'", name)),
+                        }
+                    }
+                    let segment = match name.as_str() {
+                        "tag" => Segment::Tag,
+                        "sha" => Segment::Sha,
+                        "repo" => Segment::Repo,
+                        "branch" => Segment::Branch,
+                        _ => return Err(format!("Unknown placeholder '{{{}}}'", name)),
+                    };
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(segment);
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(TagTemplate { segments })
+    }
+
+    pub fn render(&self, vars: &TagVars) -> String {
+        self.segments
+            .iter()
+            .map(|s| match s {
+                Segment::Literal(l) => l.as_str(),
+                Segment::Tag => vars.tag,
+                Segment::Sha => vars.sha,
+                Segment::Repo => vars.repo,
+                Segment::Branch => vars.branch,
+            })
+            .collect()
+    }
+}
+
+impl Default for TagTemplate {
+    fn default() -> Self {
+        TagTemplate {
+            segments: vec![Segment::Tag],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VARS: TagVars = TagVars {
+        tag: "v4.2.1",
+        sha: "0123abc",
+        repo: "core",
+        branch: "release/4.2",
+    };
+
+    #[test]
+    fn default_is_bare_tag() {
+        assert_eq!(TagTemplate::default().render(&VARS), "v4.2.1");
+        assert_eq!(TagTemplate::parse("{tag}").unwrap(), TagTemplate::default());
+    }
+
+    #[test]
+    fn renders_placeholders_and_escapes() {
+        let t = TagTemplate::parse(r"TAG={tag}\nSHA={sha}\t{repo}:{branch}\\\{x}").unwrap();
+        assert_eq!(
+            t.render(&VARS),
+            "TAG=v4.2.1\nSHA=0123abc\tcore:release/4.2\\{x}"
+        );
+    }
+
+    #[test]
+    fn error_on_unknown_placeholder() {
+        let err = TagTemplate::parse("{tga}").unwrap_err();
+        assert_eq!(err, "Unknown placeholder '{tga}'");
+    }
+
+    #[test]
+    fn error_on_unterminated_placeholder() {
+        let err = TagTemplate::parse("x{tag").unwrap_err();
+        assert_eq!(err, "Unterminated placeholder '{tag'");
+    }
+
+    #[test]
+    fn error_on_unknown_escape() {
+        let err = TagTemplate::parse(r"\q").unwrap_err();
+        assert_eq!(err, r"Unknown escape sequence '\q'");
+    }
+}
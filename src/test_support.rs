@@ -0,0 +1,195 @@
+/* A remote repository for testing clones without a server: the refs of
+ * a fixture repository, and fetch responses packing its objects */
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+use crate::git_http_client::{read_fetch_response, Deepen, GitClientError, Transport};
+use crate::local_repo::LocalRepo;
+use crate::pkt_line::{PktLine, MAX_PKT_PAYLOAD};
+use crate::reader::GitPacketLineStream;
+use crate::RefInfo;
+
+pub fn git(dir: &Path, args: &[&str]) -> String {
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+        .args(args)
+        .output()
+        .expect("running git");
+    assert!(out.status.success(), "git {:?} failed", args);
+    String::from_utf8_lossy(&out.stdout).trim().to_string()
+}
+
+/* A repository with a linear history on main */
+pub struct Fixture {
+    pub dir: tempfile::TempDir,
+    /* Newest first */
+    pub commits: Vec<String>,
+}
+
+impl Fixture {
+    /* `len` commits, with lightweight tags on the commits `tags` are
+     * counted back from the tip */
+    pub fn new(len: usize, tags: &[(usize, &str)]) -> Fixture {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "-q", "-b", "main"]);
+        let mut commits = Vec::new();
+        for i in 0..len {
+            let msg = format!("commit {}", i);
+            git(dir.path(), &["commit", "-q", "--allow-empty", "-m", &msg]);
+            commits.insert(0, git(dir.path(), &["rev-parse", "HEAD"]));
+        }
+        for (distance, name) in tags {
+            git(dir.path(), &["tag", name, &commits[*distance]]);
+        }
+        Fixture { dir, commits }
+    }
+
+    pub fn git(&self, args: &[&str]) -> String {
+        git(self.dir.path(), args)
+    }
+
+    pub fn refs(&self) -> Vec<RefInfo> {
+        self.git(&["for-each-ref", "--format=%(objectname) %(refname)"])
+            .lines()
+            .map(|line| {
+                let (sha, refname) = line.split_once(' ').unwrap();
+                RefInfo {
+                    sha: sha.to_string(),
+                    refname: refname.to_string(),
+                    peeled: None,
+                }
+            })
+            .collect()
+    }
+
+    fn pack(&self, revs: &str) -> Vec<u8> {
+        let mut child = Command::new("git")
+            .arg("-C")
+            .arg(self.dir.path())
+            .args(["pack-objects", "--revs", "--stdout", "-q"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(revs.as_bytes())
+            .unwrap();
+        let out = child.wait_with_output().unwrap();
+        assert!(out.status.success());
+        out.stdout
+    }
+
+    /* What a server answers to a fetch of commits[tip] at `depth`, from
+     * a client with `shallow` as its shallow commits */
+    pub fn fetch_response(&self, tip: usize, depth: usize, shallow: &HashSet<String>) -> Bytes {
+        let history = &self.commits[tip..];
+        let boundary = history.get(depth).map(|_| &history[depth - 1]);
+        let revs = match history.get(depth) {
+            Some(excluded) => format!("{}\n^{}\n", history[0], excluded),
+            None => format!("{}\n", history[0]),
+        };
+
+        let mut pkt = PktLine::new().add_str("shallow-info\n");
+        if let Some(boundary) = boundary {
+            pkt = pkt.add_fmt(format_args!("shallow {}", boundary));
+        }
+        for sha in shallow.iter().filter(|sha| Some(*sha) != boundary) {
+            pkt = pkt.add_fmt(format_args!("unshallow {}", sha));
+        }
+        pkt = pkt.delimit().add_str("packfile\n");
+        for chunk in self.pack(&revs).chunks(MAX_PKT_PAYLOAD - 1) {
+            pkt = pkt.add(&[&[1], chunk].concat());
+        }
+        pkt.flush().take().into()
+    }
+}
+
+/* Serves a Fixture, recording the depth of every fetch */
+pub struct MockTransport {
+    pub fixture: Fixture,
+    pub fetches: Mutex<Vec<usize>>,
+    /* Refuses wants that aren't ref tips, like servers without
+     * uploadpack.allowReachableSHA1InWant */
+    pub tips_only: bool,
+}
+
+impl MockTransport {
+    pub fn new(fixture: Fixture) -> Self {
+        MockTransport {
+            fixture,
+            fetches: Mutex::new(Vec::new()),
+            tips_only: false,
+        }
+    }
+
+    pub fn fetched_depths(&self) -> Vec<usize> {
+        self.fetches.lock().unwrap().clone()
+    }
+}
+
+impl Transport for MockTransport {
+    async fn ls_refs<T: AsRef<str> + std::fmt::Display>(
+        &self,
+        ref_prefixes: &[T],
+    ) -> Result<Vec<RefInfo>, GitClientError> {
+        Ok(self
+            .fixture
+            .refs()
+            .into_iter()
+            .filter(|r| {
+                ref_prefixes
+                    .iter()
+                    .any(|p| r.refname.starts_with(p.as_ref()))
+            })
+            .collect())
+    }
+
+    async fn fetch<T: AsRef<str>>(
+        &self,
+        local_repo: &LocalRepo,
+        wants: &[T],
+        deepen: Deepen,
+        _include_tags: bool,
+    ) -> Result<(), GitClientError> {
+        let Deepen::Depth(depth) = deepen else {
+            panic!("MockTransport only fetches with a depth, not {:?}", deepen);
+        };
+        if self.tips_only {
+            let tips: HashSet<String> = self.fixture.refs().into_iter().map(|r| r.sha).collect();
+            if let Some(want) = wants.iter().find(|w| !tips.contains(w.as_ref())) {
+                return Err(GitClientError::ResponseError(format!(
+                    "not our ref {}",
+                    want.as_ref()
+                )));
+            }
+        }
+        /* The history of the newest wanted commit covers the others */
+        let tip = wants
+            .iter()
+            .map(|w| self.fixture.commits.iter().position(|c| c == w.as_ref()))
+            .collect::<Option<Vec<_>>>()
+            .and_then(|positions| positions.into_iter().min())
+            .expect("MockTransport only serves commits on main");
+        self.fetches.lock().unwrap().push(depth);
+
+        let shallow = local_repo
+            .get_shallow_shas()
+            .await
+            .map_err(GitClientError::LocalRepo)?;
+        let response = self.fixture.fetch_response(tip, depth, &shallow);
+        let mut stream =
+            GitPacketLineStream::new(futures::stream::iter([Ok::<_, std::io::Error>(response)]));
+        read_fetch_response(&mut stream, local_repo, "mock://fixture").await
+    }
+}
@@ -0,0 +1,632 @@
+mod support;
+
+use std::path::Path;
+
+use support::{git, sleipnir, Server};
+
+fn clone_json(dir: &Path, args: &[&str]) -> serde_json::Value {
+    let out = sleipnir(
+        dir,
+        &[&["clone", "--create-dirs", "--json-output", "-"], args].concat(),
+    );
+    assert!(out.status.success(), "clone failed");
+    serde_json::from_slice(&out.stdout).unwrap()
+}
+
+#[test]
+fn clones_branch_at_tag() {
+    let server = Server::start(None);
+    let repo = server.repo("alpha", 10);
+    repo.annotated_tag(3, "v1.0")
+        .tag(6, "v0.9")
+        .branch(2, "release/1");
+    let dir = tempfile::tempdir().unwrap();
+
+    let summary = clone_json(
+        dir.path(),
+        &[
+            "--branch",
+            "main",
+            "--deepen-step",
+            "3",
+            "--output-dir",
+            "out",
+            "--tag-output-file",
+            "tag.txt",
+            &server.url("alpha"),
+        ],
+    );
+    assert_eq!(summary["tag"], "v1.0");
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("tag.txt")).unwrap(),
+        "v1.0\n"
+    );
+    let result = &summary["repos"][0];
+    assert_eq!(result["branch"], "main");
+    assert_eq!(result["sha"], repo.commits[0].as_str());
+
+    let clone = dir.path().join("out/alpha");
+    assert_eq!(
+        std::fs::read_to_string(clone.join("file.txt")).unwrap(),
+        "10\n"
+    );
+    assert_eq!(git(&clone, &["rev-parse", "HEAD"]), repo.commits[0]);
+    assert_eq!(git(&clone, &["symbolic-ref", "HEAD"]), "refs/heads/main");
+    assert_eq!(
+        git(&clone, &["rev-parse", "v1.0^{commit}"]),
+        repo.commits[3]
+    );
+    assert_eq!(git(&clone, &["cat-file", "-t", "v1.0"]), "tag");
+    assert_eq!(
+        std::fs::read_to_string(clone.join(".git/shallow")).unwrap(),
+        format!("{}\n", repo.commits[3])
+    );
+    assert_eq!(git(&clone, &["status", "--porcelain"]), "");
+}
+
+#[test]
+fn deepens_to_tag_120_commits_back() {
+    let server = Server::start(None);
+    let repo = server.repo("deep", 200);
+    repo.annotated_tag(120, "v2.0");
+    let dir = tempfile::tempdir().unwrap();
+
+    let summary = clone_json(
+        dir.path(),
+        &[
+            "--branch",
+            "main",
+            "--output-dir",
+            "out",
+            &server.url("deep"),
+        ],
+    );
+    assert_eq!(summary["tag"], "v2.0");
+    let clone = dir.path().join("out/deep");
+    assert_eq!(git(&clone, &["rev-parse", "HEAD"]), repo.commits[0]);
+    let depth: usize = git(&clone, &["rev-list", "--count", "HEAD"])
+        .parse()
+        .unwrap();
+    assert!(depth > 120 && depth < 200, "depth {}", depth);
+    assert_eq!(summary["repos"][0]["depth"], depth);
+    assert_eq!(
+        std::fs::read_to_string(clone.join(".git/shallow")).unwrap(),
+        format!("{}\n", repo.commits[depth - 1])
+    );
+}
+
+#[test]
+fn falls_back_to_release_branch() {
+    let server = Server::start(None);
+    let repo = server.repo("beta", 5);
+    repo.tag(2, "v1.0").branch(1, "release/1");
+    let dir = tempfile::tempdir().unwrap();
+
+    let summary = clone_json(
+        dir.path(),
+        &[
+            "--branch",
+            "release/1.2",
+            "--branch-fallback",
+            r"/\.[^.]*$//",
+            "--output-dir",
+            "out",
+            "--tag-output-file",
+            "tag.txt",
+            "--no-trailing-newline",
+            &server.url("beta"),
+        ],
+    );
+    assert_eq!(summary["repos"][0]["branch"], "release/1");
+    assert_eq!(summary["tag"], "v1.0");
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("tag.txt")).unwrap(),
+        "v1.0"
+    );
+
+    let out = sleipnir(
+        dir.path(),
+        &[
+            "clone",
+            "--branch",
+            "release/1",
+            "--output-dir",
+            "again",
+            "--create-dirs",
+            "--tag-output-file",
+            "-",
+            &server.url("beta"),
+        ],
+    );
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "v1.0\n");
+    let clone = dir.path().join("out/beta");
+    assert_eq!(git(&clone, &["rev-parse", "HEAD"]), repo.commits[1]);
+}
+
+#[test]
+fn auth_required() {
+    let server = Server::start(Some(("user", "s3cret")));
+    let repo = server.repo("private", 3);
+    repo.tag(0, "v1.0");
+    let dir = tempfile::tempdir().unwrap();
+
+    let out = sleipnir(
+        dir.path(),
+        &[
+            "clone",
+            "--create-dirs",
+            "--branch",
+            "main",
+            "--output-dir",
+            "anonymous",
+            &server.url("private"),
+        ],
+    );
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("401"));
+    assert!(!dir.path().join("anonymous/private").exists());
+
+    let out = sleipnir(
+        dir.path(),
+        &[
+            "clone",
+            "--create-dirs",
+            "--branch",
+            "main",
+            "--output-dir",
+            "wrong",
+            &server.url_with_auth("private", "user", "wrong"),
+        ],
+    );
+    assert!(!out.status.success());
+    assert!(!String::from_utf8_lossy(&out.stderr).contains("wrong@"));
+
+    let summary = clone_json(
+        dir.path(),
+        &[
+            "--branch",
+            "main",
+            "--output-dir",
+            "out",
+            &server.url_with_auth("private", "user", "s3cret"),
+        ],
+    );
+    assert_eq!(summary["tag"], "v1.0");
+    let clone = dir.path().join("out/private");
+    assert_eq!(git(&clone, &["rev-parse", "HEAD"]), repo.commits[0]);
+}
+
+#[test]
+fn reclones_from_lockfile() {
+    let server = Server::start(None);
+    let repo = server.repo("gamma", 10);
+    repo.annotated_tag(5, "v1.0");
+    /* main starts out 3 commits behind where it will be */
+    git(
+        &repo.path,
+        &["update-ref", "refs/heads/main", &repo.commits[3]],
+    );
+    let dir = tempfile::tempdir().unwrap();
+
+    let summary = clone_json(
+        dir.path(),
+        &[
+            "--branch",
+            "main",
+            "--output-dir",
+            "first",
+            "--write-lockfile",
+            "sleipnir.lock",
+            &server.url("gamma"),
+        ],
+    );
+    assert_eq!(summary["repos"][0]["sha"], repo.commits[3].as_str());
+    let lockfile = std::fs::read_to_string(dir.path().join("sleipnir.lock")).unwrap();
+    assert!(lockfile.contains(&format!("sha = \"{}\"", repo.commits[3])));
+    assert!(lockfile.contains("tag = \"v1.0\""));
+
+    let verify = ["clone", "--verify-lockfile", "sleipnir.lock"];
+    assert!(sleipnir(dir.path(), &verify).status.success());
+
+    git(
+        &repo.path,
+        &["update-ref", "refs/heads/main", &repo.commits[0]],
+    );
+    let out = sleipnir(dir.path(), &verify);
+    assert!(!out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        format!(
+            "gamma: refs/heads/main moved from {} to {}\n",
+            repo.commits[3], repo.commits[0]
+        )
+    );
+
+    let summary = clone_json(
+        dir.path(),
+        &[
+            "--output-dir",
+            "again",
+            "--from-lockfile",
+            "sleipnir.lock",
+            &server.url("gamma"),
+        ],
+    );
+    assert_eq!(summary["tag"], "v1.0");
+    let clone = dir.path().join("again/gamma");
+    assert_eq!(git(&clone, &["rev-parse", "HEAD"]), repo.commits[3]);
+    assert_eq!(git(&clone, &["symbolic-ref", "HEAD"]), "refs/heads/main");
+
+    let out = sleipnir(
+        dir.path(),
+        &[
+            "clone",
+            "--from-lockfile",
+            "sleipnir.lock",
+            &server.url("other"),
+        ],
+    );
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("don't match the lockfile"));
+}
+
+#[test]
+fn resolves_every_branch_before_cloning() {
+    let server = Server::start(None);
+    server
+        .repo("first", 3)
+        .tag(0, "v1.0")
+        .branch(0, "release/1");
+    server.repo("second", 3).tag(0, "v1.0");
+    server.repo("third", 3).tag(0, "v1.0");
+    let dir = tempfile::tempdir().unwrap();
+    let urls = [
+        server.url("first"),
+        server.url("second"),
+        server.url("third"),
+    ];
+    let urls: Vec<&str> = urls.iter().map(String::as_str).collect();
+    let branch = ["--branch", "release/1"];
+
+    let out = sleipnir(
+        dir.path(),
+        &[
+            &["clone", "--create-dirs", "--output-dir", "out"][..],
+            &branch,
+            &urls,
+        ]
+        .concat(),
+    );
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr)
+        .contains("No suitable branch found for: second, third"));
+    assert!(!dir.path().join("out").exists());
+
+    let out = sleipnir(
+        dir.path(),
+        &[
+            &["clone", "--create-dirs", "--output-dir", "kept"][..],
+            &["--keep-going", "--json-output", "-"],
+            &["--ref-output-file", "refs.txt"],
+            &branch,
+            &urls,
+        ]
+        .concat(),
+    );
+    assert!(!out.status.success());
+    let summary: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(summary["repos"][0]["name"], "first");
+    assert_eq!(summary["failed"][0]["name"], "second");
+    assert_eq!(summary["failed"][1]["name"], "third");
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("refs.txt")).unwrap(),
+        format!(
+            "first refs/heads/release/1 {}\nsecond FAILED -\nthird FAILED -\n",
+            summary["repos"][0]["sha"].as_str().unwrap()
+        )
+    );
+    assert!(dir.path().join("kept/first").exists());
+    assert!(!dir.path().join("kept/second").exists());
+}
+
+#[test]
+fn fails_over_to_mirror() {
+    let mirror = Server::start(None);
+    let repo = mirror.repo("team/delta", 3);
+    repo.tag(1, "v1.0").branch(0, "release/1");
+    /* Nothing listens there once the listener is gone */
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let primary = format!("http://127.0.0.1:{port}/team/delta.git");
+    let dir = tempfile::tempdir().unwrap();
+
+    let mirror_base = format!("http://127.0.0.1:{}/", mirror.port);
+    let summary = clone_json(
+        dir.path(),
+        &[
+            "--branch",
+            "main",
+            "--output-dir",
+            "out",
+            "--mirror",
+            &mirror_base,
+            &primary,
+        ],
+    );
+    let result = &summary["repos"][0];
+    assert_eq!(result["url"], primary.as_str());
+    assert_eq!(result["served_by"], mirror.url("team/delta").as_str());
+    assert_eq!(summary["tag"], "v1.0");
+    let clone = dir.path().join("out/delta");
+    assert_eq!(git(&clone, &["rev-parse", "HEAD"]), repo.commits[0]);
+    assert_eq!(git(&clone, &["remote", "get-url", "origin"]), primary);
+
+    let summary = clone_json(
+        dir.path(),
+        &[
+            "--branch",
+            "main",
+            "--output-dir",
+            "alternatives",
+            &format!("{}|{}", primary, mirror.url("team/delta")),
+        ],
+    );
+    assert_eq!(
+        summary["repos"][0]["served_by"],
+        mirror.url("team/delta").as_str()
+    );
+
+    /* A missing branch is no reason to ask a mirror */
+    let other = Server::start(None);
+    other.repo("team/delta", 2).tag(0, "v1.0");
+    let out = sleipnir(
+        dir.path(),
+        &[
+            "clone",
+            "--create-dirs",
+            "--branch",
+            "release/1",
+            "--output-dir",
+            "missing",
+            &format!("{}|{}", other.url("team/delta"), mirror.url("team/delta")),
+        ],
+    );
+    assert!(!out.status.success());
+    assert!(!String::from_utf8_lossy(&out.stderr).contains("trying the next mirror"));
+}
+
+#[test]
+fn writes_tag_of_each_repo() {
+    let server = Server::start(None);
+    server.repo("zeta", 3).tag(0, "v2.1").tag(1, "v2.0");
+    server.repo("eta", 3).tag(2, "v1.4");
+    server.repo("theta", 2);
+    let dir = tempfile::tempdir().unwrap();
+    let args = [
+        "--branch",
+        "main",
+        "--output-dir",
+        "out",
+        "--allow-no-tag",
+        "--tag-output-dir",
+        "tags",
+    ];
+    let urls = [server.url("zeta"), server.url("eta"), server.url("theta")];
+    let urls: Vec<&str> = urls.iter().map(String::as_str).collect();
+
+    let summary = clone_json(dir.path(), &[&args[..], &urls].concat());
+    assert_eq!(summary["tag"], "v1.4");
+    let tags = dir.path().join("tags");
+    assert_eq!(
+        std::fs::read_to_string(tags.join("zeta.tag")).unwrap(),
+        "v2.1\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(tags.join("eta.tag")).unwrap(),
+        "v1.4\n"
+    );
+    assert_eq!(std::fs::read_to_string(tags.join("theta.tag")).unwrap(), "");
+    assert_eq!(summary["tag_files"].as_array().unwrap().len(), 3);
+    assert_eq!(summary["tag_files"][0], "tags/zeta.tag");
+
+    let summary = clone_json(
+        dir.path(),
+        &[
+            &args[..],
+            &[
+                "--skip-untagged",
+                "--update",
+                "--tag-output-format",
+                "{repo}={tag}",
+            ],
+            &urls,
+        ]
+        .concat(),
+    );
+    assert_eq!(
+        std::fs::read_to_string(tags.join("zeta.tag")).unwrap(),
+        "zeta=v2.1\n"
+    );
+    assert_eq!(summary["tag_files"].as_array().unwrap().len(), 2);
+    assert!(!tags.join("theta.tag").exists());
+}
+
+#[test]
+fn derives_directory_names_from_urls() {
+    let server = Server::start(None);
+    server.repo("group/my repo", 2).tag(0, "v1.0");
+    let dir = tempfile::tempdir().unwrap();
+
+    let url = server.url("group/my%20repo");
+    let out = sleipnir(
+        dir.path(),
+        &[
+            "clone",
+            "--create-dirs",
+            "--branch",
+            "main",
+            "--output-dir",
+            "out",
+            &url,
+        ],
+    );
+    assert!(out.status.success());
+    assert!(dir.path().join("out/my repo/.git").is_dir());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("as 'my repo'"));
+
+    /* Names that only differ before they are made usable */
+    let collide = |urls: &[&str]| {
+        let out = sleipnir(dir.path(), &[&["clone", "--branch", "main"], urls].concat());
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr)
+            .contains("Multiple repositories would be cloned into the same directory"));
+    };
+    collide(&[&server.url("a/x%0Ay"), &server.url("b/x_y")]);
+    collide(&[&format!("{}?ref=x", server.url("a/q")), &server.url("b/q")]);
+}
+
+#[test]
+fn describes_commit_relative_to_tag() {
+    let server = Server::start(None);
+    let repo = server.repo("theta", 10);
+    repo.annotated_tag(3, "v1.0").branch(3, "release/1");
+    let dir = tempfile::tempdir().unwrap();
+    let url = server.url("theta");
+    let describe = |branch: &str| {
+        let out = sleipnir(
+            dir.path(),
+            &["describe", "--branch", branch, "--deepen-step", "2", &url],
+        );
+        assert!(out.status.success());
+        (
+            String::from_utf8_lossy(&out.stdout).to_string(),
+            String::from_utf8_lossy(&out.stderr).to_string(),
+        )
+    };
+
+    let (described, log) = describe("main");
+    assert_eq!(described, format!("v1.0-3-g{}\n", &repo.commits[0][..7]));
+    assert!(log.contains("searched to depth 5"), "{}", log);
+    let (described, log) = describe("release/1");
+    assert_eq!(described, "v1.0\n");
+    assert!(!log.contains("searched to depth"), "{}", log);
+    /* There's no tagged commit to print the sha of */
+    let out = sleipnir(dir.path(), &["describe", "--branch", "main", "--sha", &url]);
+    assert_eq!(out.status.code(), Some(2));
+
+    /* The nearest tag, even if a higher one is reachable too */
+    let repo = server.repo("kappa", 10);
+    repo.annotated_tag(5, "v2.0").tag(1, "v1.9");
+    let out = sleipnir(
+        dir.path(),
+        &[
+            "describe",
+            "--branch",
+            "main",
+            "--depth",
+            "10",
+            &server.url("kappa"),
+        ],
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        format!("v1.9-1-g{}\n", &repo.commits[0][..7])
+    );
+    let out = sleipnir(
+        dir.path(),
+        &[
+            "max-tag",
+            "--branch",
+            "main",
+            "--depth",
+            "10",
+            &server.url("kappa"),
+        ],
+    );
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "v2.0\n");
+
+    clone_json(
+        dir.path(),
+        &[
+            "--branch",
+            "main",
+            "--output-dir",
+            "out",
+            "--describe-output-file",
+            "describe.txt",
+            &url,
+        ],
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("describe.txt")).unwrap(),
+        format!("v1.0-3-g{}\n", &repo.commits[0][..7])
+    );
+}
+
+#[test]
+fn verifies_clone_against_remote() {
+    let server = Server::start(None);
+    let repo = server.repo("iota", 10);
+    repo.annotated_tag(5, "v1.0").branch(2, "release/1");
+    let dir = tempfile::tempdir().unwrap();
+    let url = server.url("iota");
+    clone_json(
+        dir.path(),
+        &["--branch", "release/1", "--output-dir", "out", &url],
+    );
+    let clone = dir.path().join("out/iota");
+    let verify = || {
+        let out = sleipnir(dir.path(), &["verify", "--json", "out/iota", &url]);
+        let details: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+        (out.status.code().unwrap(), details)
+    };
+
+    let (status, details) = verify();
+    assert_eq!(status, 0);
+    assert_eq!(details["status"], "up-to-date");
+    assert_eq!(details["head"], "refs/heads/release/1");
+    assert_eq!(details["tags_checked"], 1);
+
+    git(&clone, &["reset", "-q", "--hard", "HEAD~1"]);
+    let (status, details) = verify();
+    assert_eq!(status, 1);
+    assert_eq!(
+        (&details["behind"], &details["ahead"]),
+        (&1.into(), &0.into())
+    );
+
+    /* Rewound to a commit the clone has */
+    git(&repo.path, &["branch", "-f", "release/1", &repo.commits[4]]);
+    let (status, details) = verify();
+    assert_eq!(status, 3);
+    assert_eq!(details["status"], "diverged");
+
+    /* Moved on to one it doesn't */
+    git(&repo.path, &["branch", "-f", "release/1", &repo.commits[0]]);
+    let (status, details) = verify();
+    assert_eq!(status, 1);
+    assert!(details["behind"].is_null());
+    assert_eq!(details["remote_sha"], repo.commits[0].as_str());
+
+    git(&repo.path, &["branch", "-D", "release/1"]);
+    assert_eq!(verify().0, 2);
+
+    git(&repo.path, &["tag", "-d", "v1.0"]);
+    let (status, details) = verify();
+    assert_eq!(status, 3);
+    assert_eq!(details["mismatched_tags"][0]["refname"], "refs/tags/v1.0");
+    assert!(details["mismatched_tags"][0]["remote_sha"].is_null());
+
+    let out = sleipnir(dir.path(), &["verify", "out/iota", &url]);
+    let printed = String::from_utf8_lossy(&out.stdout);
+    assert!(printed.contains("refs/heads/release/1 gone from the remote\n"));
+    assert!(printed.contains("refs/tags/v1.0 gone from the remote\n"));
+    let out = sleipnir(dir.path(), &["verify", "nowhere", &url]);
+    assert_eq!(out.status.code(), Some(4));
+    let out = sleipnir(dir.path(), &["verify", "--bogus", "out/iota", &url]);
+    assert_eq!(out.status.code(), Some(4));
+}
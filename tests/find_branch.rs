@@ -0,0 +1,87 @@
+mod support;
+
+use support::{sleipnir, Server};
+
+#[test]
+fn resolves_local_repo_like_remote() {
+    let server = Server::start(None);
+    let repo = server.repo("zeta", 3);
+    repo.branch(1, "release/1").branch(2, "release/2");
+    let dir = tempfile::tempdir().unwrap();
+    let args = [
+        "find-branch",
+        "--branch",
+        "release/1.4",
+        "--branch-fallback",
+        r"/\.[^.]*$//",
+        "--explain",
+        "--json",
+    ];
+
+    let url = server.url("zeta");
+    let remote = sleipnir(dir.path(), &[&args[..], &[&url]].concat());
+    assert!(remote.status.success());
+    let path = repo.path.to_str().unwrap();
+    let local = sleipnir(dir.path(), &[&args[..], &["--local", path]].concat());
+    assert!(local.status.success());
+    assert_eq!(local.stdout, remote.stdout);
+
+    let json: serde_json::Value = serde_json::from_slice(&local.stdout).unwrap();
+    assert_eq!(json["refname"], "refs/heads/release/1");
+    assert_eq!(json["sha"], repo.commits[1].as_str());
+
+    /* Either a repository on disk or URLs */
+    let out = sleipnir(
+        dir.path(),
+        &["find-branch", "--branch", "main", "--local", path, &url],
+    );
+    assert_eq!(out.status.code(), Some(2));
+    let out = sleipnir(
+        dir.path(),
+        &["find-branch", "--branch", "main", "--local", "nowhere"],
+    );
+    assert!(!out.status.success());
+}
+
+#[test]
+fn tells_how_it_resolved_by_exit_status() {
+    let server = Server::start(None);
+    server.repo("eta", 2).branch(1, "release/1");
+    let dir = tempfile::tempdir().unwrap();
+    let status = |url: &str, args: &[&str]| {
+        let out = sleipnir(
+            dir.path(),
+            &[&["find-branch", "--quiet"], args, &[url]].concat(),
+        );
+        assert!(out.stdout.is_empty());
+        out.status.code().unwrap()
+    };
+
+    let url = server.url("eta");
+    assert_eq!(status(&url, &["--branch", "release/1"]), 0);
+    let fallback = [
+        "--branch",
+        "release/1.4",
+        "--branch-fallback",
+        r"/\.[^.]*$//",
+    ];
+    assert_eq!(status(&url, &fallback), 0);
+    let default = ["--branch", "feature/x", "--default-branch", "main"];
+    assert_eq!(status(&url, &default), 1);
+    assert_eq!(status(&url, &["--branch", "feature/x"]), 2);
+    assert_eq!(status(&server.url("missing"), &default), 3);
+
+    /* Failures before resolving anything too */
+    assert_eq!(status(&url, &["--branch", "main", "--bogus"]), 3);
+    let trace = ["--branch", "main", "--trace-packets", "no/such/dir/trace"];
+    assert_eq!(status(&url, &trace), 3);
+    std::fs::write(dir.path().join("sleipnir.toml"), "jbos = 4\n").unwrap();
+    assert_eq!(status(&url, &["--branch", "main"]), 3);
+    std::fs::remove_file(dir.path().join("sleipnir.toml")).unwrap();
+
+    /* Only with --quiet */
+    let out = sleipnir(dir.path(), &["find-branch", "--bogus", &url]);
+    assert_eq!(out.status.code(), Some(2));
+    let out = sleipnir(dir.path(), &["find-branch", "--quiet", "--help"]);
+    assert_eq!(out.status.code(), Some(0));
+}
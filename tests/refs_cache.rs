@@ -0,0 +1,42 @@
+mod support;
+
+use support::{git, sleipnir, Server};
+
+#[test]
+fn caches_refs_across_runs() {
+    let server = Server::start(None);
+    let repo = server.repo("cached", 3);
+    git(
+        &repo.path,
+        &["update-ref", "refs/heads/main", &repo.commits[1]],
+    );
+    let dir = tempfile::tempdir().unwrap();
+    let url = server.url("cached");
+    let main_sha = |args: &[&str]| {
+        let out = sleipnir(
+            dir.path(),
+            &[&["ls-refs", "--prefix", "refs/heads/main"], args, &[&url]].concat(),
+        );
+        assert!(out.status.success());
+        String::from_utf8_lossy(&out.stdout)
+            .split_whitespace()
+            .next()
+            .unwrap()
+            .to_string()
+    };
+
+    assert_eq!(main_sha(&["--refs-cache", "cache"]), repo.commits[1]);
+    git(
+        &repo.path,
+        &["update-ref", "refs/heads/main", &repo.commits[0]],
+    );
+    assert_eq!(main_sha(&["--refs-cache", "cache"]), repo.commits[1]);
+    assert_eq!(
+        main_sha(&["--refs-cache", "cache", "--no-cache"]),
+        repo.commits[0]
+    );
+    assert_eq!(
+        main_sha(&["--refs-cache", "cache", "--refs-cache-ttl", "0"]),
+        repo.commits[0]
+    );
+}
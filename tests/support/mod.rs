@@ -0,0 +1,381 @@
+/* End-to-end fixtures: repositories built with git and served over HTTP
+ * by `git http-backend`, behind a minimal CGI shim */
+
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+
+pub fn git(dir: &Path, args: &[&str]) -> String {
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+        .args(args)
+        .output()
+        .expect("running git");
+    assert!(
+        out.status.success(),
+        "git {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&out.stderr)
+    );
+    String::from_utf8_lossy(&out.stdout).trim().to_string()
+}
+
+/* A bare repository with a linear history on main, each commit writing
+ * its number to file.txt */
+pub struct Repo {
+    pub path: PathBuf,
+    /* Newest first */
+    pub commits: Vec<String>,
+}
+
+impl Repo {
+    pub fn new(path: PathBuf, len: usize) -> Repo {
+        std::fs::create_dir_all(&path).unwrap();
+        git(&path, &["init", "-q", "--bare", "-b", "main"]);
+
+        /* One fast-import instead of a few git commands per commit keeps
+         * long histories quick to build */
+        let mut stream = String::new();
+        for i in 1..=len {
+            let msg = format!("commit {}\n", i);
+            let content = format!("{}\n", i);
+            stream.push_str(&format!(
+                "commit refs/heads/main\nmark :{i}\ncommitter test <test@example.com> {} +0000\ndata {}\n{msg}",
+                1_700_000_000 + i,
+                msg.len()
+            ));
+            if i > 1 {
+                stream.push_str(&format!("from :{}\n", i - 1));
+            }
+            stream.push_str(&format!(
+                "M 644 inline file.txt\ndata {}\n{content}\n",
+                content.len()
+            ));
+        }
+        let mut child = Command::new("git")
+            .arg("-C")
+            .arg(&path)
+            .args(["fast-import", "--quiet"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(stream.as_bytes())
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+
+        let commits = git(&path, &["rev-list", "main"])
+            .lines()
+            .map(str::to_string)
+            .collect();
+        Repo { path, commits }
+    }
+
+    /* Tags and branches are placed `distance` commits back from the tip
+     * of main */
+    pub fn tag(&self, distance: usize, name: &str) -> &Self {
+        git(&self.path, &["tag", name, &self.commits[distance]]);
+        self
+    }
+
+    pub fn annotated_tag(&self, distance: usize, name: &str) -> &Self {
+        git(
+            &self.path,
+            &["tag", "-a", "-m", name, name, &self.commits[distance]],
+        );
+        self
+    }
+
+    pub fn branch(&self, distance: usize, name: &str) -> &Self {
+        git(&self.path, &["branch", name, &self.commits[distance]]);
+        self
+    }
+}
+
+/* The certificates in tests/support/tls: a CA, and a server certificate
+ * for 127.0.0.1 and a client certificate it signed */
+pub fn tls_file(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/support/tls")
+        .join(name)
+}
+
+/* Serves the repositories in a temporary directory over HTTP, optionally
+ * requiring basic auth. The server lives as long as the test process. */
+pub struct Server {
+    pub root: tempfile::TempDir,
+    pub port: u16,
+    scheme: &'static str,
+}
+
+impl Server {
+    pub fn start(auth: Option<(&str, &str)>) -> Server {
+        Self::listen(auth, None)
+    }
+
+    /* The same over https, with the certificate of tls_file("server.pem"),
+     * requiring one the CA signed from clients if `client_cert` */
+    pub fn start_tls(client_cert: bool) -> Server {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap();
+        let builder = if client_cert {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(tls_file("ca.pem")).unwrap() {
+                roots.add(cert.unwrap()).unwrap();
+            }
+            builder.with_client_cert_verifier(
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                    .build()
+                    .unwrap(),
+            )
+        } else {
+            builder.with_no_client_auth()
+        };
+        let certs = CertificateDer::pem_file_iter(tls_file("server.pem"))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let key = PrivateKeyDer::from_pem_file(tls_file("server.key")).unwrap();
+        Self::listen(
+            None,
+            Some(Arc::new(builder.with_single_cert(certs, key).unwrap())),
+        )
+    }
+
+    fn listen(auth: Option<(&str, &str)>, tls: Option<Arc<ServerConfig>>) -> Server {
+        let root = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let project_root = root.path().to_path_buf();
+        let expected_auth = auth.map(|(user, password)| {
+            format!("Basic {}", base64(format!("{user}:{password}").as_bytes()))
+        });
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let project_root = project_root.clone();
+                let expected_auth = expected_auth.clone();
+                let tls = tls.clone();
+                std::thread::spawn(move || match tls {
+                    Some(config) => {
+                        let conn = ServerConnection::new(config).unwrap();
+                        serve(
+                            StreamOwned::new(conn, stream),
+                            &project_root,
+                            expected_auth.as_deref(),
+                        )
+                    }
+                    None => serve(stream, &project_root, expected_auth.as_deref()),
+                });
+            }
+        });
+        Server { root, port, scheme }
+    }
+
+    pub fn repo(&self, name: &str, len: usize) -> Repo {
+        Repo::new(self.root.path().join(format!("{name}.git")), len)
+    }
+
+    pub fn url(&self, name: &str) -> String {
+        format!("{}://127.0.0.1:{}/{name}.git", self.scheme, self.port)
+    }
+
+    pub fn url_with_auth(&self, name: &str, user: &str, password: &str) -> String {
+        format!(
+            "{}://{user}:{password}@127.0.0.1:{}/{name}.git",
+            self.scheme, self.port
+        )
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+fn read_request(reader: &mut impl BufRead) -> Option<Request> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    /* PATH_INFO is decoded, as by real web servers */
+    let path = percent_encoding::percent_decode_str(path).decode_utf8_lossy();
+    let (path, query) = (path.into_owned(), query.to_string());
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.insert(name.to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    let mut body = Vec::new();
+    if headers.get("transfer-encoding").map(String::as_str) == Some("chunked") {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size).ok()?;
+            let size = usize::from_str_radix(size.trim(), 16).ok()?;
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).ok()?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(len) = headers.get("content-length") {
+        body.resize(len.parse().ok()?, 0);
+        reader.read_exact(&mut body).ok()?;
+    }
+
+    Some(Request {
+        method,
+        path,
+        query,
+        headers,
+        body,
+    })
+}
+
+/* One request per connection, answered with `Connection: close` */
+fn serve(mut stream: impl Read + Write, project_root: &Path, expected_auth: Option<&str>) {
+    let Some(req) = read_request(&mut BufReader::new(&mut stream)) else {
+        return;
+    };
+
+    if let Some(expected) = expected_auth {
+        if req.headers.get("authorization").map(String::as_str) != Some(expected) {
+            let _ = stream.write_all(
+                b"HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"test\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            );
+            return;
+        }
+    }
+
+    let mut child = Command::new("git")
+        .arg("http-backend")
+        .env("GIT_PROJECT_ROOT", project_root)
+        .env("GIT_HTTP_EXPORT_ALL", "1")
+        .env("REQUEST_METHOD", &req.method)
+        .env("PATH_INFO", &req.path)
+        .env("QUERY_STRING", &req.query)
+        .env(
+            "CONTENT_TYPE",
+            req.headers.get("content-type").cloned().unwrap_or_default(),
+        )
+        .env("CONTENT_LENGTH", req.body.len().to_string())
+        .env(
+            "GIT_PROTOCOL",
+            req.headers.get("git-protocol").cloned().unwrap_or_default(),
+        )
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let body = req.body;
+    let writer = std::thread::spawn(move || stdin.write_all(&body));
+    let Output { stdout, .. } = child.wait_with_output().unwrap();
+    let _ = writer.join();
+
+    /* CGI headers, with an optional Status, end at an empty line */
+    let split = stdout
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|pos| (pos, pos + 4))
+        .or_else(|| {
+            stdout
+                .windows(2)
+                .position(|w| w == b"\n\n")
+                .map(|pos| (pos, pos + 2))
+        })
+        .unwrap_or((stdout.len(), stdout.len()));
+    let head = String::from_utf8_lossy(&stdout[..split.0]);
+    let mut status = "200 OK".to_string();
+    let mut response = Vec::new();
+    for line in head.lines() {
+        match line.split_once(':') {
+            Some((name, value)) if name.eq_ignore_ascii_case("status") => {
+                status = value.trim().to_string()
+            }
+            Some(_) => response.extend_from_slice(format!("{line}\r\n").as_bytes()),
+            None => {}
+        }
+    }
+    let body = &stdout[split.1..];
+    let _ = stream.write_all(
+        format!(
+            "HTTP/1.1 {status}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+            String::from_utf8_lossy(&response),
+            body.len()
+        )
+        .as_bytes(),
+    );
+    let _ = stream.write_all(body);
+    let _ = stream.flush();
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/* Runs git-sleipnir with `args` in `dir` */
+pub fn sleipnir(dir: &Path, args: &[&str]) -> Output {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_git-sleipnir"));
+    cmd.current_dir(dir).args(args).env_remove("RUST_LOG");
+    /* Away from any sleipnir.toml or SLEIPNIR_* settings of whoever runs
+     * the tests */
+    cmd.env("XDG_CONFIG_HOME", dir);
+    for (name, _) in std::env::vars_os() {
+        if name.to_string_lossy().starts_with("SLEIPNIR_") {
+            cmd.env_remove(name);
+        }
+    }
+    let out = cmd.output().unwrap();
+    if !out.status.success() {
+        eprintln!("{}", String::from_utf8_lossy(&out.stderr));
+    }
+    out
+}
@@ -0,0 +1,79 @@
+/* https with the certificates of --ca-bundle and --client-cert, run in
+ * CI with either TLS backend */
+#![cfg(any(feature = "rustls", feature = "native-tls"))]
+
+mod support;
+
+use support::{sleipnir, tls_file, Server};
+
+#[test]
+fn trusts_ca_bundle() {
+    let server = Server::start_tls(false);
+    server.repo("omicron", 2).tag(1, "v1.0");
+    let dir = tempfile::tempdir().unwrap();
+    let url = server.url("omicron");
+    let ca_bundle = tls_file("ca.pem");
+    let find_branch = |args: &[&str]| {
+        sleipnir(
+            dir.path(),
+            &[&["find-branch", "--branch", "main"], args, &[&url]].concat(),
+        )
+    };
+
+    assert!(!find_branch(&[]).status.success());
+    let out = find_branch(&["--ca-bundle", ca_bundle.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), "main");
+}
+
+#[test]
+fn presents_client_cert() {
+    let server = Server::start_tls(true);
+    server.repo("pi", 3).tag(1, "v2.0");
+    let dir = tempfile::tempdir().unwrap();
+    let url = server.url("pi");
+    let (ca_bundle, cert, key) = (
+        tls_file("ca.pem"),
+        tls_file("client.pem"),
+        tls_file("client.key"),
+    );
+    let clone = |args: &[&str]| {
+        let args = [
+            &[
+                "clone",
+                "--create-dirs",
+                "--branch",
+                "main",
+                "--output-dir",
+                "out",
+                "--ca-bundle",
+                ca_bundle.to_str().unwrap(),
+            ],
+            args,
+        ]
+        .concat();
+        sleipnir(dir.path(), &[&args[..], &[&url]].concat())
+    };
+
+    assert!(!clone(&[]).status.success());
+    assert!(!dir.path().join("out/pi").exists());
+    let out = clone(&[
+        "--client-cert",
+        cert.to_str().unwrap(),
+        "--client-key",
+        key.to_str().unwrap(),
+        "--tag-output-file",
+        "tag",
+    ]);
+    assert!(out.status.success());
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("tag"))
+            .unwrap()
+            .trim(),
+        "v2.0"
+    );
+
+    /* Both or neither */
+    let out = clone(&["--client-cert", cert.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(2));
+}